@@ -13,7 +13,10 @@ async fn run_sql(ctx: &SessionContext, sql: &str) -> Result<()> {
 
 fn bench_mushrooms_arrow_cast(c: &mut Criterion) {
     let ctx = SessionContext::new();
-    let _ = ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new());
+    Runtime::new()
+        .unwrap()
+        .block_on(ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new()))
+        .unwrap();
     register_udfs(&ctx);
     let sql = "SELECT \
                     (arrow_cast(cap_shape, 'Dictionary(Int32, Utf8)')) as cap_shape,\
@@ -41,13 +44,16 @@ fn bench_mushrooms_arrow_cast(c: &mut Criterion) {
 
     c.bench_function("mushrooms_arrow_cast", |b| {
         b.to_async(Runtime::new().unwrap())
-            .iter(|| run_sql(&ctx, &sql))
+            .iter(|| run_sql(&ctx, sql))
     });
 }
 
 fn bench_mushrooms_prediction(c: &mut Criterion) {
     let ctx = SessionContext::new();
-    let _ = ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new());
+    Runtime::new()
+        .unwrap()
+        .block_on(ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new()))
+        .unwrap();
     register_udfs(&ctx);
     let sql = "SELECT predict(cap_shape, cap_surface, cap_color, bruises, odor, gill_attachment, gill_spacing, gill_size, gill_color, stalk_shape, stalk_root,\
         stalk_surface_above_ring, stalk_surface_below_ring, stalk_color_above_ring, stalk_color_below_ring, veil_type, veil_color, ring_number, ring_type, population, habitat ) FROM (SELECT \
@@ -76,13 +82,16 @@ fn bench_mushrooms_prediction(c: &mut Criterion) {
 
     c.bench_function("mushrooms_predict", |b| {
         b.to_async(Runtime::new().unwrap())
-            .iter(|| run_sql(&ctx, &sql))
+            .iter(|| run_sql(&ctx, sql))
     });
 }
 
 fn bench_mushrooms_onehot(c: &mut Criterion) {
     let ctx = SessionContext::new();
-    let _ = ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new());
+    Runtime::new()
+        .unwrap()
+        .block_on(ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new()))
+        .unwrap();
     register_udfs(&ctx);
     let sql = "SELECT onehot(arrow_cast(cap_shape, 'Dictionary(Int32, Utf8)')) as cap_shape,\
                     onehot(arrow_cast(cap_surface, 'Dictionary(Int32, Utf8)')) as cap_surface, \
@@ -109,18 +118,21 @@ fn bench_mushrooms_onehot(c: &mut Criterion) {
 
     c.bench_function("mushrooms_onehot", |b| {
         b.to_async(Runtime::new().unwrap())
-            .iter(|| run_sql(&ctx, &sql))
+            .iter(|| run_sql(&ctx, sql))
     });
 }
 
 fn bench_mushrooms_read(c: &mut Criterion) {
     let ctx = SessionContext::new();
-    let _ = ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new());
+    Runtime::new()
+        .unwrap()
+        .block_on(ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new()))
+        .unwrap();
     register_udfs(&ctx);
     let sql = "SELECT * FROM mushrooms";
     c.bench_function("mushrooms_read", |b| {
         b.to_async(Runtime::new().unwrap())
-            .iter(|| run_sql(&ctx, &sql))
+            .iter(|| run_sql(&ctx, sql))
     });
 }
 
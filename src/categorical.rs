@@ -0,0 +1,230 @@
+use datafusion::arrow::array::{as_dictionary_array, Array, ArrayRef, StringArray};
+use datafusion::arrow::datatypes::{DataType, Int32Type};
+use datafusion::error::{DataFusionError, Result};
+use std::collections::{HashMap, HashSet};
+use xgboost::Booster;
+
+/// Returns true for the `Dictionary(Int32, Utf8)` columns that are fed to
+/// XGBoost as a single feature holding the category code instead of going
+/// through `onehot`. The bundled XGBoost has no categorical splits, so trees
+/// treat the code as an ordinal number.
+pub fn is_categorical(data_type: &DataType) -> bool {
+    matches!(
+        data_type,
+        DataType::Dictionary(key, value)
+            if key.as_ref() == &DataType::Int32 && value.as_ref() == &DataType::Utf8
+    )
+}
+
+/// Category-to-code mapping for the dictionary-encoded feature columns of a
+/// model, indexed by input column position.
+///
+/// Dictionary keys produced by `arrow_cast` depend on the order values show
+/// up in each batch, so they cannot be handed to XGBoost directly. Instead
+/// every category is mapped to its position in a vocabulary fitted at
+/// training time and saved as a booster attribute, so scoring uses the same
+/// codes. Code `0` is reserved for null and unseen categories, which
+/// `DMatrix::from_dense` treats as missing. Codes follow the order in which
+/// categories were first seen, which is the order trees split them in.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CategoryMapping {
+    columns: Vec<Option<Vec<String>>>,
+}
+
+impl CategoryMapping {
+    /// Booster attribute holding the serialized mapping.
+    pub const ATTRIBUTE: &'static str = "categories";
+
+    /// Fits a vocabulary for every categorical column in `columns`.
+    /// Non-categorical columns keep an empty slot so positions line up.
+    pub fn fit(columns: &[ArrayRef]) -> Result<Self> {
+        let mut mapping = CategoryMapping::default();
+        mapping.extend(columns)?;
+        Ok(mapping)
+    }
+
+    /// The mapping to score `columns` with: the one `saved` with the model.
+    /// Models without one can only score columns that are not categorical,
+    /// as codes fitted on the scored batch would not match the training codes.
+    pub fn for_scoring(saved: Option<Self>, columns: &[ArrayRef]) -> Result<Self> {
+        match saved {
+            Some(mapping) => Ok(mapping),
+            None if columns
+                .iter()
+                .any(|column| is_categorical(column.data_type())) =>
+            {
+                Err(DataFusionError::Execution(
+                    "The model has no saved categories to encode categorical features with"
+                        .to_string(),
+                ))
+            }
+            None => Self::fit(columns),
+        }
+    }
+
+    /// Adds categories from `columns` that are not yet part of the mapping,
+    /// keeping the codes of the already known ones.
+    pub fn extend(&mut self, columns: &[ArrayRef]) -> Result<()> {
+        if self.columns.is_empty() {
+            self.columns = columns
+                .iter()
+                .map(|column| is_categorical(column.data_type()).then(Vec::new))
+                .collect();
+        }
+        if self.columns.len() != columns.len() {
+            return Err(DataFusionError::Internal(format!(
+                "Expected {} feature columns, got {}",
+                self.columns.len(),
+                columns.len()
+            )));
+        }
+
+        for (vocabulary, column) in self.columns.iter_mut().zip(columns) {
+            let Some(vocabulary) = vocabulary else {
+                continue;
+            };
+            let mut known: HashSet<String> = vocabulary.iter().cloned().collect();
+            for value in dictionary_values(column)?.into_iter().flatten() {
+                if known.insert(value.to_string()) {
+                    vocabulary.push(value.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Vocabulary of the column at `index`, or `None` if it is not categorical.
+    pub fn vocabulary(&self, index: usize) -> Option<&[String]> {
+        self.columns.get(index)?.as_deref()
+    }
+
+    /// Number of input columns covered by the mapping.
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    /// Encodes the categorical column at `index` into one feature value per
+    /// row using the fitted codes.
+    pub fn encode(&self, index: usize, column: &ArrayRef) -> Result<Vec<f32>> {
+        let vocabulary = self.vocabulary(index).ok_or_else(|| {
+            DataFusionError::Internal(format!("Column {} has no category mapping", index))
+        })?;
+        let codes: HashMap<&str, usize> = vocabulary
+            .iter()
+            .enumerate()
+            .map(|(code, value)| (value.as_str(), code))
+            .collect();
+
+        Ok(dictionary_values(column)?
+            .into_iter()
+            .map(|value| match value.and_then(|v| codes.get(v)) {
+                Some(code) => (*code + 1) as f32,
+                None => 0.0,
+            })
+            .collect())
+    }
+
+    /// Stores the mapping on `booster` so it is saved with the model file.
+    pub fn save(&self, booster: &mut Booster) -> Result<()> {
+        let value = serde_json::to_string(&self.columns)
+            .map_err(|e| DataFusionError::Internal(e.to_string()))?;
+        booster
+            .set_attribute(Self::ATTRIBUTE, &value)
+            .map_err(|e| DataFusionError::Internal(format!("Failed to save categories: {}", e)))
+    }
+
+    /// Reads the mapping saved on `booster`, if the model has one.
+    pub fn load(booster: &Booster) -> Result<Option<Self>> {
+        let value = booster
+            .get_attribute(Self::ATTRIBUTE)
+            .map_err(|e| DataFusionError::Internal(format!("Failed to load categories: {}", e)))?;
+        value
+            .map(|value| {
+                serde_json::from_str(&value)
+                    .map(|columns| CategoryMapping { columns })
+                    .map_err(|e| DataFusionError::Internal(e.to_string()))
+            })
+            .transpose()
+    }
+}
+
+/// Resolves every row of a `Dictionary(Int32, Utf8)` column to its string value.
+fn dictionary_values(column: &ArrayRef) -> Result<Vec<Option<&str>>> {
+    if !is_categorical(column.data_type()) {
+        return Err(DataFusionError::Internal(format!(
+            "Expected Dictionary(Int32, Utf8), got {}",
+            column.data_type()
+        )));
+    }
+    let data = as_dictionary_array::<Int32Type>(column);
+    let values = data
+        .values()
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| DataFusionError::Internal("Expected StringArray".to_string()))?;
+
+    Ok(data
+        .keys()
+        .iter()
+        .map(|key| {
+            key.and_then(|k| {
+                values
+                    .is_valid(k as usize)
+                    .then(|| values.value(k as usize))
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use datafusion::arrow::array::StringDictionaryBuilder;
+    use std::sync::Arc;
+
+    fn dictionary(values: &[Option<&str>]) -> ArrayRef {
+        let mut builder = StringDictionaryBuilder::<Int32Type>::new();
+        for value in values {
+            match value {
+                Some(v) => builder.append_value(v),
+                None => builder.append_null(),
+            }
+        }
+        Arc::new(builder.finish())
+    }
+
+    #[tokio::test]
+    pub async fn test_codes_are_stable_across_batches() -> Result<()> {
+        let first = dictionary(&[Some("x"), Some("y"), Some("x")]);
+        let second = dictionary(&[Some("y"), Some("z"), None]);
+
+        let mut mapping = CategoryMapping::fit(std::slice::from_ref(&first))?;
+        mapping.extend(std::slice::from_ref(&second))?;
+
+        assert_eq!(mapping.vocabulary(0).unwrap(), &["x", "y", "z"]);
+        assert_eq!(mapping.encode(0, &first)?, vec![1.0, 2.0, 1.0]);
+        assert_eq!(mapping.encode(0, &second)?, vec![2.0, 3.0, 0.0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_unseen_category_is_missing() -> Result<()> {
+        let mapping = CategoryMapping::fit(&[dictionary(&[Some("x")])])?;
+        assert_eq!(mapping.encode(0, &dictionary(&[Some("w")]))?, vec![0.0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_scoring_needs_saved_categories() -> Result<()> {
+        let columns = [dictionary(&[Some("x")])];
+        let saved = CategoryMapping::fit(&[dictionary(&[Some("y")])])?;
+        let mapping = CategoryMapping::for_scoring(Some(saved), &columns)?;
+        assert_eq!(mapping.encode(0, &columns[0])?, vec![0.0]);
+        assert!(CategoryMapping::for_scoring(None, &columns).is_err());
+        Ok(())
+    }
+}
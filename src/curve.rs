@@ -0,0 +1,223 @@
+use crate::table_function::{column_name, literal_string, LazyTable};
+use datafusion::arrow::array::{Array, ArrayRef, Float64Array};
+use datafusion::arrow::compute::{cast, concat};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datasource::function::TableFunctionImpl;
+use datafusion::datasource::TableProvider;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_expr::Expr;
+use datafusion::prelude::SessionContext;
+use std::sync::Arc;
+
+/// Operating point of a binary classifier when rows with a score of at
+/// least `threshold` are predicted positive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CurvePoint {
+    pub threshold: f64,
+    pub tpr: Option<f64>,
+    pub fpr: Option<f64>,
+    pub precision: Option<f64>,
+}
+
+impl CurvePoint {
+    /// Recall is the true positive rate.
+    pub fn recall(&self) -> Option<f64> {
+        self.tpr
+    }
+}
+
+/// One point per distinct score, from the highest threshold to the lowest.
+///
+/// Scores are sorted once and the confusion counts are accumulated while
+/// walking down, so this is `O(n log n)`. Rates whose denominator is zero
+/// are `None`, e.g. the false positive rate without negative labels.
+pub fn curve_points(scores: &[f64], labels: &[bool]) -> Vec<CurvePoint> {
+    let mut order: Vec<usize> = (0..scores.len()).collect();
+    order.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+
+    let positives = labels.iter().filter(|label| **label).count();
+    let negatives = labels.len() - positives;
+    let ratio = |n: usize, d: usize| (d > 0).then(|| n as f64 / d as f64);
+
+    let mut points = Vec::new();
+    let (mut tp, mut fp) = (0, 0);
+    for (i, row) in order.iter().enumerate() {
+        if labels[*row] {
+            tp += 1;
+        } else {
+            fp += 1;
+        }
+        let last_of_score = order
+            .get(i + 1)
+            .is_none_or(|next| scores[*next] != scores[*row]);
+        if last_of_score {
+            points.push(CurvePoint {
+                threshold: scores[*row],
+                tpr: ratio(tp, positives),
+                fpr: ratio(fp, negatives),
+                precision: ratio(tp, tp + fp),
+            });
+        }
+    }
+    points
+}
+
+/// Schema of `roc_curve` and `pr_curve`.
+pub fn curve_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("threshold", DataType::Float64, false),
+        Field::new("tpr", DataType::Float64, true),
+        Field::new("fpr", DataType::Float64, true),
+        Field::new("precision", DataType::Float64, true),
+        Field::new("recall", DataType::Float64, true),
+    ]))
+}
+
+fn points_to_batch(points: &[CurvePoint]) -> Result<RecordBatch> {
+    let column = |value: fn(&CurvePoint) -> Option<f64>| -> ArrayRef {
+        Arc::new(points.iter().map(value).collect::<Float64Array>())
+    };
+    Ok(RecordBatch::try_new(
+        curve_schema(),
+        vec![
+            column(|p| Some(p.threshold)),
+            column(|p| p.tpr),
+            column(|p| p.fpr),
+            column(|p| p.precision),
+            column(|p| p.recall()),
+        ],
+    )?)
+}
+
+/// Which curve a [`CurveFunction`] returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Curve {
+    /// Starts at `(fpr, tpr) = (0, 0)` with an infinite threshold, so the
+    /// curve covers predicting no row as positive.
+    Roc,
+    /// Only the points of [`curve_points`], as precision is undefined when
+    /// no row is predicted positive.
+    PrecisionRecall,
+}
+
+impl Curve {
+    fn name(&self) -> &'static str {
+        match self {
+            Curve::Roc => "roc_curve",
+            Curve::PrecisionRecall => "pr_curve",
+        }
+    }
+}
+
+/// Runs `sql` and computes `curve` for its `score` and boolean or 0/1
+/// `label` columns. Rows with a null score or label are skipped.
+pub async fn curve(
+    ctx: &SessionContext,
+    sql: &str,
+    score: &str,
+    label: &str,
+    curve: Curve,
+) -> Result<RecordBatch> {
+    let df = ctx.sql(sql).await?.select_columns(&[score, label])?;
+    let batches = df.collect().await?;
+    let column = |i: usize| -> Result<Float64Array> {
+        let arrays = batches
+            .iter()
+            .map(|batch| cast(batch.column(i), &DataType::Float64))
+            .collect::<Result<Vec<_>, _>>()?;
+        let arrays: Vec<&dyn Array> = arrays.iter().map(|array| array.as_ref()).collect();
+        let column = if arrays.is_empty() {
+            Arc::new(Float64Array::from(Vec::<f64>::new())) as ArrayRef
+        } else {
+            concat(&arrays)?
+        };
+        column
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .cloned()
+            .ok_or_else(|| DataFusionError::Internal("Expected Float64Array".to_string()))
+    };
+    let (scores, labels) = (column(0)?, column(1)?);
+    let (scores, labels): (Vec<f64>, Vec<bool>) = scores
+        .iter()
+        .zip(labels.iter())
+        .filter_map(|(score, label)| Some((score?, label? >= 0.5)))
+        .unzip();
+
+    let mut points = curve_points(&scores, &labels);
+    if curve == Curve::Roc {
+        let positives = labels.iter().any(|label| *label);
+        let negatives = labels.iter().any(|label| !*label);
+        points.insert(
+            0,
+            CurvePoint {
+                threshold: f64::INFINITY,
+                tpr: positives.then_some(0.0),
+                fpr: negatives.then_some(0.0),
+                precision: None,
+            },
+        );
+    }
+    points_to_batch(&points)
+}
+
+/// `roc_curve('<query>', score, label)` and `pr_curve('<query>', score, label)`
+/// table functions, see [`curve`]. The columns are given as identifiers or
+/// string literals.
+pub struct CurveFunction(pub Curve);
+
+impl TableFunctionImpl for CurveFunction {
+    fn call(&self, args: &[Expr]) -> Result<Arc<dyn TableProvider>> {
+        let kind = self.0;
+        let name = kind.name();
+        let (sql, score, label) = match args {
+            [sql, score, label] => (
+                literal_string(name, sql)?,
+                column_name(name, score)?,
+                column_name(name, label)?,
+            ),
+            _ => {
+                return Err(DataFusionError::Plan(format!(
+                    "{} expects a query, a score column and a label column",
+                    name
+                )))
+            }
+        };
+
+        Ok(Arc::new(LazyTable::new(
+            name,
+            curve_schema(),
+            Arc::new(move |ctx| {
+                let (sql, score, label) = (sql.clone(), score.clone(), label.clone());
+                Box::pin(async move { curve(&ctx, &sql, &score, &label, kind).await })
+            }),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_curve_points() -> Result<()> {
+        let points = curve_points(
+            &[0.1, 0.4, 0.35, 0.8, 0.4],
+            &[false, false, true, true, true],
+        );
+        let thresholds: Vec<f64> = points.iter().map(|p| p.threshold).collect();
+        assert_eq!(thresholds, vec![0.8, 0.4, 0.35, 0.1]);
+
+        let at_04 = points[1];
+        assert_eq!(at_04.tpr, Some(2.0 / 3.0));
+        assert_eq!(at_04.fpr, Some(0.5));
+        assert_eq!(at_04.precision, Some(2.0 / 3.0));
+        assert_eq!(points[3].tpr, Some(1.0));
+        assert_eq!(points[3].fpr, Some(1.0));
+
+        let points = curve_points(&[0.3, 0.6], &[true, true]);
+        assert_eq!(points[0].fpr, None);
+        Ok(())
+    }
+}
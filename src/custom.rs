@@ -0,0 +1,175 @@
+use crate::xgb_error;
+use datafusion::error::{DataFusionError, Result};
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::Arc;
+use xgboost::{Booster, DMatrix};
+
+type GradientFn = dyn Fn(&[f32], &[f32]) -> (Vec<f32>, Vec<f32>) + Send + Sync;
+type MetricFn = dyn Fn(&[f32], &[f32]) -> f32 + Send + Sync;
+
+/// A loss written in Rust, boosted instead of the objective's own loss.
+///
+/// The function gets the predictions of the model so far and the labels of
+/// the training rows, and returns the gradient and hessian of the loss for
+/// every row. Predictions go through the output transform of
+/// `TrainOptions::objective`, so use `binary:logitraw` or `reg:squarederror`
+/// to get raw margins. Sample weights are not supported, as the xgboost crate
+/// cannot tell whether a `DMatrix` has them.
+#[derive(Clone)]
+pub struct CustomObjective(Arc<GradientFn>);
+
+impl CustomObjective {
+    pub fn new(
+        gradient: impl Fn(&[f32], &[f32]) -> (Vec<f32>, Vec<f32>) + Send + Sync + 'static,
+    ) -> Self {
+        CustomObjective(Arc::new(gradient))
+    }
+
+    /// Boosts one round of `booster` on `dtrain` with this loss. If the loss
+    /// fails, the booster is left unchanged.
+    pub fn update(&self, booster: &mut Booster, dtrain: &DMatrix) -> Result<()> {
+        let predictions = booster.predict(dtrain).map_err(xgb_error)?;
+        let gradient = self.gradient(&predictions, dtrain)?;
+        // the xgboost crate takes the objective as a function pointer, so the
+        // gradient is passed to `boost_with_current` through a thread local
+        CURRENT.with(|current| *current.borrow_mut() = Some(gradient));
+        let result = booster.update_custom(dtrain, boost_with_current);
+        CURRENT.with(|current| current.borrow_mut().take());
+        result.map_err(xgb_error)
+    }
+
+    fn gradient(&self, predictions: &[f32], dtrain: &DMatrix) -> Result<(Vec<f32>, Vec<f32>)> {
+        let labels = dtrain.get_labels().map_err(xgb_error)?;
+        let (gradient, hessian) = (self.0)(predictions, labels);
+        if gradient.len() != predictions.len() || hessian.len() != predictions.len() {
+            return Err(DataFusionError::Execution(format!(
+                "Custom objective returned {} gradients and {} hessians for {} rows",
+                gradient.len(),
+                hessian.len(),
+                predictions.len()
+            )));
+        }
+        Ok((gradient, hessian))
+    }
+}
+
+impl fmt::Debug for CustomObjective {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CustomObjective")
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<(Vec<f32>, Vec<f32>)>> = const { RefCell::new(None) };
+}
+
+/// The `xgboost::CustomObjective` returning the gradient computed by the
+/// [`CustomObjective::update`] running on this thread.
+fn boost_with_current(_: &[f32], _: &DMatrix) -> (Vec<f32>, Vec<f32>) {
+    // without a gradient XGBoost rejects the empty one instead of boosting
+    CURRENT
+        .with(|current| current.borrow_mut().take())
+        .unwrap_or_default()
+}
+
+/// An evaluation metric written in Rust, logged after every round next to
+/// XGBoost's metrics.
+///
+/// The function gets the predictions and labels of a dataset and returns
+/// the metric value. The last custom metric decides early stopping, in the
+/// direction given by `maximize`.
+#[derive(Clone)]
+pub struct CustomMetric {
+    name: String,
+    maximize: bool,
+    metric: Arc<MetricFn>,
+}
+
+impl CustomMetric {
+    pub fn new(
+        name: impl Into<String>,
+        maximize: bool,
+        metric: impl Fn(&[f32], &[f32]) -> f32 + Send + Sync + 'static,
+    ) -> Self {
+        CustomMetric {
+            name: name.into(),
+            maximize,
+            metric: Arc::new(metric),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn maximize(&self) -> bool {
+        self.maximize
+    }
+
+    /// Value of the metric for the predictions of `booster` on `dmat`.
+    pub fn evaluate(&self, booster: &Booster, dmat: &DMatrix) -> Result<f32> {
+        let predictions = booster.predict(dmat).map_err(xgb_error)?;
+        let labels = dmat.get_labels().map_err(xgb_error)?;
+        Ok((self.metric)(&predictions, labels))
+    }
+}
+
+impl fmt::Debug for CustomMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CustomMetric")
+            .field("name", &self.name)
+            .field("maximize", &self.maximize)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::iteration::num_trees;
+    use xgboost::parameters::BoosterParameters;
+
+    fn squared_error() -> CustomObjective {
+        CustomObjective::new(|predictions, labels| {
+            let gradient = predictions.iter().zip(labels).map(|(p, y)| p - y).collect();
+            (gradient, vec![1.0; predictions.len()])
+        })
+    }
+
+    #[tokio::test]
+    pub async fn test_custom_objective_updates_booster() -> Result<()> {
+        let mut dtrain = DMatrix::from_dense(&[0.0, 1.0, 0.0, 1.0], 4).map_err(xgb_error)?;
+        dtrain
+            .set_labels(&[1.0, 3.0, 1.0, 3.0])
+            .map_err(xgb_error)?;
+        let mut booster = Booster::new_with_cached_dmats(&BoosterParameters::default(), &[&dtrain])
+            .map_err(xgb_error)?;
+        for _ in 0..20 {
+            squared_error().update(&mut booster, &dtrain)?;
+        }
+        let predictions = booster.predict(&dtrain).map_err(xgb_error)?;
+        assert!((predictions[0] - 1.0).abs() < 0.1);
+        assert!((predictions[1] - 3.0).abs() < 0.1);
+
+        let mae = CustomMetric::new("mae", false, |predictions, labels| {
+            predictions
+                .iter()
+                .zip(labels)
+                .map(|(p, y)| (p - y).abs())
+                .sum::<f32>()
+                / labels.len() as f32
+        });
+        assert!(mae.evaluate(&booster, &dtrain)? < 0.1);
+
+        let trees = num_trees(&booster)?;
+        let wrong = CustomObjective::new(|_, _| (vec![0.0], vec![1.0]));
+        let error = wrong.update(&mut booster, &dtrain).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("1 gradients and 1 hessians for 4 rows"));
+        // the failed round adds no tree
+        assert_eq!(num_trees(&booster)?, trees);
+        Ok(())
+    }
+}
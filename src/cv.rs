@@ -0,0 +1,159 @@
+use crate::split::split_bucket;
+use crate::train::{train_booster, training_data, TrainOptions};
+use crate::xgb_error;
+use datafusion::arrow::array::{ArrayRef, Float64Array, StringArray};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::prelude::SessionContext;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use xgboost::DMatrix;
+
+/// Runs `sql` and cross-validates a model predicting `label` with `folds`
+/// folds, see [`cross_validate_batches`].
+pub async fn cross_validate(
+    ctx: &SessionContext,
+    sql: &str,
+    label: &str,
+    folds: usize,
+    options: &TrainOptions,
+) -> Result<RecordBatch> {
+    let batches = ctx.sql(sql).await?.collect().await?;
+    cross_validate_batches(&batches, label, folds, options)
+}
+
+/// K-fold cross-validation over the rows of `batches`.
+///
+/// Rows are assigned to folds by hashing their position with
+/// `options.seed`, so the folds are reproducible for a query with a stable
+/// row order. For every fold a model is trained with `options` on the other
+/// folds and evaluated on both. The result has one row per fold and metric,
+/// followed by `mean` and `std` (population standard deviation) rows over the
+/// folds, see [`cv_schema`]. No model is saved.
+pub fn cross_validate_batches(
+    batches: &[RecordBatch],
+    label: &str,
+    folds: usize,
+    options: &TrainOptions,
+) -> Result<RecordBatch> {
+    if folds < 2 {
+        return Err(DataFusionError::Plan(format!(
+            "Cross-validation needs at least 2 folds, got {}",
+            folds
+        )));
+    }
+    if options.group.is_some() {
+        // folds are sampled by row, which would split query groups
+        return Err(DataFusionError::Plan(
+            "Cross-validation does not support query groups".to_string(),
+        ));
+    }
+    let data = training_data(batches, label, options)?.0;
+
+    let mut fold_names = Vec::new();
+    let mut metrics = Vec::new();
+    let mut train_values = Vec::new();
+    let mut test_values = Vec::new();
+    let mut per_metric: BTreeMap<String, Vec<(f64, f64)>> = BTreeMap::new();
+    for (fold, (dtrain, dtest)) in fold_matrices(&data, folds, options.seed)?
+        .into_iter()
+        .enumerate()
+    {
+        let booster = train_booster(&dtrain, options)?;
+        let train_metrics: HashMap<String, f32> =
+            options.evaluate(&booster, &dtrain)?.into_iter().collect();
+        let test_metrics: HashMap<String, f32> =
+            options.evaluate(&booster, &dtest)?.into_iter().collect();
+        let mut names: Vec<&String> = test_metrics.keys().collect();
+        names.sort();
+        for name in names {
+            let train_value = train_metrics.get(name).copied().unwrap_or(f32::NAN) as f64;
+            let test_value = test_metrics[name] as f64;
+            fold_names.push(fold.to_string());
+            metrics.push(name.clone());
+            train_values.push(train_value);
+            test_values.push(test_value);
+            per_metric
+                .entry(name.clone())
+                .or_default()
+                .push((train_value, test_value));
+        }
+    }
+
+    for (statistic, aggregate) in [("mean", mean as fn(&[f64]) -> f64), ("std", std)] {
+        for (name, values) in &per_metric {
+            let (train, test): (Vec<f64>, Vec<f64>) = values.iter().copied().unzip();
+            fold_names.push(statistic.to_string());
+            metrics.push(name.clone());
+            train_values.push(aggregate(&train));
+            test_values.push(aggregate(&test));
+        }
+    }
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(fold_names)),
+        Arc::new(StringArray::from(metrics)),
+        Arc::new(Float64Array::from(train_values)),
+        Arc::new(Float64Array::from(test_values)),
+    ];
+    Ok(RecordBatch::try_new(cv_schema(), columns)?)
+}
+
+/// Schema of the result of [`cross_validate`]: the fold number or `mean` /
+/// `std`, the metric name and its value on the training and held-out rows.
+pub fn cv_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("fold", DataType::Utf8, false),
+        Field::new("metric", DataType::Utf8, false),
+        Field::new("train", DataType::Float64, false),
+        Field::new("test", DataType::Float64, false),
+    ]))
+}
+
+/// Splits `data` into `(train, test)` matrices for every fold.
+fn fold_matrices(data: &DMatrix, folds: usize, seed: u64) -> Result<Vec<(DMatrix, DMatrix)>> {
+    let fractions = vec![1.0 / folds as f64; folds];
+    let assignment: Vec<usize> = (0..data.num_rows())
+        .map(|row| split_bucket(&(row as u64).to_le_bytes(), seed, &fractions).unwrap_or(folds - 1))
+        .collect();
+
+    (0..folds)
+        .map(|fold| {
+            let (test, train): (Vec<usize>, Vec<usize>) =
+                (0..data.num_rows()).partition(|row| assignment[*row] == fold);
+            if test.is_empty() || train.is_empty() {
+                return Err(DataFusionError::Execution(format!(
+                    "Fold {} of {} has no rows to train or test on",
+                    fold, folds
+                )));
+            }
+            Ok((
+                data.slice(&train).map_err(xgb_error)?,
+                data.slice(&test).map_err(xgb_error)?,
+            ))
+        })
+        .collect()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn std(values: &[f64]) -> f64 {
+    let mean = mean(values);
+    (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64).sqrt()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_fold_statistics() -> Result<()> {
+        assert_eq!(mean(&[1.0, 2.0, 3.0]), 2.0);
+        assert_eq!(std(&[1.0, 3.0]), 1.0);
+        assert_eq!(std(&[2.0, 2.0, 2.0]), 0.0);
+        Ok(())
+    }
+}
@@ -0,0 +1,779 @@
+use crate::label::{float_values, is_string_label, label_values, LabelMapping};
+use crate::{columns_to_features, is_categorical, xgb_error, CategoryMapping};
+use datafusion::arrow::array::{Array, ArrayRef, ListArray, StringArray, StructArray};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::DataType;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::dataframe::DataFrame;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::SendableRecordBatchStream;
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use xgboost::{Booster, DMatrix};
+
+/// How one input column is turned into features.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FeatureColumn {
+    /// A `onehot` column, one feature per key in this order.
+    Onehot(Vec<String>),
+    /// A `Dictionary(Int32, Utf8)` column, a single feature holding the
+    /// category code.
+    Categorical,
+}
+
+/// Names and kinds of the feature columns of a batch, in order.
+///
+/// `onehot` keys come from the dictionary of each batch, so two batches of
+/// the same query can disagree on the number or order of features. Their
+/// layouts are [merged](Self::merge) to combine them into one `DMatrix`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureLayout {
+    columns: Vec<(String, FeatureColumn)>,
+}
+
+impl FeatureLayout {
+    /// Booster attribute holding the serialized layout.
+    pub const ATTRIBUTE: &'static str = "layout";
+
+    pub fn of(batch: &RecordBatch) -> Result<Self> {
+        let columns = batch
+            .schema()
+            .fields()
+            .iter()
+            .zip(batch.columns())
+            .map(|(field, column)| {
+                Ok((field.name().clone(), feature_column(field.name(), column)?))
+            })
+            .collect::<Result<_>>()?;
+        Ok(FeatureLayout { columns })
+    }
+
+    pub fn columns(&self) -> &[(String, FeatureColumn)] {
+        &self.columns
+    }
+
+    /// Number of `DMatrix` columns produced by this layout.
+    pub fn num_features(&self) -> usize {
+        self.columns
+            .iter()
+            .map(|(_, column)| match column {
+                FeatureColumn::Onehot(keys) => keys.len(),
+                FeatureColumn::Categorical => 1,
+            })
+            .sum()
+    }
+
+    /// One name per feature: `column=key` for onehot features and the
+    /// column name for categorical ones.
+    pub fn feature_names(&self) -> Vec<String> {
+        self.columns
+            .iter()
+            .flat_map(|(name, column)| match column {
+                FeatureColumn::Onehot(keys) => {
+                    keys.iter().map(|key| format!("{}={}", name, key)).collect()
+                }
+                FeatureColumn::Categorical => vec![name.clone()],
+            })
+            .collect()
+    }
+
+    /// The layout with the `onehot` keys of both layouts: the keys of `self`
+    /// in their order, followed by the keys only `other` has. Fails if the
+    /// layouts differ in anything but `onehot` keys.
+    pub fn merge(&self, other: &FeatureLayout) -> Result<FeatureLayout> {
+        if self.columns.len() != other.columns.len() {
+            return Err(layout_error(format!(
+                "expected {} columns, got {}",
+                self.columns.len(),
+                other.columns.len()
+            )));
+        }
+        let mut columns = Vec::with_capacity(self.columns.len());
+        for ((name, column), (other_name, other_column)) in self.columns.iter().zip(&other.columns)
+        {
+            if name != other_name {
+                return Err(layout_error(format!(
+                    "expected column {}, got {}",
+                    name, other_name
+                )));
+            }
+            let column = match (column, other_column) {
+                (FeatureColumn::Onehot(keys), FeatureColumn::Onehot(other_keys)) => {
+                    let mut keys = keys.clone();
+                    for key in other_keys {
+                        if !keys.contains(key) {
+                            keys.push(key.clone());
+                        }
+                    }
+                    FeatureColumn::Onehot(keys)
+                }
+                (FeatureColumn::Categorical, FeatureColumn::Categorical) => {
+                    FeatureColumn::Categorical
+                }
+                _ => {
+                    return Err(layout_error(format!(
+                        "column {} is {}, got {}",
+                        name, column, other_column
+                    )))
+                }
+            };
+            columns.push((name.clone(), column));
+        }
+        Ok(FeatureLayout { columns })
+    }
+
+    /// Position in this layout of every feature of `other`, whose `onehot`
+    /// keys must all be keys of this layout, e.g. because it was merged in.
+    fn positions(&self, other: &FeatureLayout) -> Vec<usize> {
+        let mut positions = Vec::with_capacity(other.num_features());
+        let mut offset = 0;
+        for ((_, column), (_, other_column)) in self.columns.iter().zip(&other.columns) {
+            match (column, other_column) {
+                (FeatureColumn::Onehot(keys), FeatureColumn::Onehot(other_keys)) => {
+                    let index: HashMap<&String, usize> =
+                        keys.iter().enumerate().map(|(i, key)| (key, i)).collect();
+                    positions.extend(other_keys.iter().map(|key| offset + index[key]));
+                    offset += keys.len();
+                }
+                _ => {
+                    positions.push(offset);
+                    offset += 1;
+                }
+            }
+        }
+        positions
+    }
+
+    /// Fails with a description of the first difference if `other` does not
+    /// have the same layout.
+    pub fn check(&self, other: &FeatureLayout) -> Result<()> {
+        if self.columns.len() != other.columns.len() {
+            return Err(layout_error(format!(
+                "expected {} columns, got {}",
+                self.columns.len(),
+                other.columns.len()
+            )));
+        }
+        for ((name, column), (other_name, other_column)) in self.columns.iter().zip(&other.columns)
+        {
+            if name != other_name {
+                return Err(layout_error(format!(
+                    "expected column {}, got {}",
+                    name, other_name
+                )));
+            }
+            if column != other_column {
+                return Err(layout_error(format!(
+                    "column {} is {}, got {}",
+                    name, column, other_column
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores the layout on `booster` so data for continued training can be
+    /// checked against it.
+    pub fn save(&self, booster: &mut Booster) -> Result<()> {
+        // onehot columns have their keys, categorical ones null
+        let columns: Vec<(&String, Option<&Vec<String>>)> = self
+            .columns
+            .iter()
+            .map(|(name, column)| match column {
+                FeatureColumn::Onehot(keys) => (name, Some(keys)),
+                FeatureColumn::Categorical => (name, None),
+            })
+            .collect();
+        let value = serde_json::to_string(&columns)
+            .map_err(|e| DataFusionError::Internal(e.to_string()))?;
+        booster
+            .set_attribute(Self::ATTRIBUTE, &value)
+            .map_err(xgb_error)
+    }
+
+    /// Reads the layout saved on `booster`, if the model has one.
+    pub fn load(booster: &Booster) -> Result<Option<Self>> {
+        let value = booster.get_attribute(Self::ATTRIBUTE).map_err(xgb_error)?;
+        value
+            .map(|value| {
+                let columns: Vec<(String, Option<Vec<String>>)> = serde_json::from_str(&value)
+                    .map_err(|e| DataFusionError::Internal(e.to_string()))?;
+                let columns = columns
+                    .into_iter()
+                    .map(|(name, keys)| match keys {
+                        Some(keys) => (name, FeatureColumn::Onehot(keys)),
+                        None => (name, FeatureColumn::Categorical),
+                    })
+                    .collect();
+                Ok(FeatureLayout { columns })
+            })
+            .transpose()
+    }
+}
+
+impl fmt::Display for FeatureColumn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeatureColumn::Onehot(keys) => write!(f, "onehot [{}]", keys.join(", ")),
+            FeatureColumn::Categorical => write!(f, "categorical"),
+        }
+    }
+}
+
+/// Lengths of the runs of equal values of a group column, with the value of
+/// each run cast to a string.
+fn group_runs(groups: &ArrayRef) -> Result<Vec<(String, u32)>> {
+    let groups = cast(groups, &DataType::Utf8)?;
+    let groups = groups
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| DataFusionError::Internal("Expected StringArray".to_string()))?;
+    let mut runs: Vec<(String, u32)> = Vec::new();
+    for group in groups.iter() {
+        let group = group
+            .ok_or_else(|| DataFusionError::Execution("Group column contains nulls".to_string()))?;
+        match runs.last_mut() {
+            Some((last, size)) if last == group => *size += 1,
+            _ => runs.push((group.to_string(), 1)),
+        }
+    }
+    Ok(runs)
+}
+
+/// The weight of the class of every row of a label column.
+fn row_class_weights(labels: &ArrayRef, weights: &HashMap<String, f32>) -> Result<Vec<f32>> {
+    let labels = cast(labels, &DataType::Utf8)?;
+    let labels = labels
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| DataFusionError::Internal("Expected StringArray".to_string()))?;
+    Ok(labels
+        .iter()
+        .map(|label| {
+            label
+                .and_then(|label| weights.get(label))
+                .copied()
+                .unwrap_or(1.0)
+        })
+        .collect())
+}
+
+/// Moves the features of row-major `data` to `positions` in rows of
+/// `num_features` features, leaving the other features at 0, i.e. missing.
+fn scatter(data: Vec<f32>, num_rows: usize, positions: &[usize], num_features: usize) -> Vec<f32> {
+    let in_place =
+        positions.len() == num_features && positions.iter().enumerate().all(|(i, p)| i == *p);
+    if in_place {
+        return data;
+    }
+    let mut result = vec![0.0; num_rows * num_features];
+    for (row, features) in data
+        .chunks(positions.len().max(1))
+        .take(num_rows)
+        .enumerate()
+    {
+        for (feature, position) in features.iter().zip(positions) {
+            result[row * num_features + position] = *feature;
+        }
+    }
+    result
+}
+
+fn layout_error(message: String) -> DataFusionError {
+    DataFusionError::Execution(format!("Feature layout mismatch: {}", message))
+}
+
+fn feature_column(name: &str, column: &ArrayRef) -> Result<FeatureColumn> {
+    if is_categorical(column.data_type()) {
+        return Ok(FeatureColumn::Categorical);
+    }
+    if !matches!(column.data_type(), DataType::List(_)) {
+        return Err(DataFusionError::Execution(format!(
+            "Feature column {} has unsupported type {}",
+            name,
+            column.data_type()
+        )));
+    }
+    let list = column
+        .as_any()
+        .downcast_ref::<ListArray>()
+        .ok_or_else(|| DataFusionError::Internal("Expected ListArray".to_string()))?;
+    if list.is_empty() {
+        return Ok(FeatureColumn::Onehot(vec![]));
+    }
+    let first = list.value(0);
+    let keys = first
+        .as_any()
+        .downcast_ref::<StructArray>()
+        .and_then(|item| item.column(0).as_any().downcast_ref::<StringArray>())
+        .ok_or_else(|| {
+            DataFusionError::Internal(format!("Expected onehot struct list in {}", name))
+        })?;
+    Ok(FeatureColumn::Onehot(
+        keys.iter()
+            .map(|key| key.unwrap_or_default().to_string())
+            .collect(),
+    ))
+}
+
+/// Accumulates the rows of several batches into one `DMatrix`.
+///
+/// Every appended batch must have the feature columns of the first non-empty
+/// one. Their `onehot` keys may differ: the [`FeatureLayout`] is merged batch
+/// by batch, and rows appended before a key was first seen do not have it
+/// set. Categorical columns are encoded with a fixed mapping, or with one
+/// that is extended batch by batch, which keeps the codes of categories
+/// already seen.
+///
+/// Columns named with [`label`](Self::label), [`weight`](Self::weight),
+/// [`base_margin`](Self::base_margin) and [`group`](Self::group) are set as
+/// the corresponding `DMatrix` info instead of being used as features.
+#[derive(Debug, Default)]
+pub struct DMatrixBuilder {
+    categories: CategoryMapping,
+    fit_categories: bool,
+    label: Option<String>,
+    label_mapping: Option<LabelMapping>,
+    weight: Option<String>,
+    class_weights: Option<HashMap<String, f32>>,
+    base_margin: Option<String>,
+    group: Option<String>,
+    layout: Option<FeatureLayout>,
+    fixed_layout: bool,
+    data: Vec<f32>,
+    labels: Vec<f32>,
+    weights: Vec<f32>,
+    base_margins: Vec<f32>,
+    group_sizes: Vec<u32>,
+    group_keys: HashSet<String>,
+    last_group: Option<String>,
+    num_rows: usize,
+}
+
+impl DMatrixBuilder {
+    /// A builder fitting the category mapping on the appended batches.
+    pub fn new() -> Self {
+        DMatrixBuilder {
+            fit_categories: true,
+            ..Default::default()
+        }
+    }
+
+    /// A builder encoding categorical columns with `categories`, e.g. the
+    /// mapping saved with a model.
+    pub fn with_categories(categories: CategoryMapping) -> Self {
+        DMatrixBuilder {
+            categories,
+            fit_categories: false,
+            ..Default::default()
+        }
+    }
+
+    /// Encodes the features with `layout`, e.g. the one saved with a model,
+    /// instead of merging the layouts of the batches. The `onehot` keys of a
+    /// batch may come in any order or be missing, but keys that `layout` does
+    /// not have fail.
+    pub fn with_layout(mut self, layout: FeatureLayout) -> Self {
+        self.layout = Some(layout);
+        self.fixed_layout = true;
+        self
+    }
+
+    /// Takes the labels from the numeric or boolean column `name`. String
+    /// labels also need a [`label_mapping`](Self::label_mapping).
+    pub fn label(mut self, name: impl Into<String>) -> Self {
+        self.label = Some(name.into());
+        self
+    }
+
+    pub fn label_mapping(mut self, mapping: LabelMapping) -> Self {
+        self.label_mapping = Some(mapping);
+        self
+    }
+
+    /// Takes the sample weights from the numeric column `name`.
+    pub fn weight(mut self, name: impl Into<String>) -> Self {
+        self.weight = Some(name.into());
+        self
+    }
+
+    /// Weighs every row by the weight of its class, multiplied with the
+    /// [`weight`](Self::weight) column if there is one. Classes are the label
+    /// values cast to strings, e.g. `fraud`, `1` or `true`; rows of other
+    /// classes keep their weight.
+    pub fn class_weights(mut self, weights: impl IntoIterator<Item = (String, f32)>) -> Self {
+        self.class_weights = Some(weights.into_iter().collect());
+        self
+    }
+
+    /// Takes the initial margin of every row from the numeric column `name`.
+    pub fn base_margin(mut self, name: impl Into<String>) -> Self {
+        self.base_margin = Some(name.into());
+        self
+    }
+
+    /// Takes the query group of every row from the column `name`, for
+    /// ranking objectives. The rows of a group must be contiguous, e.g. by
+    /// ordering the query by the group column; a group continues across
+    /// batches.
+    pub fn group(mut self, name: impl Into<String>) -> Self {
+        self.group = Some(name.into());
+        self
+    }
+
+    pub fn append(&mut self, batch: &RecordBatch) -> Result<()> {
+        if batch.num_rows() == 0 {
+            return Ok(());
+        }
+        let schema = batch.schema();
+        let mut info_indices = Vec::new();
+        let mut class_weights = None;
+        let labels = match &self.label {
+            Some(name) => {
+                let index = schema.index_of(name)?;
+                info_indices.push(index);
+                let column = batch.column(index);
+                if let Some(weights) = &self.class_weights {
+                    class_weights = Some(row_class_weights(column, weights)?);
+                }
+                match &self.label_mapping {
+                    Some(mapping) if is_string_label(column.data_type()) => {
+                        mapping.encode(column)?
+                    }
+                    _ => label_values(column)?,
+                }
+            }
+            None if self.class_weights.is_some() => {
+                return Err(DataFusionError::Plan(
+                    "Class weights need a label column".to_string(),
+                ))
+            }
+            None => vec![],
+        };
+        let mut info = |name: &Option<String>, kind: &str| -> Result<Vec<f32>> {
+            match name {
+                Some(name) => {
+                    let index = schema.index_of(name)?;
+                    info_indices.push(index);
+                    float_values(kind, batch.column(index))
+                }
+                None => Ok(vec![]),
+            }
+        };
+        let mut weights = info(&self.weight, "Weight")?;
+        if let Some(class_weights) = class_weights {
+            weights = if weights.is_empty() {
+                class_weights
+            } else {
+                weights
+                    .iter()
+                    .zip(class_weights)
+                    .map(|(w, c)| w * c)
+                    .collect()
+            };
+        }
+        let base_margins = info(&self.base_margin, "Base margin")?;
+        let groups = match &self.group {
+            Some(name) => {
+                let index = schema.index_of(name)?;
+                info_indices.push(index);
+                group_runs(batch.column(index))?
+            }
+            None => vec![],
+        };
+        let mut batch_groups = HashSet::new();
+        for (i, (group, _)) in groups.iter().enumerate() {
+            let continues_last = i == 0 && self.last_group.as_ref() == Some(group);
+            if !continues_last && (self.group_keys.contains(group) || !batch_groups.insert(group)) {
+                return Err(DataFusionError::Execution(format!(
+                    "Rows of group {} are not contiguous, order the rows by the group column",
+                    group
+                )));
+            }
+        }
+
+        let feature_indices: Vec<usize> = (0..batch.num_columns())
+            .filter(|i| !info_indices.contains(i))
+            .collect();
+        let features = batch.project(&feature_indices)?;
+        let batch_layout = FeatureLayout::of(&features)?;
+        let layout = match &self.layout {
+            Some(expected) => {
+                let merged = expected.merge(&batch_layout)?;
+                if self.fixed_layout {
+                    expected.check(&merged)?;
+                }
+                merged
+            }
+            None => batch_layout.clone(),
+        };
+        if self.fit_categories {
+            self.categories.extend(features.columns())?;
+        }
+
+        let (data, num_rows) = columns_to_features(features.columns(), &self.categories)?;
+        let num_features = layout.num_features();
+        if let Some(previous) = self.layout.as_ref().filter(|previous| **previous != layout) {
+            // widen the rows appended so far with the new onehot keys
+            self.data = scatter(
+                std::mem::take(&mut self.data),
+                self.num_rows,
+                &layout.positions(previous),
+                num_features,
+            );
+        }
+        self.data.extend(scatter(
+            data,
+            num_rows,
+            &layout.positions(&batch_layout),
+            num_features,
+        ));
+        self.layout = Some(layout);
+        self.labels.extend(labels);
+        self.weights.extend(weights);
+        self.base_margins.extend(base_margins);
+        for (i, (group, size)) in groups.into_iter().enumerate() {
+            if i == 0 && self.last_group.as_ref() == Some(&group) {
+                *self.group_sizes.last_mut().unwrap() += size;
+            } else {
+                self.group_sizes.push(size);
+                self.group_keys.insert(group.clone());
+                self.last_group = Some(group);
+            }
+        }
+        self.num_rows += num_rows;
+        Ok(())
+    }
+
+    pub fn append_batches(&mut self, batches: &[RecordBatch]) -> Result<()> {
+        batches.iter().try_for_each(|batch| self.append(batch))
+    }
+
+    /// Appends batches as they are produced by `stream`.
+    pub async fn append_stream(&mut self, mut stream: SendableRecordBatchStream) -> Result<()> {
+        while let Some(batch) = stream.next().await {
+            self.append(&batch?)?;
+        }
+        Ok(())
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.num_rows
+    }
+
+    /// Layout of the appended batches, `None` until a row was appended.
+    pub fn layout(&self) -> Option<&FeatureLayout> {
+        self.layout.as_ref()
+    }
+
+    pub fn categories(&self) -> &CategoryMapping {
+        &self.categories
+    }
+
+    pub fn build(&self) -> Result<DMatrix> {
+        if self.num_rows == 0 {
+            return Err(DataFusionError::Internal("No rows to convert".to_string()));
+        }
+        let mut dmat = DMatrix::from_dense(&self.data, self.num_rows)
+            .map_err(|_| DataFusionError::Internal("Failed to create dmatrix".to_string()))?;
+        if self.label.is_some() {
+            dmat.set_labels(&self.labels).map_err(xgb_error)?;
+        }
+        if self.weight.is_some() || self.class_weights.is_some() {
+            dmat.set_weights(&self.weights).map_err(xgb_error)?;
+        }
+        if self.base_margin.is_some() {
+            dmat.set_base_margin(&self.base_margins)
+                .map_err(xgb_error)?;
+        }
+        if self.group.is_some() {
+            dmat.set_group(&self.group_sizes).map_err(xgb_error)?;
+        }
+        Ok(dmat)
+    }
+}
+
+/// Builds a `DMatrix` from all rows of `batches`, see [`DMatrixBuilder`].
+pub fn create_dmatrix_from_batches(batches: &[RecordBatch]) -> Result<DMatrix> {
+    let mut builder = DMatrixBuilder::new();
+    builder.append_batches(batches)?;
+    builder.build()
+}
+
+/// Builds a `DMatrix` from a stream without collecting its batches first.
+pub async fn create_dmatrix_from_stream(stream: SendableRecordBatchStream) -> Result<DMatrix> {
+    let mut builder = DMatrixBuilder::new();
+    builder.append_stream(stream).await?;
+    builder.build()
+}
+
+/// Builds a `DMatrix` from all rows of `df`.
+pub async fn create_dmatrix_from_dataframe(df: DataFrame) -> Result<DMatrix> {
+    create_dmatrix_from_stream(df.execute_stream().await?).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use datafusion::arrow::array::{Float32Array, Int64Array, StringDictionaryBuilder};
+    use datafusion::arrow::datatypes::Int32Type;
+    use std::sync::Arc;
+
+    fn batch(values: &[&str], onehot: bool) -> Result<RecordBatch> {
+        let mut builder = StringDictionaryBuilder::<Int32Type>::new();
+        for value in values {
+            builder.append(value)?;
+        }
+        let column: ArrayRef = Arc::new(builder.finish());
+        let column = if onehot {
+            crate::onehot(&[column])?
+        } else {
+            column
+        };
+        Ok(RecordBatch::try_from_iter(vec![("color", column)])?)
+    }
+
+    #[tokio::test]
+    pub async fn test_appends_batches() -> Result<()> {
+        let mut builder = DMatrixBuilder::new();
+        builder.append_batches(&[
+            batch(&["red", "blue"], false)?,
+            batch(&["green", "red", "red"], false)?,
+        ])?;
+        assert_eq!(builder.num_rows(), 5);
+        assert_eq!(
+            builder.categories().vocabulary(0).unwrap(),
+            &["red", "blue", "green"]
+        );
+        assert_eq!(builder.build()?.shape(), (5, 1));
+
+        let mut builder = DMatrixBuilder::new();
+        builder.append(&batch(&["red", "blue"], true)?)?;
+        builder.append(&batch(&["red", "blue", "red"], true)?)?;
+        assert_eq!(
+            builder.layout().unwrap().feature_names(),
+            vec!["color=red", "color=blue"]
+        );
+        assert_eq!(builder.build()?.shape(), (5, 2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_merges_onehot_keys() -> Result<()> {
+        let mut builder = DMatrixBuilder::new();
+        builder.append(&batch(&["red", "blue"], true)?)?;
+        builder.append(&batch(&["green", "blue"], true)?)?;
+        assert_eq!(
+            builder.layout().unwrap().feature_names(),
+            vec!["color=red", "color=blue", "color=green"]
+        );
+        #[rustfmt::skip]
+        assert_eq!(builder.data, vec![
+            1.0, 0.0, 0.0,
+            0.0, 1.0, 0.0,
+            0.0, 0.0, 1.0,
+            0.0, 1.0, 0.0,
+        ]);
+        assert_eq!(builder.build()?.shape(), (4, 3));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_rejects_different_layouts() -> Result<()> {
+        let mut builder = DMatrixBuilder::new();
+        builder.append(&batch(&["red", "blue"], true)?)?;
+        let error = builder.append(&batch(&["red"], false)?).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("column color is onehot [red, blue], got categorical"));
+        assert_eq!(builder.num_rows(), 2);
+
+        // a fixed layout takes keys in any order, but no new ones
+        let layout = builder.layout().unwrap().clone();
+        let mut builder = DMatrixBuilder::new().with_layout(layout);
+        builder.append(&batch(&["blue", "red"], true)?)?;
+        assert_eq!(builder.data, vec![0.0, 1.0, 1.0, 0.0]);
+        assert!(builder.append(&batch(&["green"], true)?).is_err());
+        assert_eq!(builder.num_rows(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_group_column() -> Result<()> {
+        let group_batch = |groups: Vec<i64>| -> Result<RecordBatch> {
+            let colors: Vec<&str> = groups.iter().map(|_| "red").collect();
+            let column: ArrayRef = Arc::new(Int64Array::from(groups));
+            let mut columns = batch(&colors, false)?.columns().to_vec();
+            columns.push(column);
+            Ok(RecordBatch::try_from_iter(
+                ["color", "qid"].into_iter().zip(columns),
+            )?)
+        };
+        let mut builder = DMatrixBuilder::new().group("qid");
+        builder.append(&group_batch(vec![7, 7, 3])?)?;
+        builder.append(&group_batch(vec![3, 5, 5, 5])?)?;
+        assert_eq!(builder.layout().unwrap().feature_names(), vec!["color"]);
+        assert_eq!(builder.group_sizes, vec![2, 2, 3]);
+        assert_eq!(builder.build()?.shape(), (7, 1));
+
+        let error = builder.append(&group_batch(vec![5, 7])?).unwrap_err();
+        assert!(error.to_string().contains("group 7 are not contiguous"));
+        let mut builder = DMatrixBuilder::new().group("qid");
+        assert!(builder.append(&group_batch(vec![1, 2, 1])?).is_err());
+        assert_eq!(builder.num_rows(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_layout_is_saved_with_model() -> Result<()> {
+        let onehot = FeatureLayout::of(&batch(&["red", "blue"], true)?)?;
+        let categorical = FeatureLayout::of(&batch(&["red", "blue"], false)?)?;
+        let mut booster = Booster::new(&Default::default()).map_err(xgb_error)?;
+        assert_eq!(FeatureLayout::load(&booster)?, None);
+        onehot.save(&mut booster)?;
+        assert_eq!(FeatureLayout::load(&booster)?, Some(onehot));
+        categorical.save(&mut booster)?;
+        assert_eq!(FeatureLayout::load(&booster)?, Some(categorical));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_label_weight_and_base_margin_columns() -> Result<()> {
+        let features = batch(&["red", "blue", "red"], false)?;
+        let class: ArrayRef = Arc::new(StringArray::from(vec!["yes", "no", "yes"]));
+        let weight: ArrayRef = Arc::new(Float32Array::from(vec![1.0, 2.0, 0.5]));
+        let margin: ArrayRef = Arc::new(Float32Array::from(vec![0.1, 0.2, 0.3]));
+        let data = RecordBatch::try_from_iter(vec![
+            ("class", class),
+            ("color", features.column(0).clone()),
+            ("w", weight),
+            ("m", margin),
+        ])?;
+
+        let mut builder = DMatrixBuilder::new()
+            .label("class")
+            .label_mapping(LabelMapping::new(["no", "yes"]))
+            .weight("w")
+            .base_margin("m");
+        builder.append(&data)?;
+        assert_eq!(builder.layout().unwrap().feature_names(), vec!["color"]);
+        let dmat = builder.build()?;
+        assert_eq!(dmat.shape(), (3, 1));
+        assert_eq!(dmat.get_labels().unwrap(), &[1.0, 0.0, 1.0]);
+        assert_eq!(dmat.get_weights().unwrap(), &[1.0, 2.0, 0.5]);
+        assert_eq!(dmat.get_base_margin().unwrap(), &[0.1, 0.2, 0.3]);
+
+        let mut builder = DMatrixBuilder::new().label("class");
+        assert!(builder.append(&data).is_err());
+
+        let mut builder = DMatrixBuilder::new()
+            .label("class")
+            .label_mapping(LabelMapping::new(["no", "yes"]))
+            .weight("w")
+            .class_weights([("no".to_string(), 3.0)]);
+        builder.append(&data.project(&[0, 1, 2])?)?;
+        assert_eq!(builder.build()?.get_weights().unwrap(), &[1.0, 6.0, 0.5]);
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+use crate::iteration::{margin_model_range, predict_model_range, ModelVersion};
+use crate::{features_dmatrix, load_model, xgb_error, CategoryMapping, IterationRange};
+use datafusion::arrow::array::{ArrayRef, Float32Array};
+use datafusion::arrow::datatypes::DataType;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_expr::{ReturnTypeFunction, ScalarUDF, Signature, Volatility};
+use datafusion::physical_plan::functions::make_scalar_function;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use xgboost::{Booster, DMatrix};
+
+/// How the predictions of the members of an [`Ensemble`] are combined.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Combination {
+    /// Mean of the member predictions.
+    Average,
+    /// Mean of the member predictions weighted by one weight per member.
+    Weighted(Vec<f32>),
+    /// Prediction of a meta-model whose features are the member margins, in
+    /// member order, see [`Ensemble::margins`].
+    Stacked(PathBuf),
+}
+
+/// Several models scoring the same features, combined into one prediction.
+///
+/// The members must be trained on the same features and categories, so the
+/// feature matrix of a batch is built once and evaluated by every member.
+#[derive(Debug, Clone)]
+pub struct Ensemble {
+    members: Vec<PathBuf>,
+    combination: Combination,
+}
+
+impl Ensemble {
+    pub fn new(members: &[impl AsRef<Path>], combination: Combination) -> Result<Self> {
+        if members.is_empty() {
+            return Err(DataFusionError::Plan(
+                "An ensemble needs at least one model".to_string(),
+            ));
+        }
+        if let Combination::Weighted(weights) = &combination {
+            if weights.len() != members.len() {
+                return Err(DataFusionError::Plan(format!(
+                    "An ensemble of {} models needs {} weights, got {}",
+                    members.len(),
+                    members.len(),
+                    weights.len()
+                )));
+            }
+            if weights.iter().any(|weight| *weight < 0.0) || weights.iter().sum::<f32>() <= 0.0 {
+                return Err(DataFusionError::Plan(
+                    "Ensemble weights must be non-negative with a positive sum".to_string(),
+                ));
+            }
+        }
+        Ok(Ensemble {
+            members: members
+                .iter()
+                .map(|path| path.as_ref().to_path_buf())
+                .collect(),
+            combination,
+        })
+    }
+
+    /// Combined predictions for the feature arguments of a UDF call.
+    pub fn predict(&self, args: &[ArrayRef]) -> Result<Vec<f32>> {
+        if args.first().is_some_and(|arg| arg.is_empty()) {
+            return Ok(Vec::new());
+        }
+        let members = self.load_members()?;
+        let (dmat, num_rows) = self.dmatrix(args, &members)?;
+        let weights = match &self.combination {
+            Combination::Average => vec![1.0; members.len()],
+            Combination::Weighted(weights) => weights.clone(),
+            Combination::Stacked(meta_model) => {
+                let margins = member_margins(&members, &dmat, num_rows)?;
+                let meta = DMatrix::from_dense(&margins, num_rows).map_err(xgb_error)?;
+                return load_model(meta_model)?.predict(&meta).map_err(xgb_error);
+            }
+        };
+
+        let total: f32 = weights.iter().sum();
+        let mut result = vec![0.0; num_rows];
+        for ((booster, version), weight) in members.iter().zip(weights) {
+            let predictions =
+                predict_model_range(booster, version, &dmat, IterationRange::default())?;
+            for (sum, prediction) in result.iter_mut().zip(predictions) {
+                *sum += weight * prediction;
+            }
+        }
+        Ok(result.into_iter().map(|sum| sum / total).collect())
+    }
+
+    /// Row-major margins of the members for the feature arguments of a UDF
+    /// call, one column per member, with the number of rows. These are the
+    /// features a [`Combination::Stacked`] meta-model is trained on.
+    pub fn margins(&self, args: &[ArrayRef]) -> Result<(Vec<f32>, usize)> {
+        if args.first().is_some_and(|arg| arg.is_empty()) {
+            return Ok((Vec::new(), 0));
+        }
+        let members = self.load_members()?;
+        let (dmat, num_rows) = self.dmatrix(args, &members)?;
+        Ok((member_margins(&members, &dmat, num_rows)?, num_rows))
+    }
+
+    fn load_members(&self) -> Result<Vec<(Booster, ModelVersion)>> {
+        self.members
+            .iter()
+            .map(|path| ModelVersion::load(path))
+            .collect()
+    }
+
+    /// The feature matrix shared by all members, encoded with the categories
+    /// they were trained on.
+    fn dmatrix(
+        &self,
+        args: &[ArrayRef],
+        members: &[(Booster, ModelVersion)],
+    ) -> Result<(DMatrix, usize)> {
+        let mut categories = members
+            .iter()
+            .map(|(booster, _)| CategoryMapping::load(booster));
+        let first = categories.next().transpose()?.flatten();
+        for (i, other) in categories.enumerate() {
+            if other? != first {
+                return Err(DataFusionError::Plan(format!(
+                    "Ensemble member {} was trained on other categories than {}",
+                    self.members[i + 1].display(),
+                    self.members[0].display()
+                )));
+            }
+        }
+        features_dmatrix(args, &CategoryMapping::for_scoring(first, args)?)
+    }
+}
+
+fn member_margins(
+    members: &[(Booster, ModelVersion)],
+    dmat: &DMatrix,
+    num_rows: usize,
+) -> Result<Vec<f32>> {
+    let mut margins = vec![0.0; num_rows * members.len()];
+    for (member, (booster, version)) in members.iter().enumerate() {
+        let member_margins = margin_model_range(booster, version, dmat, IterationRange::default())?;
+        for (row, margin) in member_margins.into_iter().enumerate() {
+            margins[row * members.len() + member] = margin;
+        }
+    }
+    Ok(margins)
+}
+
+/// Creates a UDF scoring its feature arguments with `ensemble`, e.g.
+/// `predict_ensemble(f1, ..., fn)` with the features of [`predict_udf`](crate::predict_udf).
+pub fn predict_ensemble_udf(name: &str, ensemble: Ensemble) -> ScalarUDF {
+    let predict = make_scalar_function(move |args: &[ArrayRef]| {
+        Ok(Arc::new(Float32Array::from(ensemble.predict(args)?)) as ArrayRef)
+    });
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Float32)));
+    ScalarUDF::new(
+        name,
+        &Signature::variadic_any(Volatility::Immutable),
+        &return_type,
+        &predict,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_ensemble_weights() -> Result<()> {
+        let members = ["a.xgb", "b.xgb"];
+        assert!(Ensemble::new(&members, Combination::Weighted(vec![1.0, 3.0])).is_ok());
+        let error = Ensemble::new(&members, Combination::Weighted(vec![1.0]))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("needs 2 weights, got 1"));
+        assert!(Ensemble::new(&members, Combination::Weighted(vec![0.0, 0.0])).is_err());
+        assert!(Ensemble::new(&[] as &[&str], Combination::Average).is_err());
+
+        // an empty batch is not scored, so the models are not loaded
+        let ensemble = Ensemble::new(&members, Combination::Average)?;
+        let empty: ArrayRef = Arc::new(Float32Array::from(Vec::<f32>::new()));
+        assert!(ensemble.predict(std::slice::from_ref(&empty))?.is_empty());
+        assert_eq!(ensemble.margins(&[empty])?, (vec![], 0));
+        Ok(())
+    }
+}
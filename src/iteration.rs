@@ -0,0 +1,479 @@
+use crate::xgb_error;
+use datafusion::arrow::array::{Array, ArrayRef, Int64Array};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::DataType;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_expr::{ColumnarValue, ScalarFunctionImplementation};
+use datafusion::physical_plan::functions::make_scalar_function;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use xgboost::{Booster, DMatrix};
+
+/// Half-open range of trees `[begin, end)` used to score a model. For
+/// single-output models every boosting round adds one tree, so this is also
+/// the range of boosting rounds. `end` of `None` means up to the last tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IterationRange {
+    pub begin: usize,
+    pub end: Option<usize>,
+}
+
+impl IterationRange {
+    /// Uses only the first `n` trees of the model.
+    pub fn first(n: usize) -> Self {
+        IterationRange {
+            begin: 0,
+            end: Some(n),
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.begin == 0 && self.end.is_none()
+    }
+
+    /// Splits trailing integer arguments of a scoring UDF off the feature
+    /// columns: `predict(f1, ..., fn, end)` scores with the first `end`
+    /// trees and `predict(f1, ..., fn, begin, end)` with trees in between.
+    /// The bounds are read from the first row, see [`scoring_function`] for
+    /// the UDFs that only accept them as literals.
+    pub fn from_args(args: &[ArrayRef]) -> Result<(&[ArrayRef], Self)> {
+        let num_features = args
+            .iter()
+            .rposition(|arg| !arg.data_type().is_integer())
+            .map_or(0, |i| i + 1);
+        let (features, range) = args.split_at(num_features);
+        if range.iter().any(|arg| arg.is_empty()) {
+            // no rows to score, so the bounds do not matter
+            return Ok((features, IterationRange::default()));
+        }
+        let bounds = range
+            .iter()
+            .map(|arg| {
+                let arg = cast(arg, &DataType::Int64)?;
+                let arg = arg
+                    .as_any()
+                    .downcast_ref::<Int64Array>()
+                    .ok_or_else(|| DataFusionError::Internal("Expected Int64Array".to_string()))?;
+                if arg.is_null(0) || arg.value(0) < 0 {
+                    return Err(DataFusionError::Execution(
+                        "Iteration range bounds must be non-negative integers".to_string(),
+                    ));
+                }
+                Ok(arg.value(0) as usize)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let range = match bounds[..] {
+            [] => IterationRange::default(),
+            [end] => IterationRange::first(end),
+            [begin, end] if begin < end => IterationRange {
+                begin,
+                end: Some(end),
+            },
+            [begin, end] => {
+                return Err(DataFusionError::Execution(format!(
+                    "Invalid iteration range [{}, {})",
+                    begin, end
+                )))
+            }
+            _ => {
+                return Err(DataFusionError::Execution(
+                    "Expected at most two iteration range arguments".to_string(),
+                ))
+            }
+        };
+        Ok((features, range))
+    }
+}
+
+/// Like `make_scalar_function`, for scoring UDFs whose trailing numeric
+/// arguments, iteration range bounds and the `predict_label` threshold, are
+/// options of the call rather than features. Those are read from the first
+/// row, so they must be literals; a column in their place is rejected
+/// instead of scoring every row with the value of the first one.
+pub(crate) fn scoring_function(
+    score: impl Fn(&[ArrayRef]) -> Result<ArrayRef> + Send + Sync + 'static,
+) -> ScalarFunctionImplementation {
+    let score = make_scalar_function(score);
+    Arc::new(move |args: &[ColumnarValue]| {
+        if args
+            .iter()
+            .rev()
+            .take_while(|arg| arg.data_type().is_numeric())
+            .any(|arg| matches!(arg, ColumnarValue::Array(_)))
+        {
+            return Err(DataFusionError::Plan(
+                "Iteration range bounds and thresholds must be literals, not columns".to_string(),
+            ));
+        }
+        score(args)
+    })
+}
+
+/// Leaf value of every node id of every tree of a model.
+type LeafValues = Vec<HashMap<usize, f32>>;
+
+/// The model file a booster was loaded from, with a hash of the bytes that
+/// were loaded. A file rewritten after loading gets another hash, so the
+/// leaf values cached for a version always belong to the loaded booster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ModelVersion {
+    path: PathBuf,
+    digest: u64,
+}
+
+impl ModelVersion {
+    /// Loads the booster saved at `model_path` with the version it was
+    /// loaded from.
+    pub(crate) fn load(model_path: &Path) -> Result<(Booster, Self)> {
+        let load_error = |e: &dyn std::fmt::Display| {
+            DataFusionError::Internal(format!("Failed to load model: {}", e))
+        };
+        let bytes = fs::read(model_path).map_err(|e| load_error(&e))?;
+        let booster = Booster::load_buffer(&bytes).map_err(|e| load_error(&e))?;
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        Ok((
+            booster,
+            ModelVersion {
+                path: model_path.to_path_buf(),
+                digest: hasher.finish(),
+            },
+        ))
+    }
+}
+
+/// Predicts with the trees in `range` only.
+///
+/// The xgboost crate does not expose `ntree_limit`, so the margin of the
+/// trees outside the range is computed from the leaf each row lands in and
+/// the leaf values of the model dump, subtracted from the full margin and
+/// passed through the output transform of the model's objective. The dump
+/// rounds leaf values, so predictions of a partial range are approximate,
+/// with an error that grows with the number of trees outside the range.
+pub fn predict_range(booster: &Booster, dmat: &DMatrix, range: IterationRange) -> Result<Vec<f32>> {
+    predict_trees(booster, dmat, range, || parse_leaf_values(booster))
+}
+
+/// Untransformed predictions of the trees in `range` only, approximate for
+/// partial ranges like [`predict_range`].
+pub fn margin_range(booster: &Booster, dmat: &DMatrix, range: IterationRange) -> Result<Vec<f32>> {
+    margin_trees(booster, dmat, range, || parse_leaf_values(booster))
+}
+
+/// [`predict_range`] of a booster loaded with [`ModelVersion::load`], whose
+/// leaf values are parsed once per model version rather than for every batch.
+pub(crate) fn predict_model_range(
+    booster: &Booster,
+    version: &ModelVersion,
+    dmat: &DMatrix,
+    range: IterationRange,
+) -> Result<Vec<f32>> {
+    predict_trees(booster, dmat, range, || {
+        cached_leaf_values(booster, version)
+    })
+}
+
+/// [`margin_range`] of a booster loaded with [`ModelVersion::load`], see
+/// [`predict_model_range`].
+pub(crate) fn margin_model_range(
+    booster: &Booster,
+    version: &ModelVersion,
+    dmat: &DMatrix,
+    range: IterationRange,
+) -> Result<Vec<f32>> {
+    margin_trees(booster, dmat, range, || {
+        cached_leaf_values(booster, version)
+    })
+}
+
+fn predict_trees(
+    booster: &Booster,
+    dmat: &DMatrix,
+    range: IterationRange,
+    leaf_values: impl FnOnce() -> Result<Arc<LeafValues>>,
+) -> Result<Vec<f32>> {
+    if range.is_full() {
+        return booster.predict(dmat).map_err(xgb_error);
+    }
+
+    let margins = booster.predict_margin(dmat).map_err(xgb_error)?;
+    let transform = match booster.get_attribute("objective").map_err(xgb_error)? {
+        Some(objective) => OutputTransform::of_objective(&objective)?,
+        None => OutputTransform::infer(&margins, &booster.predict(dmat).map_err(xgb_error)?)?,
+    };
+    let margins = exclude_trees(booster, dmat, margins, range, &*leaf_values()?)?;
+    Ok(margins
+        .into_iter()
+        .map(|margin| transform.apply(margin))
+        .collect())
+}
+
+fn margin_trees(
+    booster: &Booster,
+    dmat: &DMatrix,
+    range: IterationRange,
+    leaf_values: impl FnOnce() -> Result<Arc<LeafValues>>,
+) -> Result<Vec<f32>> {
+    let margins = booster.predict_margin(dmat).map_err(xgb_error)?;
+    if range.is_full() {
+        return Ok(margins);
+    }
+    exclude_trees(booster, dmat, margins, range, &*leaf_values()?)
+}
+
+/// Subtracts the leaf values of the trees outside `range` from the full
+/// `margins`.
+fn exclude_trees(
+    booster: &Booster,
+    dmat: &DMatrix,
+    mut margins: Vec<f32>,
+    range: IterationRange,
+    leaf_values: &LeafValues,
+) -> Result<Vec<f32>> {
+    let (leaves, (_, num_trees)) = booster.predict_leaf(dmat).map_err(xgb_error)?;
+    let end = range.end.unwrap_or(num_trees);
+    if end > num_trees {
+        return Err(DataFusionError::Execution(format!(
+            "Iteration range ends at {} but the model has {} trees",
+            end, num_trees
+        )));
+    }
+    if leaf_values.len() != num_trees {
+        return Err(DataFusionError::Internal(format!(
+            "Expected leaf values of {} trees, got {}",
+            num_trees,
+            leaf_values.len()
+        )));
+    }
+
+    for (row, margin) in margins.iter_mut().enumerate() {
+        for tree in (0..range.begin).chain(end..num_trees) {
+            let leaf = leaves[row * num_trees + tree] as usize;
+            *margin -= leaf_values[tree].get(&leaf).ok_or_else(|| {
+                DataFusionError::Internal(format!("Leaf {} not found in tree {}", leaf, tree))
+            })?;
+        }
+    }
+    Ok(margins)
+}
+
+fn parse_leaf_values(booster: &Booster) -> Result<Arc<LeafValues>> {
+    Ok(Arc::new(leaf_values(
+        &booster.dump_model(false, None).map_err(xgb_error)?,
+    )?))
+}
+
+/// The leaf values of `booster`, loaded as `version`. Only the last version
+/// of every model file is cached, so a model file that is replaced is parsed
+/// again.
+fn cached_leaf_values(booster: &Booster, version: &ModelVersion) -> Result<Arc<LeafValues>> {
+    type Cache = Mutex<HashMap<PathBuf, (u64, Arc<LeafValues>)>>;
+    static CACHE: OnceLock<Cache> = OnceLock::new();
+
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some((digest, leaf_values)) = cache
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&version.path)
+    {
+        if *digest == version.digest {
+            return Ok(leaf_values.clone());
+        }
+    }
+    let leaf_values = parse_leaf_values(booster)?;
+    cache
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(version.path.clone(), (version.digest, leaf_values.clone()));
+    Ok(leaf_values)
+}
+
+/// Number of trees of `booster`, which is the number of boosting rounds for
+/// single-output models.
+pub(crate) fn num_trees(booster: &Booster) -> Result<usize> {
+    Ok(leaf_values(&booster.dump_model(false, None).map_err(xgb_error)?)?.len())
+}
+
+/// Parses the text model dump into a map of node id to leaf value per tree.
+fn leaf_values(dump: &str) -> Result<LeafValues> {
+    let mut trees: LeafValues = Vec::new();
+    for line in dump.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (id, node) = line
+            .split_once(':')
+            .ok_or_else(|| DataFusionError::Internal(format!("Unexpected dump line {}", line)))?;
+        let id: usize = id
+            .parse()
+            .map_err(|_| DataFusionError::Internal(format!("Unexpected node id {}", id)))?;
+        if id == 0 {
+            trees.push(HashMap::new());
+        }
+        if let Some(value) = node.strip_prefix("leaf=") {
+            let value = value
+                .parse()
+                .map_err(|_| DataFusionError::Internal(format!("Unexpected leaf {}", value)))?;
+            trees
+                .last_mut()
+                .ok_or_else(|| DataFusionError::Internal("Leaf outside of a tree".to_string()))?
+                .insert(id, value);
+        }
+    }
+    Ok(trees)
+}
+
+/// Maps margins to predictions. This XGBoost version cannot report the
+/// objective of a loaded model, so it is taken from the `objective`
+/// attribute saved by training, or else recognised by comparing the full
+/// margins with the full predictions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputTransform {
+    Identity,
+    Sigmoid,
+    Exp,
+}
+
+impl OutputTransform {
+    fn of_objective(objective: &str) -> Result<Self> {
+        match objective {
+            "reg:linear" | "reg:squarederror" | "binary:logitraw" | "rank:pairwise" => {
+                Ok(OutputTransform::Identity)
+            }
+            "reg:logistic" | "binary:logistic" => Ok(OutputTransform::Sigmoid),
+            "count:poisson" | "reg:gamma" | "reg:tweedie" | "survival:cox" => {
+                Ok(OutputTransform::Exp)
+            }
+            _ => Err(DataFusionError::Execution(format!(
+                "Iteration ranges are not supported for {}",
+                objective
+            ))),
+        }
+    }
+
+    fn infer(margins: &[f32], predictions: &[f32]) -> Result<Self> {
+        [
+            OutputTransform::Identity,
+            OutputTransform::Sigmoid,
+            OutputTransform::Exp,
+        ]
+        .into_iter()
+        .find(|transform| {
+            margins.iter().zip(predictions).all(|(margin, prediction)| {
+                (transform.apply(*margin) - prediction).abs() <= 1e-5 * prediction.abs().max(1.0)
+            })
+        })
+        .ok_or_else(|| {
+            DataFusionError::Execution(
+                "Iteration ranges are only supported for single-output objectives".to_string(),
+            )
+        })
+    }
+
+    fn apply(&self, margin: f32) -> f32 {
+        match self {
+            OutputTransform::Identity => margin,
+            OutputTransform::Sigmoid => 1.0 / (1.0 + (-margin).exp()),
+            OutputTransform::Exp => margin.exp(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use datafusion::arrow::array::{Int64Array, StringArray};
+
+    #[tokio::test]
+    pub async fn test_iteration_range_from_args() -> Result<()> {
+        let feature: ArrayRef = Arc::new(StringArray::from(vec!["a", "b"]));
+        let bound = |v: i64| Arc::new(Int64Array::from(vec![v, v])) as ArrayRef;
+
+        let args = [feature.clone()];
+        let (features, range) = IterationRange::from_args(&args)?;
+        assert_eq!(features.len(), 1);
+        assert!(range.is_full());
+
+        let args = [feature.clone(), bound(3)];
+        let (features, range) = IterationRange::from_args(&args)?;
+        assert_eq!(features.len(), 1);
+        assert_eq!(range, IterationRange::first(3));
+
+        let args = [feature.clone(), bound(1), bound(3)];
+        let (_, range) = IterationRange::from_args(&args)?;
+        assert_eq!(range.begin, 1);
+        assert_eq!(range.end, Some(3));
+
+        let args = [feature, bound(3), bound(1)];
+        assert!(IterationRange::from_args(&args).is_err());
+
+        let empty: ArrayRef = Arc::new(StringArray::from(Vec::<&str>::new()));
+        let args = [
+            empty,
+            Arc::new(Int64Array::from(Vec::<i64>::new())) as ArrayRef,
+        ];
+        let (features, range) = IterationRange::from_args(&args)?;
+        assert_eq!(features.len(), 1);
+        assert!(range.is_full());
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_scoring_function_needs_literal_options() -> Result<()> {
+        let score = scoring_function(|args| Ok(args[0].clone()));
+        let feature = ColumnarValue::Array(Arc::new(StringArray::from(vec!["a", "b"])));
+        let bound = ColumnarValue::Scalar(10i64.into());
+        let threshold = ColumnarValue::Scalar(0.7f64.into());
+        assert!(score(&[feature.clone(), bound.clone(), threshold]).is_ok());
+
+        let column = ColumnarValue::Array(Arc::new(Int64Array::from(vec![10, 20])));
+        let error = score(&[feature.clone(), column.clone()]).unwrap_err();
+        assert!(error.to_string().contains("must be literals"));
+        assert!(score(&[feature, column, bound]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_output_transform_of_objective() -> Result<()> {
+        let transform = |objective: &str| OutputTransform::of_objective(objective);
+        assert_eq!(transform("reg:squarederror")?, OutputTransform::Identity);
+        assert_eq!(transform("binary:logistic")?.apply(0.0), 0.5);
+        assert_eq!(transform("count:poisson")?.apply(0.0), 1.0);
+        assert_eq!(transform("reg:tweedie")?, OutputTransform::Exp);
+        assert!(transform("multi:softprob").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_leaf_values() -> Result<()> {
+        let dump =
+            "0:[f1<0.5] yes=1,no=2,missing=1\n\t1:leaf=0.25\n\t2:leaf=-0.5\n\n0:leaf=0.125\n";
+        let trees = leaf_values(dump)?;
+        assert_eq!(trees.len(), 2);
+        assert_eq!(trees[0][&1], 0.25);
+        assert_eq!(trees[0][&2], -0.5);
+        assert_eq!(trees[1][&0], 0.125);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_leaf_values_are_cached_per_model() -> Result<()> {
+        let (booster, version) = ModelVersion::load(Path::new("model.xgb"))?;
+        let first = cached_leaf_values(&booster, &version)?;
+        let second = cached_leaf_values(&booster, &version)?;
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.len(), num_trees(&booster)?);
+
+        // other bytes at the same path are another version, parsed again
+        let rewritten = ModelVersion {
+            digest: version.digest.wrapping_add(1),
+            ..version.clone()
+        };
+        let third = cached_leaf_values(&booster, &rewritten)?;
+        assert!(!Arc::ptr_eq(&first, &third));
+        assert!(ModelVersion::load(Path::new("missing.xgb")).is_err());
+        Ok(())
+    }
+}
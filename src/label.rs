@@ -0,0 +1,291 @@
+use crate::xgb_error;
+use datafusion::arrow::array::{Array, ArrayRef, Float32Array, Float64Array, StringArray};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::DataType;
+use datafusion::error::{DataFusionError, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use xgboost::Booster;
+
+/// Maps string class labels to the numeric labels XGBoost trains on.
+///
+/// The class order decides what the model predicts: with
+/// `LabelMapping::new(["p", "e"])` a `binary:logistic` model predicts the
+/// probability of `e`. The mapping is saved as a booster attribute so
+/// `predict_label` can turn predictions back into classes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LabelMapping {
+    classes: Vec<String>,
+}
+
+impl LabelMapping {
+    /// Booster attribute holding the serialized classes.
+    pub const ATTRIBUTE: &'static str = "labels";
+
+    /// Class `i` of `classes` gets label `i`.
+    pub fn new<S: Into<String>>(classes: impl IntoIterator<Item = S>) -> Self {
+        LabelMapping {
+            classes: classes.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn classes(&self) -> &[String] {
+        &self.classes
+    }
+
+    pub fn len(&self) -> usize {
+        self.classes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.classes.is_empty()
+    }
+
+    /// Class name of a numeric label.
+    pub fn class(&self, label: usize) -> Option<&str> {
+        self.classes.get(label).map(String::as_str)
+    }
+
+    /// Uses the distinct values of string label columns in sorted order.
+    pub fn fit(columns: &[ArrayRef]) -> Result<Self> {
+        let mut classes = BTreeSet::new();
+        for column in columns {
+            let column = cast(column, &DataType::Utf8)?;
+            let column = column
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .ok_or_else(|| DataFusionError::Internal("Expected StringArray".to_string()))?;
+            classes.extend(column.iter().flatten().map(str::to_string));
+        }
+        Ok(LabelMapping::new(classes))
+    }
+
+    /// Encodes a string or dictionary label column, failing on nulls and on
+    /// classes that are not part of the mapping.
+    pub fn encode(&self, column: &ArrayRef) -> Result<Vec<f32>> {
+        let labels: HashMap<&str, usize> = self
+            .classes
+            .iter()
+            .enumerate()
+            .map(|(label, class)| (class.as_str(), label))
+            .collect();
+
+        let column = cast(column, &DataType::Utf8)?;
+        let column = column
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .ok_or_else(|| DataFusionError::Internal("Expected StringArray".to_string()))?;
+        column
+            .iter()
+            .map(|class| {
+                let class = class.ok_or_else(|| {
+                    DataFusionError::Execution("Label column contains nulls".to_string())
+                })?;
+                labels.get(class).map(|label| *label as f32).ok_or_else(|| {
+                    DataFusionError::Execution(format!(
+                        "Label {} is not one of the classes {:?}",
+                        class, self.classes
+                    ))
+                })
+            })
+            .collect()
+    }
+
+    /// Turns predictions for `num_rows` rows back into classes.
+    ///
+    /// Binary models predict one probability per row and get the second
+    /// class if it is at least `threshold`; margins of `binary:logitraw`
+    /// models go through [`sigmoid`] first, see [`predicts_margins`]. Multiclass models either predict the class
+    /// index or one probability per class, of which the largest wins.
+    pub fn decode(&self, predictions: &[f32], num_rows: usize, threshold: f32) -> Result<ArrayRef> {
+        let labels: Vec<usize> = if num_rows > 0 && predictions.len() == num_rows * self.len() {
+            predictions
+                .chunks(self.len())
+                .map(|scores| {
+                    scores.iter().enumerate().fold(
+                        0,
+                        |best, (i, score)| if *score > scores[best] { i } else { best },
+                    )
+                })
+                .collect()
+        } else if predictions.len() == num_rows && self.len() == 2 {
+            predictions
+                .iter()
+                .map(|score| (*score >= threshold) as usize)
+                .collect()
+        } else if predictions.len() == num_rows {
+            predictions
+                .iter()
+                .map(|label| label.round() as usize)
+                .collect()
+        } else {
+            return Err(DataFusionError::Execution(format!(
+                "Cannot decode {} predictions for {} rows into {} classes",
+                predictions.len(),
+                num_rows,
+                self.len()
+            )));
+        };
+
+        let classes = labels
+            .into_iter()
+            .map(|label| {
+                self.class(label).ok_or_else(|| {
+                    DataFusionError::Execution(format!("Predicted label {} has no class", label))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Arc::new(StringArray::from(classes)))
+    }
+
+    /// Stores the classes on `booster` so they are saved with the model file.
+    pub fn save(&self, booster: &mut Booster) -> Result<()> {
+        let value = serde_json::to_string(&self.classes)
+            .map_err(|e| DataFusionError::Internal(e.to_string()))?;
+        booster
+            .set_attribute(Self::ATTRIBUTE, &value)
+            .map_err(xgb_error)
+    }
+
+    /// Reads the classes saved on `booster`, if the model has them.
+    pub fn load(booster: &Booster) -> Result<Option<Self>> {
+        let value = booster.get_attribute(Self::ATTRIBUTE).map_err(xgb_error)?;
+        value
+            .map(|value| {
+                serde_json::from_str(&value)
+                    .map(|classes| LabelMapping { classes })
+                    .map_err(|e| DataFusionError::Internal(e.to_string()))
+            })
+            .transpose()
+    }
+}
+
+/// Splits a trailing floating point threshold argument of `predict_label`
+/// off the other arguments.
+pub(crate) fn threshold_from_args(args: &[ArrayRef]) -> Result<(&[ArrayRef], Option<f32>)> {
+    match args.split_last() {
+        Some((last, rest)) if last.data_type().is_floating() => {
+            let threshold = cast(last, &DataType::Float64)?;
+            let threshold = threshold
+                .as_any()
+                .downcast_ref::<Float64Array>()
+                .ok_or_else(|| DataFusionError::Internal("Expected Float64Array".to_string()))?;
+            if threshold.is_empty() {
+                // no rows to decode, so the threshold does not matter
+                return Ok((rest, None));
+            }
+            if threshold.is_null(0) {
+                return Err(DataFusionError::Execution(
+                    "Threshold must not be null".to_string(),
+                ));
+            }
+            Ok((rest, Some(threshold.value(0) as f32)))
+        }
+        _ => Ok((args, None)),
+    }
+}
+
+/// Returns true for label columns that need a [`LabelMapping`].
+pub fn is_string_label(data_type: &DataType) -> bool {
+    match data_type {
+        DataType::Utf8 | DataType::LargeUtf8 => true,
+        DataType::Dictionary(_, value) => is_string_label(value),
+        _ => false,
+    }
+}
+
+/// Converts a numeric or boolean label column to XGBoost labels.
+pub fn label_values(column: &ArrayRef) -> Result<Vec<f32>> {
+    if is_string_label(column.data_type()) {
+        return Err(DataFusionError::Plan(
+            "String label columns need a LabelMapping".to_string(),
+        ));
+    }
+    float_values("Label", column)
+}
+
+/// Converts a numeric column such as a weight to `f32`, failing on nulls.
+pub(crate) fn float_values(name: &str, column: &ArrayRef) -> Result<Vec<f32>> {
+    let values = cast(column, &DataType::Float32)?;
+    let values = values
+        .as_any()
+        .downcast_ref::<Float32Array>()
+        .ok_or_else(|| DataFusionError::Internal("Expected Float32Array".to_string()))?;
+    if values.null_count() > 0 {
+        return Err(DataFusionError::Execution(format!(
+            "{} column contains nulls",
+            name
+        )));
+    }
+    Ok(values.values().to_vec())
+}
+
+/// Whether `booster` was trained with `binary:logitraw`, whose predictions
+/// are margins rather than the probabilities a threshold applies to.
+pub(crate) fn predicts_margins(booster: &Booster) -> Result<bool> {
+    let objective = booster.get_attribute("objective").map_err(xgb_error)?;
+    Ok(objective.as_deref() == Some("binary:logitraw"))
+}
+
+/// Probabilities of the margins of a `binary:logitraw` model, as
+/// `binary:logistic` would predict them.
+pub(crate) fn sigmoid(margins: Vec<f32>) -> Vec<f32> {
+    margins
+        .into_iter()
+        .map(|margin| 1.0 / (1.0 + (-margin).exp()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use datafusion::arrow::array::BooleanArray;
+
+    #[tokio::test]
+    pub async fn test_encode_labels() -> Result<()> {
+        let mapping = LabelMapping::new(["p", "e"]);
+        let column: ArrayRef = Arc::new(StringArray::from(vec!["e", "p", "e"]));
+        assert_eq!(mapping.encode(&column)?, vec![1.0, 0.0, 1.0]);
+        assert_eq!(mapping.class(1), Some("e"));
+
+        let unknown: ArrayRef = Arc::new(StringArray::from(vec!["x"]));
+        assert!(mapping.encode(&unknown).is_err());
+        assert!(label_values(&column).is_err());
+
+        let column: ArrayRef = Arc::new(BooleanArray::from(vec![true, false]));
+        assert_eq!(label_values(&column)?, vec![1.0, 0.0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_decode_predictions() -> Result<()> {
+        let binary = LabelMapping::new(["p", "e"]);
+        let decoded = binary.decode(&[0.2, 0.6, 0.9], 3, 0.5)?;
+        let expected: ArrayRef = Arc::new(StringArray::from(vec!["p", "e", "e"]));
+        assert_eq!(&decoded, &expected);
+        let decoded = binary.decode(&[0.2, 0.6, 0.9], 3, 0.8)?;
+        let expected: ArrayRef = Arc::new(StringArray::from(vec!["p", "p", "e"]));
+        assert_eq!(&decoded, &expected);
+
+        let decoded = binary.decode(&sigmoid(vec![-1.0, 0.5, 2.0]), 3, 0.8)?;
+        let expected: ArrayRef = Arc::new(StringArray::from(vec!["p", "p", "e"]));
+        assert_eq!(&decoded, &expected);
+
+        let multiclass = LabelMapping::fit(&[Arc::new(StringArray::from(vec!["c", "a", "b"]))])?;
+        assert_eq!(multiclass.classes(), &["a", "b", "c"]);
+        let decoded = multiclass.decode(&[0.1, 0.7, 0.2, 0.5, 0.2, 0.3], 2, 0.5)?;
+        let expected: ArrayRef = Arc::new(StringArray::from(vec!["b", "a"]));
+        assert_eq!(&decoded, &expected);
+        let decoded = multiclass.decode(&[2.0, 0.0], 2, 0.5)?;
+        let expected: ArrayRef = Arc::new(StringArray::from(vec!["c", "a"]));
+        assert_eq!(&decoded, &expected);
+
+        let empty: ArrayRef = Arc::new(StringArray::from(Vec::<&str>::new()));
+        let threshold: ArrayRef = Arc::new(Float64Array::from(Vec::<f64>::new()));
+        let args = [empty, threshold];
+        let (features, threshold) = threshold_from_args(&args)?;
+        assert_eq!((features.len(), threshold), (1, None));
+        assert!(binary.decode(&[], 0, 0.5)?.is_empty());
+        Ok(())
+    }
+}
@@ -1,22 +1,86 @@
+use crate::iteration::{scoring_function, ModelVersion};
 use datafusion::arrow::array::{
     as_dictionary_array, Array, ArrayRef, BooleanArray, BooleanBuilder, DictionaryArray,
     Float32Array, ListArray, ListBuilder, StringArray, StringBuilder, StructArray, StructBuilder,
 };
+use datafusion::arrow::compute::cast;
 use datafusion::arrow::datatypes::{DataType, Field, Fields, Int32Type};
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::error::{DataFusionError, Result};
-use datafusion::logical_expr::{create_udf, Volatility};
+use datafusion::logical_expr::{
+    ColumnarValue, ReturnTypeFunction, ScalarFunctionImplementation, ScalarUDF, Signature,
+    TypeSignature, Volatility,
+};
 use datafusion::physical_plan::functions::make_scalar_function;
 use datafusion::prelude::SessionContext;
+use datafusion::scalar::ScalarValue;
+use std::path::Path;
 use std::sync::Arc;
 use xgboost::{Booster, DMatrix};
 
-fn onehot(args: &[ArrayRef]) -> Result<ArrayRef> {
-    let data: &DictionaryArray<Int32Type> = as_dictionary_array::<_>(&args[0]);
+pub mod categorical;
+pub mod curve;
+pub mod custom;
+pub mod cv;
+pub mod dmatrix;
+pub mod ensemble;
+pub mod iteration;
+pub mod label;
+pub mod metrics;
+pub mod ml_predict;
+pub mod pipeline;
+pub mod predict_raw;
+pub mod registry;
+pub mod routing;
+pub mod search;
+pub mod split;
+pub mod statement;
+mod table_function;
+pub mod train;
+pub mod train_agg;
+pub mod training_log;
+
+pub use categorical::{is_categorical, CategoryMapping};
+pub use curve::{curve_points, Curve, CurveFunction};
+pub use custom::{CustomMetric, CustomObjective};
+pub use cv::cross_validate;
+pub use dmatrix::{
+    create_dmatrix_from_batches, create_dmatrix_from_dataframe, create_dmatrix_from_stream,
+    DMatrixBuilder, FeatureLayout,
+};
+pub use ensemble::{predict_ensemble_udf, Combination, Ensemble};
+pub use iteration::{margin_range, predict_range, IterationRange};
+pub use label::{label_values, LabelMapping};
+pub use ml_predict::MlPredictFunction;
+pub use pipeline::{Encoder, Pipeline};
+pub use predict_raw::{with_predict_raw, PredictRawRule};
+pub use registry::ModelRegistry;
+pub use routing::{model_routes, predict_routed_udf, ModelRoutes};
+pub use search::{search, ParamRange, SearchSpace, SearchStrategy};
+pub use split::{split_bucket, split_dataframe};
+pub use statement::{ModelSession, ModelStatement};
+pub use train::{balanced_class_weights, continue_training, next_version, train, TrainOptions};
+pub use train_agg::{load_model_bytes, train_xgboost_agg_udaf};
+pub use training_log::TrainingLog;
+
+pub(crate) fn onehot(args: &[ArrayRef]) -> Result<ArrayRef> {
+    if args.len() > 1 {
+        return onehot_with_keys(args);
+    }
+    let column = cast(
+        &args[0],
+        &DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)),
+    )?;
+    let data: &DictionaryArray<Int32Type> = as_dictionary_array::<_>(&column);
     let key = data.keys();
     let values = data.values();
 
     let values = values.as_any().downcast_ref::<StringArray>().unwrap();
+    if values.null_count() > 0 {
+        return Err(DataFusionError::Execution(
+            "onehot dictionary values must not be null".to_string(),
+        ));
+    }
 
     let struct_builder = StructBuilder::from_fields(
         Fields::from(vec![
@@ -28,13 +92,14 @@ fn onehot(args: &[ArrayRef]) -> Result<ArrayRef> {
 
     let mut list_builder = ListBuilder::new(struct_builder);
     for key_value in key.iter() {
-        for (j, struct_key) in values.iter().enumerate() {
-            let struct_value = j == key_value.unwrap() as usize;
+        // a null row has no key set, as with `onehot(column, 'key1', ...)`
+        for (j, struct_key) in values.iter().flatten().enumerate() {
+            let struct_value = key_value == Some(j as i32);
             list_builder
                 .values()
                 .field_builder::<StringBuilder>(0)
                 .unwrap()
-                .append_value(struct_key.unwrap());
+                .append_value(struct_key);
             list_builder
                 .values()
                 .field_builder::<BooleanBuilder>(1)
@@ -48,44 +113,129 @@ fn onehot(args: &[ArrayRef]) -> Result<ArrayRef> {
     Ok(Arc::new(list_array))
 }
 
-pub fn register_udfs(ctx: &SessionContext) {
-    let onehot = make_scalar_function(onehot);
+fn onehot_with_keys(args: &[ArrayRef]) -> Result<ArrayRef> {
+    let values = cast(&args[0], &DataType::Utf8)?;
+    let values = values
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| DataFusionError::Internal("Expected StringArray".to_string()))?;
+    if values.is_empty() {
+        return Ok(pipeline::onehot_with_vocabulary(values, &[]));
+    }
+    let keys = args[1..]
+        .iter()
+        .map(|key| {
+            key.as_any()
+                .downcast_ref::<StringArray>()
+                .filter(|key| key.is_valid(0))
+                .map(|key| key.value(0).to_string())
+                .ok_or_else(|| {
+                    DataFusionError::Plan("onehot keys must be non-null strings".to_string())
+                })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(pipeline::onehot_with_vocabulary(values, &keys))
+}
+
+/// `onehot(column)` turns a `Dictionary(Int32, Utf8)` column into a list of
+/// `{key, value}` structs, one per dictionary value of the batch.
+/// `onehot(column, 'key1', ..., 'keyn')` uses the given keys instead, so
+/// every batch has the same features; values that are not one of the keys
+/// have no key set. The keys must be string literals and, as no argument of
+/// this form is cast, the column must already be a `Dictionary(Int32, Utf8)`.
+pub fn onehot_udf() -> ScalarUDF {
+    let dictionary = DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8));
     let struct_type = DataType::Struct(Fields::from(vec![
         Field::new("key", DataType::Utf8, false),
         Field::new("value", DataType::Boolean, false),
     ]));
-
     let list_field = Field::new("item", struct_type, true);
-    let list_type = DataType::List(Arc::new(list_field));
-    let onehot_udf = create_udf(
-        "onehot",
-        vec![DataType::Dictionary(
-            Box::new(DataType::Int32),
-            Box::new(DataType::Utf8),
-        )],
-        Arc::new(list_type),
+    let list_type = Arc::new(DataType::List(Arc::new(list_field)));
+    let column_type = dictionary.clone();
+    let return_type: ReturnTypeFunction = Arc::new(move |args| {
+        // the keyed form matches any arguments, so its types are checked here
+        if args.first() != Some(&column_type) || args[1..].iter().any(|key| key != &DataType::Utf8)
+        {
+            return Err(DataFusionError::Plan(format!(
+                "onehot expects a {} column followed by Utf8 keys, got {:?}",
+                column_type, args
+            )));
+        }
+        Ok(list_type.clone())
+    });
+    let signature = Signature::one_of(
+        vec![
+            TypeSignature::Exact(vec![dictionary]),
+            TypeSignature::VariadicAny,
+        ],
         Volatility::Immutable,
-        onehot,
     );
+    let onehot = make_scalar_function(onehot);
+    let fun: ScalarFunctionImplementation = Arc::new(move |args: &[ColumnarValue]| {
+        let literal =
+            |key: &ColumnarValue| matches!(key, ColumnarValue::Scalar(ScalarValue::Utf8(Some(_))));
+        if !args.iter().skip(1).all(literal) {
+            return Err(DataFusionError::Plan(
+                "onehot keys must be non-null string literals".to_string(),
+            ));
+        }
+        onehot(args)
+    });
+    ScalarUDF::new("onehot", &signature, &return_type, &fun)
+}
 
-    ctx.register_udf(onehot_udf);
-    let predict = make_scalar_function(predict);
-    let struct_type = DataType::Struct(Fields::from(vec![
-        Field::new("key", DataType::Utf8, false),
-        Field::new("value", DataType::Boolean, false),
-    ]));
+pub fn register_udfs(ctx: &SessionContext) {
+    ctx.register_udf(onehot_udf());
+    ctx.register_udf(predict_udf("predict", "model.xgb"));
+    ctx.register_udf(predict_label_udf("predict_label", "model.xgb"));
+    ctx.register_udf(split::split_bucket_udf());
+    for udaf in metrics::metric_udafs() {
+        ctx.register_udaf(udaf);
+    }
+    ctx.register_udaf(train_agg::train_xgboost_agg_udaf());
+    ctx.register_udtf("train_xgboost", Arc::new(train::TrainFunction));
+    ctx.register_udtf("xgboost_search", Arc::new(search::SearchFunction));
+    ctx.register_udtf("roc_curve", Arc::new(CurveFunction(Curve::Roc)));
+    ctx.register_udtf("pr_curve", Arc::new(CurveFunction(Curve::PrecisionRecall)));
+    ctx.register_udtf("ml_predict", Arc::new(MlPredictFunction::new(ctx)));
+}
 
-    let list_field = Field::new("item", struct_type, true);
-    let list_type = DataType::List(Arc::new(list_field));
-    let predict_udf = create_udf(
-        "predict",
-        vec![list_type.clone(); 21],
-        Arc::new(DataType::Float32),
-        Volatility::Immutable,
-        predict,
-    );
+/// Creates a scoring UDF backed by the model saved at `model_path`.
+///
+/// Each argument is either a `onehot` column or a `Dictionary(Int32, Utf8)`
+/// column used as a category code feature, in the order the model was trained on.
+/// One or two trailing integer literals restrict scoring to an
+/// [`IterationRange`] of the ensemble, e.g. `predict(f1, ..., fn, 10)`.
+/// Predictions of such a range are approximate, see [`predict_range`].
+pub fn predict_udf(name: &str, model_path: impl AsRef<Path>) -> ScalarUDF {
+    let model_path = model_path.as_ref().to_path_buf();
+    let predict = scoring_function(move |args: &[ArrayRef]| predict_with_model(args, &model_path));
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Float32)));
+    ScalarUDF::new(
+        name,
+        &Signature::variadic_any(Volatility::Immutable),
+        &return_type,
+        &predict,
+    )
+}
 
-    ctx.register_udf(predict_udf);
+/// Creates a UDF returning the class label predicted by the model saved at
+/// `model_path`, using the [`LabelMapping`] saved with it.
+///
+/// Takes the same arguments as [`predict_udf`], optionally followed by a
+/// float literal threshold for binary models, e.g.
+/// `predict_label(f1, ..., fn, 0.7)`. The threshold defaults to 0.5.
+pub fn predict_label_udf(name: &str, model_path: impl AsRef<Path>) -> ScalarUDF {
+    let model_path = model_path.as_ref().to_path_buf();
+    let predict =
+        scoring_function(move |args: &[ArrayRef]| predict_label_with_model(args, &model_path));
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Utf8)));
+    ScalarUDF::new(
+        name,
+        &Signature::variadic_any(Volatility::Immutable),
+        &return_type,
+        &predict,
+    )
 }
 
 pub fn convert_to_native(
@@ -156,7 +306,7 @@ fn to_dense(batch: &ArrayRef) -> Result<(Vec<bool>, usize, Vec<String>), DataFus
     Ok((flattened, num, dim_names))
 }
 
-fn records_to_dense(
+pub fn records_to_dense(
     batch: &RecordBatch,
 ) -> Result<(Vec<bool>, usize, Vec<String>), DataFusionError> {
     let mut result = Vec::new();
@@ -172,13 +322,50 @@ fn records_to_dense(
     Ok((result, num_rows, dim_names))
 }
 
+/// Builds a row-major feature matrix from `onehot` and categorical columns,
+/// returning the data together with the number of rows.
+pub(crate) fn columns_to_features(
+    columns: &[ArrayRef],
+    categories: &CategoryMapping,
+) -> Result<(Vec<f32>, usize), DataFusionError> {
+    let num_rows = match columns.first() {
+        Some(column) if !column.is_empty() => column.len(),
+        _ => return Err(DataFusionError::Internal("No rows to convert".to_string())),
+    };
+
+    let mut features = Vec::new();
+    for (i, column) in columns.iter().enumerate() {
+        if is_categorical(column.data_type()) {
+            features.push(categories.encode(i, column)?);
+        } else {
+            let (dense, _, _) = to_dense(column)?;
+            features.extend(dense.chunks(num_rows).map(process_chunk));
+        }
+    }
+
+    let mut data = Vec::with_capacity(num_rows * features.len());
+    for row in 0..num_rows {
+        data.extend(features.iter().map(|feature| feature[row]));
+    }
+    Ok((data, num_rows))
+}
+
+/// Builds a `DMatrix` from a single batch. Use [`create_dmatrix_from_batches`]
+/// or [`create_dmatrix_from_dataframe`] for query results, which can span
+/// several batches.
 pub fn create_dmatrix(data: &RecordBatch) -> Result<DMatrix, DataFusionError> {
-    let (data, num_rows, _) = records_to_dense(data)?;
-    let data_transform = data
-        .into_iter()
-        .map(|x| x as u8 as f32)
-        .collect::<Vec<f32>>();
-    let dmat = DMatrix::from_dense(&data_transform, num_rows)
+    let categories = CategoryMapping::fit(data.columns())?;
+    create_dmatrix_with_categories(data, &categories)
+}
+
+/// Like [`create_dmatrix`], but encodes categorical columns with an existing
+/// mapping, e.g. the one that will be saved with the model.
+pub fn create_dmatrix_with_categories(
+    data: &RecordBatch,
+    categories: &CategoryMapping,
+) -> Result<DMatrix, DataFusionError> {
+    let (data, num_rows) = columns_to_features(data.columns(), categories)?;
+    let dmat = DMatrix::from_dense(&data, num_rows)
         .map_err(|_| DataFusionError::Internal("Failed to create dmatrix".to_string()))?;
     Ok(dmat)
 }
@@ -187,36 +374,67 @@ fn process_chunk(chunk: &[bool]) -> Vec<f32> {
     chunk.iter().map(|x| *x as u8 as f32).collect::<Vec<f32>>()
 }
 
-fn predict(args: &[ArrayRef]) -> Result<ArrayRef> {
-    let mut result = Vec::new();
-    let mut num_rows_final = 0;
-    println!("args len: {}", args.len());
-    for arg in args {
-        let (result_col, num_rows, _dim_names_col) = to_dense(arg)?;
-        let chunks = result_col.chunks(result_col.len() / num_rows);
-
-        if result.is_empty() {
-            for chunk in chunks {
-                result.push(process_chunk(chunk));
-            }
-            num_rows_final = num_rows;
-        } else {
-            for (index, chunk) in chunks.enumerate() {
-                result.get_mut(index).unwrap().extend(process_chunk(chunk));
-            }
-        }
+pub(crate) fn predict_with_model(args: &[ArrayRef], model_path: &Path) -> Result<ArrayRef> {
+    let (booster, version) = ModelVersion::load(model_path)?;
+    let (result, _) = score(args, &booster, &version)?;
+    Ok(Arc::new(Float32Array::from(result)))
+}
+
+pub(crate) fn predict_label_with_model(args: &[ArrayRef], model_path: &Path) -> Result<ArrayRef> {
+    let (args, threshold) = label::threshold_from_args(args)?;
+    let (booster, version) = ModelVersion::load(model_path)?;
+    let labels = LabelMapping::load(&booster)?.ok_or_else(|| {
+        DataFusionError::Execution(format!(
+            "Model {} has no label classes, it was not trained on a string label",
+            model_path.display()
+        ))
+    })?;
+    let (mut result, num_rows) = score(args, &booster, &version)?;
+    if label::predicts_margins(&booster)? {
+        result = label::sigmoid(result);
     }
-    let mut data_transform: Vec<f32> = Vec::new();
-    for re in result {
-        data_transform.extend(re);
+    labels.decode(&result, num_rows, threshold.unwrap_or(0.5))
+}
+
+pub(crate) fn load_model(model_path: &Path) -> Result<Booster> {
+    Booster::load(model_path)
+        .map_err(|e| DataFusionError::Internal(format!("Failed to load model: {}", e)))
+}
+
+/// Scores the feature arguments of a UDF call with the model loaded as
+/// `version`, returning the predictions and the number of rows.
+pub(crate) fn score(
+    args: &[ArrayRef],
+    booster: &Booster,
+    version: &ModelVersion,
+) -> Result<(Vec<f32>, usize)> {
+    if args.first().is_some_and(|arg| arg.is_empty()) {
+        return Ok((Vec::new(), 0));
     }
+    let (args, range) = IterationRange::from_args(args)?;
+    let categories = CategoryMapping::for_scoring(CategoryMapping::load(booster)?, args)?;
+
+    let (dmat, num_rows) = features_dmatrix(args, &categories)?;
+    Ok((
+        iteration::predict_model_range(booster, version, &dmat, range)?,
+        num_rows,
+    ))
+}
 
-    let dmat = DMatrix::from_dense(&data_transform, num_rows_final).unwrap();
-    println!("dmat shape: {:?}", dmat.shape());
-    let booster = Booster::load("model.xgb").unwrap();
-    let result = Float32Array::from(booster.predict(&dmat).unwrap());
+/// Builds the `DMatrix` of the feature arguments of a UDF call, returning it
+/// with the number of rows.
+pub(crate) fn features_dmatrix(
+    args: &[ArrayRef],
+    categories: &CategoryMapping,
+) -> Result<(DMatrix, usize)> {
+    let (data_transform, num_rows) = columns_to_features(args, categories)?;
+    let dmat = DMatrix::from_dense(&data_transform, num_rows)
+        .map_err(|_| DataFusionError::Internal("Failed to create dmatrix".to_string()))?;
+    Ok((dmat, num_rows))
+}
 
-    Ok(Arc::new(result))
+pub(crate) fn xgb_error(e: xgboost::XGBError) -> DataFusionError {
+    DataFusionError::Internal(format!("XGBoost error: {}", e))
 }
 
 #[cfg(test)]
@@ -231,9 +449,55 @@ mod test {
         let mut builder = StringDictionaryBuilder::<Int32Type>::new();
         builder.append("a").unwrap();
         builder.append("b").unwrap();
+        builder.append_null();
         let dict = Arc::new(builder.finish()).clone();
         let result = onehot(&[dict])?;
-        assert_eq!(result.len(), 2);
+        assert_eq!(result.len(), 3);
+        let (dense, _, names) = to_dense(&result)?;
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(dense, vec![true, false, false, false, true, false]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_onehot_with_keys() -> Result<()> {
+        let mut builder = StringDictionaryBuilder::<Int32Type>::new();
+        builder.append("b").unwrap();
+        builder.append("z").unwrap();
+        let dict = Arc::new(builder.finish()) as ArrayRef;
+        let keys = Arc::new(StringArray::from(vec!["a", "a"])) as ArrayRef;
+        let other_keys = Arc::new(StringArray::from(vec!["b", "b"])) as ArrayRef;
+        let result = onehot(&[dict, keys, other_keys])?;
+        // features follow the keys, not the dictionary of the batch
+        let (dense, num_rows, names) = to_dense(&result)?;
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(num_rows, 2);
+        assert_eq!(dense, vec![false, false, true, false]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_onehot_udf_arguments() -> Result<()> {
+        let udf = onehot_udf();
+        let dictionary = DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8));
+        assert!(udf.return_type(std::slice::from_ref(&dictionary)).is_ok());
+        assert!(udf
+            .return_type(&[dictionary.clone(), DataType::Utf8])
+            .is_ok());
+        assert!(udf.return_type(&[DataType::Int64]).is_err());
+        assert!(udf.return_type(&[DataType::Utf8, DataType::Utf8]).is_err());
+        assert!(udf.return_type(&[dictionary, DataType::Int64]).is_err());
+
+        let mut builder = StringDictionaryBuilder::<Int32Type>::new();
+        builder.append("b").unwrap();
+        let dict = ColumnarValue::Array(Arc::new(builder.finish()));
+        let key = ColumnarValue::Scalar(ScalarValue::Utf8(Some("b".to_string())));
+        assert!(udf.fun()(&[dict.clone(), key.clone()]).is_ok());
+        let column = ColumnarValue::Array(Arc::new(StringArray::from(vec!["b"])));
+        let error = udf.fun()(&[dict, key, column]).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("must be non-null string literals"));
         Ok(())
     }
 
@@ -416,8 +680,22 @@ mod test {
         let f2 = array.clone();
         let f3 = array.clone();
 
-        let _result = predict(&[f0, f1, f2, f3])?;
+        let _result = predict_with_model(&[f0, f1, f2, f3], Path::new("model.xgb"))?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_predict_empty_batch() -> Result<()> {
+        let fields = Fields::from(vec![
+            Field::new("key", DataType::Utf8, false),
+            Field::new("value", DataType::Boolean, false),
+        ]);
+        let mut list_builder = ListBuilder::new(StructBuilder::from_fields(fields, 0));
+        let array = Arc::new(list_builder.finish()) as ArrayRef;
 
+        let result = predict_with_model(&vec![array; 4], Path::new("model.xgb"))?;
+        assert_eq!(result.len(), 0);
         Ok(())
     }
 }
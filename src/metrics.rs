@@ -0,0 +1,570 @@
+use datafusion::arrow::array::{
+    Array, ArrayRef, BooleanArray, Float64Array, ListArray, StringArray, UInt64Array,
+};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::{DataType, Field, Fields};
+use datafusion::common::utils::array_into_list_array;
+use datafusion::common::ScalarValue;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_expr::{
+    Accumulator, AccumulatorFactoryFunction, AggregateUDF, ReturnTypeFunction, Signature,
+    StateTypeFunction, TypeSignature, Volatility,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Evaluation metric aggregates over a score and a label column.
+///
+/// Scores are predictions as returned by `predict`, labels are booleans or
+/// `0`/`1` (any numeric label for `rmse` and `mae`). Rows where either is null
+/// are skipped, and a metric over no rows is null.
+pub fn metric_udafs() -> Vec<AggregateUDF> {
+    vec![
+        mean_udaf("logloss", Loss::Log),
+        mean_udaf("rmse", Loss::Squared),
+        mean_udaf("mae", Loss::Absolute),
+        mean_udaf("accuracy_at", Loss::Accuracy),
+        auc_udaf(),
+        confusion_matrix_udaf(),
+        ndcg_udaf(),
+    ]
+}
+
+/// Fields of the struct returned by `confusion_matrix`.
+pub fn confusion_matrix_fields() -> Fields {
+    Fields::from(
+        ["tp", "fp", "tn", "fn"]
+            .iter()
+            .map(|name| Field::new(*name, DataType::UInt64, false))
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Area under the ROC curve of `scores` for binary `labels`, `None` unless
+/// there are both positive and negative labels. Tied scores get their
+/// average rank.
+pub fn auc(scores: &[f64], labels: &[bool]) -> Option<f64> {
+    let mut order: Vec<usize> = (0..scores.len()).collect();
+    order.sort_by(|a, b| scores[*a].total_cmp(&scores[*b]));
+
+    let positives = labels.iter().filter(|label| **label).count() as f64;
+    let negatives = labels.len() as f64 - positives;
+    if positives == 0.0 || negatives == 0.0 {
+        return None;
+    }
+
+    let mut positive_ranks = 0.0;
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && scores[order[end]] == scores[order[start]] {
+            end += 1;
+        }
+        // ranks start at 1, so the tied rows start..end share the rank below
+        let rank = (start + end + 1) as f64 / 2.0;
+        let tied_positives = order[start..end].iter().filter(|i| labels[**i]).count();
+        positive_ranks += rank * tied_positives as f64;
+        start = end;
+    }
+    Some((positive_ranks - positives * (positives + 1.0) / 2.0) / (positives * negatives))
+}
+
+/// NDCG of the first `k` rows of a query group ranked by `scores`, with
+/// gains `2^relevance - 1`, as XGBoost's `ndcg@k`. Tied scores are ranked
+/// with the lower relevance first, and a group without relevant rows has an
+/// NDCG of 1.
+pub fn ndcg(scores: &[f64], relevances: &[f64], k: usize) -> f64 {
+    let dcg = |order: &[usize]| -> f64 {
+        order
+            .iter()
+            .take(k)
+            .enumerate()
+            .map(|(rank, i)| (2f64.powf(relevances[*i]) - 1.0) / (rank as f64 + 2.0).log2())
+            .sum()
+    };
+    let mut ideal: Vec<usize> = (0..relevances.len()).collect();
+    ideal.sort_by(|a, b| relevances[*b].total_cmp(&relevances[*a]));
+    let ideal_dcg = dcg(&ideal);
+    if ideal_dcg == 0.0 {
+        return 1.0;
+    }
+    let mut ranked: Vec<usize> = (0..scores.len()).collect();
+    ranked.sort_by(|a, b| {
+        scores[*b]
+            .total_cmp(&scores[*a])
+            .then(relevances[*a].total_cmp(&relevances[*b]))
+    });
+    dcg(&ranked) / ideal_dcg
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Loss {
+    Log,
+    Squared,
+    Absolute,
+    Accuracy,
+}
+
+impl Loss {
+    fn value(&self, score: f64, label: f64, threshold: f64) -> f64 {
+        match self {
+            Loss::Log => {
+                let p = score.clamp(1e-15, 1.0 - 1e-15);
+                -(label * p.ln() + (1.0 - label) * (1.0 - p).ln())
+            }
+            Loss::Squared => (score - label).powi(2),
+            Loss::Absolute => (score - label).abs(),
+            Loss::Accuracy => ((score >= threshold) == is_positive(label)) as u8 as f64,
+        }
+    }
+
+    fn num_args(&self) -> usize {
+        match self {
+            Loss::Accuracy => 3,
+            _ => 2,
+        }
+    }
+}
+
+fn is_positive(label: f64) -> bool {
+    label >= 0.5
+}
+
+/// Averages a per-row [`Loss`], `rmse` takes the root of the average.
+#[derive(Debug)]
+struct MeanAccumulator {
+    loss: Loss,
+    sum: f64,
+    count: u64,
+}
+
+impl Accumulator for MeanAccumulator {
+    fn update_batch(&mut self, values: &[ArrayRef]) -> Result<()> {
+        let scores = float64(&values[0])?;
+        let labels = float64(&values[1])?;
+        let threshold = match self.loss {
+            Loss::Accuracy => threshold(&values[2])?,
+            _ => 0.0,
+        };
+        for (score, label) in scores.iter().zip(labels.iter()) {
+            if let (Some(score), Some(label)) = (score, label) {
+                self.sum += self.loss.value(score, label, threshold);
+                self.count += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue> {
+        if self.count == 0 {
+            return Ok(ScalarValue::Float64(None));
+        }
+        let mean = self.sum / self.count as f64;
+        Ok(ScalarValue::Float64(Some(match self.loss {
+            Loss::Squared => mean.sqrt(),
+            _ => mean,
+        })))
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+
+    fn state(&self) -> Result<Vec<ScalarValue>> {
+        Ok(vec![
+            ScalarValue::Float64(Some(self.sum)),
+            ScalarValue::UInt64(Some(self.count)),
+        ])
+    }
+
+    fn merge_batch(&mut self, states: &[ArrayRef]) -> Result<()> {
+        self.sum += float64(&states[0])?.iter().flatten().sum::<f64>();
+        self.count += downcast::<UInt64Array>(&states[1])?
+            .iter()
+            .flatten()
+            .sum::<u64>();
+        Ok(())
+    }
+}
+
+fn mean_udaf(name: &str, loss: Loss) -> AggregateUDF {
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Float64)));
+    let accumulator: AccumulatorFactoryFunction = Arc::new(move |_| {
+        Ok(Box::new(MeanAccumulator {
+            loss,
+            sum: 0.0,
+            count: 0,
+        }))
+    });
+    let state_type: StateTypeFunction =
+        Arc::new(|_| Ok(Arc::new(vec![DataType::Float64, DataType::UInt64])));
+    AggregateUDF::new(
+        name,
+        &Signature::new(TypeSignature::Any(loss.num_args()), Volatility::Immutable),
+        &return_type,
+        &accumulator,
+        &state_type,
+    )
+}
+
+/// Keeps all scores and labels, as the AUC depends on their global order.
+#[derive(Debug, Default)]
+struct AucAccumulator {
+    scores: Vec<f64>,
+    labels: Vec<bool>,
+}
+
+impl AucAccumulator {
+    fn push(&mut self, scores: &Float64Array, labels: &Float64Array) {
+        for (score, label) in scores.iter().zip(labels.iter()) {
+            if let (Some(score), Some(label)) = (score, label) {
+                self.scores.push(score);
+                self.labels.push(is_positive(label));
+            }
+        }
+    }
+}
+
+impl Accumulator for AucAccumulator {
+    fn update_batch(&mut self, values: &[ArrayRef]) -> Result<()> {
+        self.push(&float64(&values[0])?, &float64(&values[1])?);
+        Ok(())
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue> {
+        Ok(ScalarValue::Float64(auc(&self.scores, &self.labels)))
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of_val(self)
+            + self.scores.capacity() * std::mem::size_of::<f64>()
+            + self.labels.capacity()
+    }
+
+    fn state(&self) -> Result<Vec<ScalarValue>> {
+        let scores: ArrayRef = Arc::new(Float64Array::from(self.scores.clone()));
+        let labels: ArrayRef = Arc::new(BooleanArray::from(self.labels.clone()));
+        Ok(vec![
+            ScalarValue::List(Arc::new(array_into_list_array(scores))),
+            ScalarValue::List(Arc::new(array_into_list_array(labels))),
+        ])
+    }
+
+    fn merge_batch(&mut self, states: &[ArrayRef]) -> Result<()> {
+        let scores = downcast::<ListArray>(&states[0])?;
+        let labels = downcast::<ListArray>(&states[1])?;
+        for (scores, labels) in scores.iter().zip(labels.iter()) {
+            if let (Some(scores), Some(labels)) = (scores, labels) {
+                self.push(&float64(&scores)?, &float64(&labels)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn auc_udaf() -> AggregateUDF {
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Float64)));
+    let accumulator: AccumulatorFactoryFunction =
+        Arc::new(|_| Ok(Box::<AucAccumulator>::default()));
+    let state_type: StateTypeFunction = Arc::new(|_| {
+        Ok(Arc::new(vec![
+            DataType::List(Arc::new(Field::new("item", DataType::Float64, true))),
+            DataType::List(Arc::new(Field::new("item", DataType::Boolean, true))),
+        ]))
+    });
+    AggregateUDF::new(
+        "auc",
+        &Signature::new(TypeSignature::Any(2), Volatility::Immutable),
+        &return_type,
+        &accumulator,
+        &state_type,
+    )
+}
+
+/// Keeps all rows with their query group and averages the [`ndcg`] of the
+/// groups.
+#[derive(Debug, Default)]
+struct NdcgAccumulator {
+    scores: Vec<f64>,
+    relevances: Vec<f64>,
+    groups: Vec<String>,
+    k: u64,
+}
+
+impl NdcgAccumulator {
+    fn push(&mut self, scores: &Float64Array, relevances: &Float64Array, groups: &StringArray) {
+        for ((score, relevance), group) in scores.iter().zip(relevances.iter()).zip(groups.iter()) {
+            if let (Some(score), Some(relevance), Some(group)) = (score, relevance, group) {
+                self.scores.push(score);
+                self.relevances.push(relevance);
+                self.groups.push(group.to_string());
+            }
+        }
+    }
+}
+
+impl Accumulator for NdcgAccumulator {
+    fn update_batch(&mut self, values: &[ArrayRef]) -> Result<()> {
+        // an empty batch has no cutoff to read
+        if values[3].is_empty() {
+            return Ok(());
+        }
+        self.k = cutoff(&values[3])?;
+        self.push(
+            &float64(&values[0])?,
+            &float64(&values[1])?,
+            &utf8(&values[2])?,
+        );
+        Ok(())
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue> {
+        let mut groups: HashMap<&str, (Vec<f64>, Vec<f64>)> = HashMap::new();
+        for ((score, relevance), group) in
+            self.scores.iter().zip(&self.relevances).zip(&self.groups)
+        {
+            let (scores, relevances) = groups.entry(group.as_str()).or_default();
+            scores.push(*score);
+            relevances.push(*relevance);
+        }
+        if groups.is_empty() {
+            return Ok(ScalarValue::Float64(None));
+        }
+        let total: f64 = groups
+            .values()
+            .map(|(scores, relevances)| ndcg(scores, relevances, self.k as usize))
+            .sum();
+        Ok(ScalarValue::Float64(Some(total / groups.len() as f64)))
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of_val(self)
+            + (self.scores.capacity() + self.relevances.capacity()) * std::mem::size_of::<f64>()
+            + self
+                .groups
+                .iter()
+                .map(|group| group.capacity())
+                .sum::<usize>()
+            + self.groups.capacity() * std::mem::size_of::<String>()
+    }
+
+    fn state(&self) -> Result<Vec<ScalarValue>> {
+        let scores: ArrayRef = Arc::new(Float64Array::from(self.scores.clone()));
+        let relevances: ArrayRef = Arc::new(Float64Array::from(self.relevances.clone()));
+        let groups: ArrayRef = Arc::new(StringArray::from(self.groups.clone()));
+        Ok(vec![
+            ScalarValue::List(Arc::new(array_into_list_array(scores))),
+            ScalarValue::List(Arc::new(array_into_list_array(relevances))),
+            ScalarValue::List(Arc::new(array_into_list_array(groups))),
+            ScalarValue::UInt64(Some(self.k)),
+        ])
+    }
+
+    fn merge_batch(&mut self, states: &[ArrayRef]) -> Result<()> {
+        let scores = downcast::<ListArray>(&states[0])?;
+        let relevances = downcast::<ListArray>(&states[1])?;
+        let groups = downcast::<ListArray>(&states[2])?;
+        for ((scores, relevances), groups) in
+            scores.iter().zip(relevances.iter()).zip(groups.iter())
+        {
+            if let (Some(scores), Some(relevances), Some(groups)) = (scores, relevances, groups) {
+                self.push(&float64(&scores)?, &float64(&relevances)?, &utf8(&groups)?);
+            }
+        }
+        // states of accumulators without rows have no cutoff
+        if let Some(k) = downcast::<UInt64Array>(&states[3])?.iter().flatten().max() {
+            self.k = self.k.max(k);
+        }
+        Ok(())
+    }
+}
+
+fn ndcg_udaf() -> AggregateUDF {
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Float64)));
+    let accumulator: AccumulatorFactoryFunction =
+        Arc::new(|_| Ok(Box::<NdcgAccumulator>::default()));
+    let state_type: StateTypeFunction = Arc::new(|_| {
+        Ok(Arc::new(vec![
+            DataType::List(Arc::new(Field::new("item", DataType::Float64, true))),
+            DataType::List(Arc::new(Field::new("item", DataType::Float64, true))),
+            DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))),
+            DataType::UInt64,
+        ]))
+    });
+    AggregateUDF::new(
+        "ndcg_at",
+        &Signature::new(TypeSignature::Any(4), Volatility::Immutable),
+        &return_type,
+        &accumulator,
+        &state_type,
+    )
+}
+
+/// Counts true/false positives/negatives at a threshold.
+#[derive(Debug, Default)]
+struct ConfusionAccumulator {
+    // tp, fp, tn, fn
+    counts: [u64; 4],
+}
+
+impl Accumulator for ConfusionAccumulator {
+    fn update_batch(&mut self, values: &[ArrayRef]) -> Result<()> {
+        let scores = float64(&values[0])?;
+        let labels = float64(&values[1])?;
+        let threshold = threshold(&values[2])?;
+        for (score, label) in scores.iter().zip(labels.iter()) {
+            if let (Some(score), Some(label)) = (score, label) {
+                let cell = match (score >= threshold, is_positive(label)) {
+                    (true, true) => 0,
+                    (true, false) => 1,
+                    (false, false) => 2,
+                    (false, true) => 3,
+                };
+                self.counts[cell] += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue> {
+        Ok(ScalarValue::Struct(
+            Some(
+                self.counts
+                    .iter()
+                    .map(|count| ScalarValue::UInt64(Some(*count)))
+                    .collect(),
+            ),
+            confusion_matrix_fields(),
+        ))
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+
+    fn state(&self) -> Result<Vec<ScalarValue>> {
+        Ok(self
+            .counts
+            .iter()
+            .map(|count| ScalarValue::UInt64(Some(*count)))
+            .collect())
+    }
+
+    fn merge_batch(&mut self, states: &[ArrayRef]) -> Result<()> {
+        for (count, state) in self.counts.iter_mut().zip(states) {
+            *count += downcast::<UInt64Array>(state)?
+                .iter()
+                .flatten()
+                .sum::<u64>();
+        }
+        Ok(())
+    }
+}
+
+fn confusion_matrix_udaf() -> AggregateUDF {
+    let return_type: ReturnTypeFunction =
+        Arc::new(|_| Ok(Arc::new(DataType::Struct(confusion_matrix_fields()))));
+    let accumulator: AccumulatorFactoryFunction =
+        Arc::new(|_| Ok(Box::<ConfusionAccumulator>::default()));
+    let state_type: StateTypeFunction = Arc::new(|_| Ok(Arc::new(vec![DataType::UInt64; 4])));
+    AggregateUDF::new(
+        "confusion_matrix",
+        &Signature::new(TypeSignature::Any(3), Volatility::Immutable),
+        &return_type,
+        &accumulator,
+        &state_type,
+    )
+}
+
+fn float64(array: &ArrayRef) -> Result<Float64Array> {
+    Ok(downcast::<Float64Array>(&cast(array, &DataType::Float64)?)?.clone())
+}
+
+fn downcast<T: 'static>(array: &ArrayRef) -> Result<&T> {
+    array
+        .as_any()
+        .downcast_ref::<T>()
+        .ok_or_else(|| DataFusionError::Internal(format!("Unexpected {}", array.data_type())))
+}
+
+fn utf8(array: &ArrayRef) -> Result<StringArray> {
+    Ok(downcast::<StringArray>(&cast(array, &DataType::Utf8)?)?.clone())
+}
+
+/// Reads the constant cutoff argument of `ndcg_at`, which must be positive.
+fn cutoff(array: &ArrayRef) -> Result<u64> {
+    match float64(array)?.iter().flatten().next() {
+        Some(k) if k >= 1.0 && k.fract() == 0.0 => Ok(k as u64),
+        _ => Err(DataFusionError::Execution(
+            "ndcg_at expects a positive integer cutoff".to_string(),
+        )),
+    }
+}
+
+/// Reads the constant threshold argument of `accuracy_at` and
+/// `confusion_matrix`.
+fn threshold(array: &ArrayRef) -> Result<f64> {
+    float64(array)?.iter().flatten().next().map_or(Ok(0.5), Ok)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_auc() -> Result<()> {
+        assert_eq!(
+            auc(&[0.1, 0.4, 0.35, 0.8], &[false, false, true, true]),
+            Some(0.75)
+        );
+        assert_eq!(auc(&[0.5, 0.5], &[false, true]), Some(0.5));
+        assert_eq!(auc(&[0.1, 0.2], &[true, true]), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_ndcg() -> Result<()> {
+        assert_eq!(ndcg(&[0.9, 0.5, 0.1], &[2.0, 1.0, 0.0], 3), 1.0);
+        // (1 / log2(2) + 3 / log2(3)) / (3 / log2(2) + 1 / log2(3))
+        let expected = (1.0 + 3.0 / 3f64.log2()) / (3.0 + 1.0 / 3f64.log2());
+        assert!((ndcg(&[0.9, 0.5, 0.1], &[1.0, 2.0, 0.0], 3) - expected).abs() < 1e-12);
+        assert_eq!(ndcg(&[0.9, 0.5], &[1.0, 2.0], 1), 1.0 / 3.0);
+        assert_eq!(ndcg(&[0.5, 0.5], &[1.0, 0.0], 1), 0.0);
+        assert_eq!(ndcg(&[0.9, 0.5], &[0.0, 0.0], 2), 1.0);
+
+        let mut accumulator = NdcgAccumulator::default();
+        let empty: ArrayRef = Arc::new(Float64Array::from(Vec::<f64>::new()));
+        let groups: ArrayRef = Arc::new(StringArray::from(Vec::<&str>::new()));
+        accumulator.update_batch(&[empty.clone(), empty.clone(), groups, empty])?;
+        assert_eq!(accumulator.evaluate()?, ScalarValue::Float64(None));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_mean_accumulator_merges_states() -> Result<()> {
+        let scores: ArrayRef = Arc::new(Float64Array::from(vec![Some(0.0), Some(3.0), None]));
+        let labels: ArrayRef = Arc::new(Float64Array::from(vec![1.0, 1.0, 1.0]));
+        let mut first = MeanAccumulator {
+            loss: Loss::Squared,
+            sum: 0.0,
+            count: 0,
+        };
+        first.update_batch(&[scores.clone(), labels.clone()])?;
+        let mut second = MeanAccumulator {
+            loss: Loss::Squared,
+            sum: 0.0,
+            count: 0,
+        };
+        let state = first
+            .state()?
+            .iter()
+            .map(|value| value.to_array())
+            .collect::<Result<Vec<_>>>()?;
+        second.merge_batch(&state)?;
+        assert_eq!(
+            second.evaluate()?,
+            ScalarValue::Float64(Some(2.5f64.sqrt()))
+        );
+        Ok(())
+    }
+}
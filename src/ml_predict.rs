@@ -0,0 +1,295 @@
+use crate::label::{predicts_margins, sigmoid};
+use crate::registry::ModelRegistry;
+use crate::table_function::{literal_string, LazyTable};
+use crate::{load_model, xgb_error, LabelMapping, Pipeline};
+use datafusion::arrow::array::{ArrayRef, Float32Array};
+use datafusion::arrow::compute::concat_batches;
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datasource::function::TableFunctionImpl;
+use datafusion::datasource::TableProvider;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::SessionState;
+use datafusion::logical_expr::Expr;
+use datafusion::prelude::SessionContext;
+use futures::FutureExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// `ml_predict('model', 'table')` table function, also taking a query as
+/// second argument, e.g. `ml_predict('model', 'SELECT * FROM t WHERE x')`.
+///
+/// Returns the input columns followed by a `prediction` column. The raw input
+/// columns are matched to the features of the model by name and encoded as
+/// the model was trained, see [`Pipeline::of_model`]. For models trained on
+/// a string label, `prediction` is the predicted class and a
+/// `probability_<class>` column per class follows for `binary:logistic` and
+/// `multi:softprob` models.
+///
+/// The output columns are resolved while the query is planned, against the
+/// current tables and UDFs of the session the function is registered on.
+/// Planning cannot wait, so the tables read by the input must be resolved
+/// without awaiting, as those of the built-in catalog are.
+pub struct MlPredictFunction {
+    state: SessionStateRef,
+    registry: Option<Arc<ModelRegistry>>,
+}
+
+/// Current state of a session, without keeping the session alive.
+type SessionStateRef = Box<dyn Fn() -> Option<SessionState> + Send + Sync>;
+
+impl MlPredictFunction {
+    /// Resolves models as model file paths, planning inputs in `ctx`.
+    pub fn new(ctx: &SessionContext) -> Self {
+        let state = ctx.state_weak_ref();
+        MlPredictFunction {
+            state: Box::new(move || state.upgrade().map(|state| state.read().clone())),
+            registry: None,
+        }
+    }
+
+    /// Resolves models as the names of models in `registry`.
+    pub fn with_registry(ctx: &SessionContext, registry: Arc<ModelRegistry>) -> Self {
+        MlPredictFunction {
+            registry: Some(registry),
+            ..Self::new(ctx)
+        }
+    }
+
+    fn model_path(&self, model: &str) -> Result<PathBuf> {
+        match &self.registry {
+            Some(registry) => registry.resolve(model),
+            None => Ok(PathBuf::from(model)),
+        }
+    }
+
+    /// Schema of the rows of `query`, planned without running it.
+    fn input_schema(&self, query: &str) -> Result<SchemaRef> {
+        let state = (self.state)()
+            .ok_or_else(|| DataFusionError::Plan("ml_predict outlived its session".to_string()))?;
+        let plan = state
+            .create_logical_plan(query)
+            .now_or_never()
+            .ok_or_else(|| {
+                DataFusionError::Plan(format!(
+                    "ml_predict cannot resolve the columns of {} while planning, \
+                     a table it reads is provided asynchronously",
+                    query
+                ))
+            })??;
+        Ok(Arc::new(plan.schema().as_ref().into()))
+    }
+}
+
+impl TableFunctionImpl for MlPredictFunction {
+    fn call(&self, args: &[Expr]) -> Result<Arc<dyn TableProvider>> {
+        let args = args
+            .iter()
+            .map(|arg| literal_string("ml_predict", arg))
+            .collect::<Result<Vec<_>>>()?;
+        let [model, input] = &args[..] else {
+            return Err(DataFusionError::Plan(
+                "ml_predict expects a model and a table or query".to_string(),
+            ));
+        };
+        let query = if is_query(input) {
+            input.clone()
+        } else {
+            format!("SELECT * FROM {}", input)
+        };
+
+        let pipeline = Pipeline::of_model(self.model_path(model)?)?;
+        let input_schema = self.input_schema(&query)?;
+        let scoring = Scoring::new(pipeline, &input_schema)?;
+        let schema = scoring.schema.clone();
+        Ok(Arc::new(LazyTable::new(
+            "ml_predict",
+            schema,
+            Arc::new(move |ctx| {
+                let (query, scoring) = (query.clone(), scoring.clone());
+                Box::pin(async move { scoring.predict(&ctx, &query).await })
+            }),
+        )))
+    }
+}
+
+fn is_query(input: &str) -> bool {
+    let first = input.split_whitespace().next().unwrap_or_default();
+    first.eq_ignore_ascii_case("SELECT") || first.eq_ignore_ascii_case("WITH")
+}
+
+/// A pipeline bound to the columns of an input.
+#[derive(Clone)]
+struct Scoring {
+    pipeline: Pipeline,
+    /// Input column position of every raw feature column of the pipeline.
+    features: Vec<usize>,
+    labels: Option<LabelMapping>,
+    /// Whether the model predicts `binary:logitraw` margins, which are turned
+    /// into probabilities before decoding the classes.
+    margins: bool,
+    probabilities: bool,
+    schema: SchemaRef,
+}
+
+impl Scoring {
+    fn new(pipeline: Pipeline, input: &Schema) -> Result<Self> {
+        let features = pipeline
+            .columns()
+            .iter()
+            .map(|name| {
+                input.index_of(name).map_err(|_| {
+                    DataFusionError::Plan(format!(
+                        "Model {} needs a column {}, the input has {}",
+                        pipeline.model_path().display(),
+                        name,
+                        input
+                            .fields()
+                            .iter()
+                            .map(|field| field.name().as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let booster = load_model(pipeline.model_path())?;
+        let labels = LabelMapping::load(&booster)?;
+        let objective = booster.get_attribute("objective").map_err(xgb_error)?;
+        let margins = predicts_margins(&booster)?;
+        let probabilities = labels.is_some()
+            && (margins
+                || matches!(
+                    objective.as_deref(),
+                    Some("binary:logistic") | Some("multi:softprob")
+                ));
+
+        let mut fields: Vec<Field> = input
+            .fields()
+            .iter()
+            .map(|field| field.as_ref().clone())
+            .collect();
+        match &labels {
+            Some(labels) => {
+                fields.push(Field::new("prediction", DataType::Utf8, true));
+                if probabilities {
+                    fields.extend(labels.classes().iter().map(|class| {
+                        Field::new(format!("probability_{}", class), DataType::Float32, true)
+                    }));
+                }
+            }
+            None => fields.push(Field::new("prediction", DataType::Float32, true)),
+        }
+        for (i, field) in fields.iter().enumerate().skip(input.fields().len()) {
+            if fields[..i].iter().any(|other| other.name() == field.name()) {
+                return Err(DataFusionError::Plan(format!(
+                    "ml_predict adds a {} column, which the input already has",
+                    field.name()
+                )));
+            }
+        }
+        Ok(Scoring {
+            pipeline,
+            features,
+            labels,
+            margins,
+            probabilities,
+            schema: Arc::new(Schema::new(fields)),
+        })
+    }
+
+    async fn predict(&self, ctx: &SessionContext, query: &str) -> Result<RecordBatch> {
+        let batches = ctx.sql(query).await?.collect().await?;
+        let batches = batches
+            .iter()
+            .filter(|batch| batch.num_rows() > 0)
+            .map(|batch| self.predict_batch(batch))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(concat_batches(&self.schema, &batches)?)
+    }
+
+    fn predict_batch(&self, batch: &RecordBatch) -> Result<RecordBatch> {
+        let raw: Vec<ArrayRef> = self
+            .features
+            .iter()
+            .map(|i| batch.column(*i).clone())
+            .collect();
+        let predictions = self.pipeline.predict(&raw)?;
+        let num_rows = batch.num_rows();
+
+        let mut columns = batch.columns().to_vec();
+        match &self.labels {
+            Some(labels) => {
+                let predictions = if self.margins {
+                    sigmoid(predictions)
+                } else {
+                    predictions
+                };
+                columns.push(labels.decode(&predictions, num_rows, 0.5)?);
+                if self.probabilities {
+                    columns.extend(probabilities(&predictions, num_rows, labels.len())?);
+                }
+            }
+            None => columns.push(Arc::new(Float32Array::from(predictions))),
+        }
+        Ok(RecordBatch::try_new(self.schema.clone(), columns)?)
+    }
+}
+
+/// One column of class probabilities per class, from the probability of
+/// the second class of binary models or the row-major probabilities of
+/// multi-class ones.
+fn probabilities(predictions: &[f32], num_rows: usize, classes: usize) -> Result<Vec<ArrayRef>> {
+    let class_probability: Box<dyn Fn(usize, usize) -> f32> =
+        if predictions.len() == num_rows && classes == 2 {
+            Box::new(|row, class| {
+                let p = predictions[row];
+                if class == 1 {
+                    p
+                } else {
+                    1.0 - p
+                }
+            })
+        } else if predictions.len() == num_rows * classes {
+            Box::new(|row, class| predictions[row * classes + class])
+        } else {
+            return Err(DataFusionError::Execution(format!(
+                "Cannot split {} predictions for {} rows into {} class probabilities",
+                predictions.len(),
+                num_rows,
+                classes
+            )));
+        };
+    Ok((0..classes)
+        .map(|class| {
+            Arc::new(Float32Array::from_iter_values(
+                (0..num_rows).map(|row| class_probability(row, class)),
+            )) as ArrayRef
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use datafusion::arrow::array::Array;
+
+    #[tokio::test]
+    pub async fn test_probabilities() -> Result<()> {
+        let column = |columns: &[ArrayRef], i: usize| -> Vec<f32> {
+            let column = columns[i].as_any().downcast_ref::<Float32Array>().unwrap();
+            (0..column.len()).map(|row| column.value(row)).collect()
+        };
+        let binary = probabilities(&[0.25, 1.0], 2, 2)?;
+        assert_eq!(column(&binary, 0), vec![0.75, 0.0]);
+        assert_eq!(column(&binary, 1), vec![0.25, 1.0]);
+        let multi = probabilities(&[0.5, 0.3, 0.2, 0.1, 0.1, 0.8], 2, 3)?;
+        assert_eq!(column(&multi, 2), vec![0.2, 0.8]);
+        assert!(probabilities(&[0.5], 2, 3).is_err());
+
+        assert!(is_query(" select * from t"));
+        assert!(is_query("WITH t AS (SELECT 1) SELECT * FROM t"));
+        assert!(!is_query("mushrooms"));
+        Ok(())
+    }
+}
@@ -0,0 +1,426 @@
+use crate::dmatrix::FeatureColumn;
+use crate::iteration::{predict_model_range, ModelVersion};
+use crate::train::{balanced_class_weights, collect_training_query, train_with_validation};
+use crate::{features_dmatrix, load_model, xgb_error, CategoryMapping};
+use crate::{FeatureLayout, IterationRange, TrainOptions};
+use datafusion::arrow::array::{
+    Array, ArrayRef, BooleanBuilder, Float32Array, ListBuilder, StringArray, StringBuilder,
+    StructBuilder,
+};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::{DataType, Field, Fields, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_expr::{ReturnTypeFunction, ScalarUDF, Signature, Volatility};
+use datafusion::physical_plan::functions::make_scalar_function;
+use datafusion::prelude::SessionContext;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use xgboost::Booster;
+
+/// How a raw column is turned into a feature of a [`Pipeline`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Encoder {
+    /// `onehot` over the vocabulary; null and unseen values have no key set.
+    Onehot(Vec<String>),
+    /// A categorical feature coded by position in the vocabulary, see
+    /// [`CategoryMapping`].
+    Categorical(Vec<String>),
+}
+
+impl Encoder {
+    pub fn vocabulary(&self) -> &[String] {
+        match self {
+            Encoder::Onehot(vocabulary) | Encoder::Categorical(vocabulary) => vocabulary,
+        }
+    }
+
+    /// Encodes a raw column, cast to strings, as the feature column `predict`
+    /// expects for this encoder.
+    pub fn encode(&self, column: &ArrayRef) -> Result<ArrayRef> {
+        let values = cast(column, &DataType::Utf8)?;
+        match self {
+            Encoder::Onehot(vocabulary) => {
+                let values = values
+                    .as_any()
+                    .downcast_ref::<StringArray>()
+                    .ok_or_else(|| DataFusionError::Internal("Expected StringArray".to_string()))?;
+                Ok(onehot_with_vocabulary(values, vocabulary))
+            }
+            Encoder::Categorical(_) => Ok(cast(
+                &values,
+                &DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)),
+            )?),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            Encoder::Onehot(vocabulary) => json!(["onehot", vocabulary]),
+            Encoder::Categorical(vocabulary) => json!(["categorical", vocabulary]),
+        }
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let vocabulary = value
+            .get(1)?
+            .as_array()?
+            .iter()
+            .map(|value| value.as_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()?;
+        match value.get(0)?.as_str()? {
+            "onehot" => Some(Encoder::Onehot(vocabulary)),
+            "categorical" => Some(Encoder::Categorical(vocabulary)),
+            _ => None,
+        }
+    }
+}
+
+/// A model file that also holds the encoders of its raw input columns in
+/// feature order, so raw columns can be scored without the `onehot` and
+/// `arrow_cast` SQL the model was trained with.
+///
+/// The encoders are saved as the [`Pipeline::ATTRIBUTE`] booster attribute,
+/// versioned by [`Pipeline::VERSION`].
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    columns: Vec<(String, Encoder)>,
+    model_path: PathBuf,
+}
+
+impl Pipeline {
+    /// Booster attribute holding the serialized encoders.
+    pub const ATTRIBUTE: &'static str = "pipeline";
+
+    /// Version of the saved encoders written by this library.
+    pub const VERSION: u64 = 1;
+
+    /// Fits the encoders on the raw feature columns of `sql`, trains a model
+    /// on the encoded rows as [`train`](crate::train) does and saves the
+    /// pipeline to `options.model_path`.
+    ///
+    /// Every column except `label` and the weight and group columns of
+    /// `options` is a feature, in query order, encoded with `onehot` unless
+    /// it is listed in `categorical`. Values are cast to strings and the
+    /// vocabularies are the sorted distinct values of the training rows.
+    pub async fn train(
+        ctx: &SessionContext,
+        sql: &str,
+        label: &str,
+        categorical: &[&str],
+        options: &TrainOptions,
+    ) -> Result<Self> {
+        let mut options = options.clone();
+        if options.balance_classes && options.class_weights.is_none() {
+            options.class_weights = Some(balanced_class_weights(ctx, sql, label).await?);
+        }
+        let batches = collect_training_query(ctx, sql, &options).await?;
+        let schema = batches
+            .first()
+            .map(|batch| batch.schema())
+            .ok_or_else(|| DataFusionError::Plan(format!("No training rows in {}", sql)))?;
+        let passed = [
+            Some(label),
+            options.weight.as_deref(),
+            options.group.as_deref(),
+        ];
+        let mut columns = Vec::new();
+        for field in schema.fields() {
+            if passed.contains(&Some(field.name().as_str())) {
+                continue;
+            }
+            let vocabulary = fit_vocabulary(&batches, field.name())?;
+            let encoder = if categorical.contains(&field.name().as_str()) {
+                Encoder::Categorical(vocabulary)
+            } else {
+                Encoder::Onehot(vocabulary)
+            };
+            columns.push((field.name().clone(), encoder));
+        }
+        let pipeline = Pipeline {
+            columns,
+            model_path: options.model_path.clone(),
+        };
+
+        let batches = pipeline.encode_batches(&batches)?;
+        let mut validation = Vec::with_capacity(options.validation.len());
+        for query in &options.validation {
+            let batches = collect_training_query(ctx, query, &options).await?;
+            validation.push(pipeline.encode_batches(&batches)?);
+        }
+        train_with_validation(&batches, &validation, label, &options)?;
+        pipeline.save()?;
+        Ok(pipeline)
+    }
+
+    /// Loads the pipeline saved at `model_path`.
+    pub fn load(model_path: impl AsRef<Path>) -> Result<Self> {
+        let model_path = model_path.as_ref();
+        Self::saved(&load_model(model_path)?, model_path)?.ok_or_else(|| {
+            DataFusionError::Execution(format!(
+                "Model {} is not a pipeline, it has no saved encoders",
+                model_path.display()
+            ))
+        })
+    }
+
+    /// The pipeline of any model: the saved one of a model trained by
+    /// [`Pipeline::train`], else encoders rebuilt from the feature layout and
+    /// categories saved by [`train`](crate::train), whose raw columns are
+    /// named like the feature columns the model was trained on.
+    pub fn of_model(model_path: impl AsRef<Path>) -> Result<Self> {
+        let model_path = model_path.as_ref();
+        let booster = load_model(model_path)?;
+        if let Some(pipeline) = Self::saved(&booster, model_path)? {
+            return Ok(pipeline);
+        }
+        let layout = FeatureLayout::load(&booster)?.ok_or_else(|| {
+            DataFusionError::Execution(format!(
+                "Model {} has no saved feature layout to encode columns with",
+                model_path.display()
+            ))
+        })?;
+        let categories = CategoryMapping::load(&booster)?.unwrap_or_default();
+        let columns = layout
+            .columns()
+            .iter()
+            .enumerate()
+            .map(|(i, (name, column))| {
+                let encoder = match column {
+                    FeatureColumn::Onehot(keys) => Encoder::Onehot(keys.clone()),
+                    FeatureColumn::Categorical => {
+                        Encoder::Categorical(categories.vocabulary(i).unwrap_or_default().to_vec())
+                    }
+                };
+                (name.clone(), encoder)
+            })
+            .collect();
+        Ok(Pipeline {
+            columns,
+            model_path: model_path.to_path_buf(),
+        })
+    }
+
+    fn saved(booster: &Booster, model_path: &Path) -> Result<Option<Self>> {
+        let Some(value) = booster.get_attribute(Self::ATTRIBUTE).map_err(xgb_error)? else {
+            return Ok(None);
+        };
+        let invalid = || DataFusionError::Internal(format!("Invalid pipeline {}", value));
+        let value: Value = serde_json::from_str(&value).map_err(|_| invalid())?;
+        let version = value["version"].as_u64().ok_or_else(invalid)?;
+        if version != Self::VERSION {
+            return Err(DataFusionError::Execution(format!(
+                "Model {} has pipeline version {}, this library reads version {}",
+                model_path.display(),
+                version,
+                Self::VERSION
+            )));
+        }
+        let columns = value["columns"]
+            .as_array()
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|column| {
+                let name = column.get(0)?.as_str()?.to_string();
+                Some((name, Encoder::from_json(column.get(1)?)?))
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        Ok(Some(Pipeline {
+            columns,
+            model_path: model_path.to_path_buf(),
+        }))
+    }
+
+    fn save(&self) -> Result<()> {
+        let mut booster = load_model(&self.model_path)?;
+        let columns: Vec<Value> = self
+            .columns
+            .iter()
+            .map(|(name, encoder)| json!([name, encoder.to_json()]))
+            .collect();
+        let value = json!({ "version": Self::VERSION, "columns": columns });
+        booster
+            .set_attribute(Self::ATTRIBUTE, &value.to_string())
+            .map_err(xgb_error)?;
+        booster.save(&self.model_path).map_err(xgb_error)
+    }
+
+    /// Names of the raw feature columns in the order `score` takes them.
+    pub fn columns(&self) -> Vec<&str> {
+        self.columns.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    pub fn encoder(&self, column: &str) -> Option<&Encoder> {
+        self.columns
+            .iter()
+            .find(|(name, _)| name == column)
+            .map(|(_, encoder)| encoder)
+    }
+
+    pub fn model_path(&self) -> &Path {
+        &self.model_path
+    }
+
+    /// Encodes raw feature columns, given in [`columns`](Self::columns) order.
+    pub fn encode(&self, raw: &[ArrayRef]) -> Result<Vec<ArrayRef>> {
+        if raw.len() != self.columns.len() {
+            return Err(DataFusionError::Plan(format!(
+                "Pipeline {} takes {} columns ({}), got {}",
+                self.model_path.display(),
+                self.columns.len(),
+                self.columns().join(", "),
+                raw.len()
+            )));
+        }
+        self.columns
+            .iter()
+            .zip(raw)
+            .map(|((_, encoder), column)| encoder.encode(column))
+            .collect()
+    }
+
+    /// Replaces the raw feature columns of every batch by their encoding,
+    /// keeping the other columns.
+    fn encode_batches(&self, batches: &[RecordBatch]) -> Result<Vec<RecordBatch>> {
+        batches
+            .iter()
+            .map(|batch| {
+                let mut fields = Vec::with_capacity(batch.num_columns());
+                let mut arrays = Vec::with_capacity(batch.num_columns());
+                for (field, column) in batch.schema().fields().iter().zip(batch.columns()) {
+                    match self.encoder(field.name()) {
+                        Some(encoder) => {
+                            let encoded = encoder.encode(column)?;
+                            fields.push(Field::new(
+                                field.name(),
+                                encoded.data_type().clone(),
+                                true,
+                            ));
+                            arrays.push(encoded);
+                        }
+                        None => {
+                            fields.push(field.as_ref().clone());
+                            arrays.push(column.clone());
+                        }
+                    }
+                }
+                Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?)
+            })
+            .collect()
+    }
+
+    /// Predictions of the model for raw feature columns.
+    pub fn predict(&self, raw: &[ArrayRef]) -> Result<Vec<f32>> {
+        let features = self.encode(raw)?;
+        if features.first().is_some_and(|feature| feature.is_empty()) {
+            return Ok(Vec::new());
+        }
+        let (booster, version) = ModelVersion::load(&self.model_path)?;
+        let categories = CategoryMapping::for_scoring(CategoryMapping::load(&booster)?, &features)?;
+        let (dmat, _) = features_dmatrix(&features, &categories)?;
+        predict_model_range(&booster, &version, &dmat, IterationRange::default())
+    }
+
+    /// Creates a UDF scoring raw columns with the pipeline,
+    /// `score(raw_col1, ..., raw_coln)` in [`columns`](Self::columns) order.
+    pub fn score_udf(&self, name: &str) -> ScalarUDF {
+        let pipeline = self.clone();
+        let score = make_scalar_function(move |args: &[ArrayRef]| {
+            Ok(Arc::new(Float32Array::from(pipeline.predict(args)?)) as ArrayRef)
+        });
+        let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Float32)));
+        ScalarUDF::new(
+            name,
+            &Signature::variadic_any(Volatility::Immutable),
+            &return_type,
+            &score,
+        )
+    }
+}
+
+/// Sorted distinct non-null values of the `column` of `batches`, as strings.
+fn fit_vocabulary(batches: &[RecordBatch], column: &str) -> Result<Vec<String>> {
+    let mut vocabulary = BTreeSet::new();
+    for batch in batches {
+        let values = cast(
+            batch.column(batch.schema().index_of(column)?),
+            &DataType::Utf8,
+        )?;
+        let values = values
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .ok_or_else(|| DataFusionError::Internal("Expected StringArray".to_string()))?;
+        vocabulary.extend(values.iter().flatten().map(str::to_string));
+    }
+    Ok(vocabulary.into_iter().collect())
+}
+
+/// Builds the `onehot` list of every value with the keys of `vocabulary`,
+/// so all batches have the same features.
+pub(crate) fn onehot_with_vocabulary(values: &StringArray, vocabulary: &[String]) -> ArrayRef {
+    let struct_builder = StructBuilder::new(
+        Fields::from(vec![
+            Field::new("key", DataType::Utf8, false),
+            Field::new("value", DataType::Boolean, false),
+        ]),
+        vec![
+            Box::new(StringBuilder::new()) as Box<dyn datafusion::arrow::array::ArrayBuilder>,
+            Box::new(BooleanBuilder::new()),
+        ],
+    );
+    let mut list_builder = ListBuilder::new(struct_builder);
+    for value in values.iter() {
+        for key in vocabulary {
+            let entries = list_builder.values();
+            entries
+                .field_builder::<StringBuilder>(0)
+                .unwrap()
+                .append_value(key);
+            entries
+                .field_builder::<BooleanBuilder>(1)
+                .unwrap()
+                .append_value(value == Some(key.as_str()));
+            entries.append(true);
+        }
+        list_builder.append(true);
+    }
+    Arc::new(list_builder.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use datafusion::arrow::array::{ListArray, StructArray};
+
+    #[tokio::test]
+    pub async fn test_encoders() -> Result<()> {
+        let vocabulary = vec!["a".to_string(), "b".to_string()];
+        let raw: ArrayRef = Arc::new(StringArray::from(vec![Some("b"), None, Some("z")]));
+
+        let onehot = Encoder::Onehot(vocabulary.clone()).encode(&raw)?;
+        let onehot = onehot.as_any().downcast_ref::<ListArray>().unwrap();
+        let set = |row: usize| -> Vec<bool> {
+            let entries = onehot.value(row);
+            let entries = entries.as_any().downcast_ref::<StructArray>().unwrap();
+            let values = entries
+                .column(1)
+                .as_any()
+                .downcast_ref::<datafusion::arrow::array::BooleanArray>()
+                .unwrap();
+            values.iter().map(Option::unwrap).collect()
+        };
+        assert_eq!(set(0), vec![false, true]);
+        assert_eq!(set(1), vec![false, false]);
+        assert_eq!(set(2), vec![false, false]);
+
+        let categorical = Encoder::Categorical(vocabulary.clone());
+        assert!(crate::is_categorical(categorical.encode(&raw)?.data_type()));
+        for encoder in [Encoder::Onehot(vocabulary), categorical] {
+            assert_eq!(Encoder::from_json(&encoder.to_json()), Some(encoder));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use crate::{onehot_udf, predict_udf, Encoder, Pipeline};
+use datafusion::arrow::array::ArrayRef;
+use datafusion::arrow::datatypes::DataType;
+use datafusion::common::config::ConfigOptions;
+use datafusion::common::tree_node::{Transformed, TreeNode, VisitRecursion};
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_expr::expr::{Cast, ScalarFunction};
+use datafusion::logical_expr::{
+    lit, Expr, LogicalPlan, ReturnTypeFunction, ScalarFunctionDefinition, ScalarUDF, Signature,
+    Volatility,
+};
+use datafusion::optimizer::analyzer::AnalyzerRule;
+use datafusion::physical_plan::functions::make_scalar_function;
+use datafusion::prelude::SessionContext;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Analyzer rule rewriting `name(raw_col1, ..., raw_coln)` over raw columns
+/// into the `predict` call the model was trained for, e.g.
+/// `predict(onehot(arrow_cast(cap_shape, 'Dictionary(Int32, Utf8)'), 'b', 'c', ...), ...)`.
+///
+/// The arguments are encoded by the [`Pipeline::of_model`] of the model, so
+/// `onehot` columns get the keys of the training data in training order
+/// rather than the dictionary values of each batch. The model is read when
+/// a query is planned, so a retrained model is picked up by new queries.
+#[derive(Debug, Clone)]
+pub struct PredictRawRule {
+    name: String,
+    model_path: PathBuf,
+}
+
+impl PredictRawRule {
+    pub fn new(name: &str, model_path: impl AsRef<Path>) -> Self {
+        PredictRawRule {
+            name: name.to_string(),
+            model_path: model_path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// The function the rule rewrites, for the SQL planner to resolve calls
+    /// to. It fails if it is run, as without the rule no encoding is known.
+    pub fn udf(&self) -> ScalarUDF {
+        let name = self.name.clone();
+        let unplanned = make_scalar_function(move |_: &[ArrayRef]| -> Result<ArrayRef> {
+            Err(DataFusionError::Plan(format!(
+                "{} is rewritten by PredictRawRule, add the rule to the session",
+                name
+            )))
+        });
+        let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Float32)));
+        ScalarUDF::new(
+            &self.name,
+            &Signature::variadic_any(Volatility::Immutable),
+            &return_type,
+            &unplanned,
+        )
+    }
+
+    fn is_call(&self, expr: &Expr) -> bool {
+        matches!(
+            expr,
+            Expr::ScalarFunction(ScalarFunction {
+                func_def: ScalarFunctionDefinition::UDF(udf),
+                ..
+            }) if udf.name() == self.name
+        )
+    }
+
+    fn contains_call(&self, expr: &Expr) -> Result<bool> {
+        let mut found = false;
+        expr.apply(&mut |expr| {
+            found |= self.is_call(expr);
+            Ok(if found {
+                VisitRecursion::Stop
+            } else {
+                VisitRecursion::Continue
+            })
+        })?;
+        Ok(found)
+    }
+
+    /// Rewrites the calls in the expressions of one plan node. Output
+    /// columns keep their names, as the nodes above refer to them.
+    fn rewrite_node(&self, plan: LogicalPlan, pipeline: &Pipeline) -> Result<LogicalPlan> {
+        let exprs = plan.expressions();
+        let mut changed = false;
+        for expr in &exprs {
+            changed |= self.contains_call(expr)?;
+        }
+        if !changed {
+            return Ok(plan);
+        }
+        let names_output = matches!(plan, LogicalPlan::Projection(_) | LogicalPlan::Aggregate(_));
+        let exprs = exprs
+            .into_iter()
+            .map(|expr| {
+                if !self.contains_call(&expr)? {
+                    return Ok(expr);
+                }
+                let name = expr.display_name()?;
+                let rewritten = expr.transform_up(&|expr| {
+                    if !self.is_call(&expr) {
+                        return Ok(Transformed::No(expr));
+                    }
+                    let Expr::ScalarFunction(call) = expr else {
+                        unreachable!()
+                    };
+                    Ok(Transformed::Yes(self.predict(call.args, pipeline)?))
+                })?;
+                Ok(match rewritten {
+                    Expr::Alias(_) => rewritten,
+                    rewritten if names_output => rewritten.alias(name),
+                    rewritten => rewritten,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let inputs: Vec<LogicalPlan> = plan.inputs().into_iter().cloned().collect();
+        plan.with_new_exprs(exprs, &inputs)
+    }
+
+    /// The `predict` call scoring the raw `args`.
+    fn predict(&self, args: Vec<Expr>, pipeline: &Pipeline) -> Result<Expr> {
+        let columns = pipeline.columns();
+        if args.len() != columns.len() {
+            return Err(DataFusionError::Plan(format!(
+                "{} takes the {} columns {}, got {} arguments",
+                self.name,
+                columns.len(),
+                columns.join(", "),
+                args.len()
+            )));
+        }
+        let onehot = Arc::new(onehot_udf());
+        let features = args
+            .into_iter()
+            .zip(&columns)
+            .map(|(arg, column)| {
+                let encoder = pipeline.encoder(column).ok_or_else(|| {
+                    DataFusionError::Internal(format!("No encoder for {}", column))
+                })?;
+                let dictionary = Expr::Cast(Cast::new(
+                    Box::new(arg),
+                    DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)),
+                ));
+                Ok(match encoder {
+                    Encoder::Onehot(keys) => {
+                        let mut args = vec![dictionary];
+                        args.extend(keys.iter().map(|key| lit(key.as_str())));
+                        Expr::ScalarFunction(ScalarFunction::new_udf(onehot.clone(), args))
+                    }
+                    Encoder::Categorical(_) => dictionary,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let predict = Arc::new(predict_udf("predict", &self.model_path));
+        Ok(Expr::ScalarFunction(ScalarFunction::new_udf(
+            predict, features,
+        )))
+    }
+}
+
+impl AnalyzerRule for PredictRawRule {
+    fn analyze(&self, plan: LogicalPlan, _config: &ConfigOptions) -> Result<LogicalPlan> {
+        let mut calls = false;
+        plan.apply(&mut |node| {
+            for expr in node.expressions() {
+                calls |= self.contains_call(&expr)?;
+            }
+            Ok(VisitRecursion::Continue)
+        })?;
+        if !calls {
+            return Ok(plan);
+        }
+        let pipeline = Pipeline::of_model(&self.model_path)?;
+        plan.transform_up(&|node| Ok(Transformed::Yes(self.rewrite_node(node, &pipeline)?)))
+    }
+
+    fn name(&self) -> &str {
+        "predict_raw"
+    }
+}
+
+/// A session like `ctx` where `name(raw_col1, ..., raw_coln)` scores raw
+/// columns with the model at `model_path`, see [`PredictRawRule`]. The
+/// session shares the tables of `ctx`, and its `ml_predict` still plans its
+/// inputs in `ctx`.
+pub fn with_predict_raw(
+    ctx: &SessionContext,
+    name: &str,
+    model_path: impl AsRef<Path>,
+) -> SessionContext {
+    let rule = PredictRawRule::new(name, model_path);
+    let ctx = SessionContext::new_with_state(ctx.state().add_analyzer_rule(Arc::new(rule.clone())));
+    ctx.register_udf(rule.udf());
+    ctx
+}
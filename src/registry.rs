@@ -0,0 +1,160 @@
+use crate::iteration::scoring_function;
+use crate::{predict_with_model, xgb_error, TrainingLog};
+use datafusion::arrow::array::{Array, ArrayRef, Float32Array, StringArray};
+use datafusion::arrow::datatypes::DataType;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_expr::{
+    ColumnarValue, ReturnTypeFunction, ScalarFunctionImplementation, ScalarUDF, Signature,
+    Volatility,
+};
+use datafusion::scalar::ScalarValue;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use xgboost::Booster;
+
+const MODEL_EXTENSION: &str = "xgb";
+
+/// Named models stored as `<name>.xgb` files in a directory.
+///
+/// The directory is the only state, so models created by one session are
+/// visible to the next one opened on the same directory.
+#[derive(Debug, Clone)]
+pub struct ModelRegistry {
+    dir: PathBuf,
+}
+
+impl ModelRegistry {
+    /// Opens the registry in `dir`, creating the directory if needed.
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(ModelRegistry { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// File the model called `name` is saved to.
+    pub fn path(&self, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(DataFusionError::Plan(format!(
+                "Invalid model name {}, expected letters, digits and underscores",
+                name
+            )));
+        }
+        Ok(self
+            .dir
+            .join(name.to_lowercase())
+            .with_extension(MODEL_EXTENSION))
+    }
+
+    pub fn contains(&self, name: &str) -> Result<bool> {
+        Ok(self.path(name)?.exists())
+    }
+
+    /// Path of an existing model, failing if there is none called `name`.
+    pub fn resolve(&self, name: &str) -> Result<PathBuf> {
+        let path = self.path(name)?;
+        if !path.exists() {
+            return Err(DataFusionError::Plan(format!(
+                "Model {} does not exist",
+                name
+            )));
+        }
+        Ok(path)
+    }
+
+    /// Loads the booster of the model called `name`.
+    pub fn load(&self, name: &str) -> Result<Booster> {
+        Booster::load(self.resolve(name)?).map_err(xgb_error)
+    }
+
+    /// Names of all models, sorted.
+    pub fn names(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some(MODEL_EXTENSION) {
+                if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Deletes the model called `name` and its training log.
+    pub fn remove(&self, name: &str) -> Result<()> {
+        let path = self.resolve(name)?;
+        fs::remove_file(&path)?;
+        let log = TrainingLog::path(&path);
+        if log.exists() {
+            fs::remove_file(log)?;
+        }
+        Ok(())
+    }
+
+    /// `predict_model('<name>', f1, ..., fn)` scores with a registered model.
+    ///
+    /// The model is looked up when the query runs, so models created or
+    /// dropped after the UDF is registered are picked up. The name must be a
+    /// string literal, as one model scores all rows of a call. The feature
+    /// arguments are the same as for `predict`.
+    pub fn predict_udf(self: &Arc<Self>) -> ScalarUDF {
+        let registry = self.clone();
+        let score = scoring_function(move |args: &[ArrayRef]| {
+            let name = args[0].as_any().downcast_ref::<StringArray>().unwrap();
+            if name.is_empty() {
+                return Ok(Arc::new(Float32Array::from(Vec::<f32>::new())) as ArrayRef);
+            }
+            predict_with_model(&args[1..], &registry.resolve(name.value(0))?)
+        });
+        let predict: ScalarFunctionImplementation =
+            Arc::new(move |args: &[ColumnarValue]| match args.first() {
+                Some(ColumnarValue::Scalar(ScalarValue::Utf8(Some(_)))) => score(args),
+                _ => Err(DataFusionError::Plan(
+                    "predict_model expects the model name as a string literal first argument"
+                        .to_string(),
+                )),
+            });
+        let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Float32)));
+        ScalarUDF::new(
+            "predict_model",
+            &Signature::variadic_any(Volatility::Volatile),
+            &return_type,
+            &predict,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_model_paths() -> Result<()> {
+        let registry = ModelRegistry::new(std::env::temp_dir().join("registry_test_model_paths"))?;
+        assert_eq!(
+            registry.path("Mushrooms_1")?,
+            registry.dir().join("mushrooms_1.xgb")
+        );
+        assert!(registry.path("../model").is_err());
+        assert!(registry.path("").is_err());
+        assert!(registry.resolve("missing").is_err());
+
+        let predict = Arc::new(registry).predict_udf();
+        let name = ColumnarValue::Scalar(ScalarValue::Utf8(Some("missing".to_string())));
+        let empty: ArrayRef = Arc::new(StringArray::from(Vec::<&str>::new()));
+        let result = predict.fun()(&[name, ColumnarValue::Array(empty)])?;
+        assert_eq!(result.into_array(0)?.len(), 0);
+
+        // a column of names would score every row with the first model
+        let names: ArrayRef = Arc::new(StringArray::from(vec!["a", "b"]));
+        let error = predict.fun()(&[ColumnarValue::Array(names)]).unwrap_err();
+        assert!(error.to_string().contains("string literal"));
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+use crate::iteration::{scoring_function, ModelVersion};
+use crate::score;
+use datafusion::arrow::array::{Array, ArrayRef, Float32Array, StringArray, UInt32Array};
+use datafusion::arrow::compute::{cast, take};
+use datafusion::arrow::datatypes::DataType;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_expr::{ReturnTypeFunction, ScalarUDF, Signature, Volatility};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Model files by the key value whose rows they score.
+pub type ModelRoutes = HashMap<String, PathBuf>;
+
+/// Routes to the `*.xgb` files of `dir` by file stem, e.g. `g` for `g.xgb`,
+/// as written for the models of a `train_xgboost_agg` query.
+pub fn model_routes(dir: impl AsRef<Path>) -> Result<ModelRoutes> {
+    let mut routes = ModelRoutes::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "xgb") {
+            if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+                routes.insert(stem.to_string(), path.clone());
+            }
+        }
+    }
+    Ok(routes)
+}
+
+/// Creates a scoring UDF that picks the model of every row by a key column,
+/// `predict_routed(key, f1, ..., fn)`.
+///
+/// The key is cast to a string and looked up in `routes`. The rows of a batch
+/// are grouped by key and every model scores its rows as one `DMatrix`, with
+/// the feature arguments and iteration range accepted by
+/// [`predict_udf`](crate::predict_udf). Rows with a null key or a key without
+/// a model get a null prediction.
+pub fn predict_routed_udf(name: &str, routes: ModelRoutes) -> ScalarUDF {
+    let predict = scoring_function(move |args: &[ArrayRef]| predict_routed(args, &routes));
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Float32)));
+    ScalarUDF::new(
+        name,
+        &Signature::variadic_any(Volatility::Immutable),
+        &return_type,
+        &predict,
+    )
+}
+
+fn predict_routed(args: &[ArrayRef], routes: &ModelRoutes) -> Result<ArrayRef> {
+    let [key, features @ ..] = args else {
+        return Err(DataFusionError::Plan(
+            "predict_routed expects a key and features".to_string(),
+        ));
+    };
+    let keys = cast(key, &DataType::Utf8)?;
+    let keys = keys
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| DataFusionError::Internal("Expected StringArray".to_string()))?;
+
+    // rows per routed key, ordered so models are loaded in a stable order
+    let mut rows: BTreeMap<&str, Vec<u32>> = BTreeMap::new();
+    for (row, key) in keys.iter().enumerate() {
+        if let Some(key) = key.filter(|key| routes.contains_key(*key)) {
+            rows.entry(key).or_default().push(row as u32);
+        }
+    }
+
+    let mut predictions: Vec<Option<f32>> = vec![None; keys.len()];
+    for (key, rows) in rows {
+        let indices = UInt32Array::from(rows);
+        let features = features
+            .iter()
+            .map(|feature| Ok(take(feature.as_ref(), &indices, None)?))
+            .collect::<Result<Vec<_>>>()?;
+        let (booster, version) = ModelVersion::load(&routes[key])?;
+        let (scores, _) = score(&features, &booster, &version)?;
+        for (row, score) in indices.values().iter().zip(scores) {
+            predictions[*row as usize] = Some(score);
+        }
+    }
+    Ok(Arc::new(Float32Array::from(predictions)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_model_routes() -> Result<()> {
+        let dir = std::env::temp_dir().join("test_model_routes");
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("north.xgb"), b"")?;
+        std::fs::write(dir.join("north.log.csv"), b"")?;
+        let routes = model_routes(&dir)?;
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes["north"], dir.join("north.xgb"));
+        Ok(())
+    }
+}
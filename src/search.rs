@@ -0,0 +1,479 @@
+use crate::table_function::{literal_string, parse_option, LazyTable};
+use crate::train::{
+    collect_training_query, save_model, train_booster_with_validation, training_data, TrainOptions,
+    TrainingOutcome,
+};
+use datafusion::arrow::array::{ArrayRef, BooleanArray, Float32Array, StringArray, UInt32Array};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datasource::function::TableFunctionImpl;
+use datafusion::datasource::TableProvider;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_expr::Expr;
+use datafusion::prelude::SessionContext;
+use std::sync::Arc;
+use xgboost::Booster;
+
+/// Hyperparameters a [`SearchSpace`] can vary.
+pub const SEARCHABLE: &[&str] = &[
+    "max_depth",
+    "eta",
+    "learning_rate",
+    "gamma",
+    "min_child_weight",
+    "subsample",
+    "colsample_bytree",
+    "lambda",
+    "alpha",
+];
+
+/// Returns true for the [`SEARCHABLE`] parameters the xgboost crate takes as
+/// integers, which cannot be searched over a [`ParamRange::Uniform`] range.
+fn is_integer_param(name: &str) -> bool {
+    matches!(
+        name,
+        "max_depth" | "gamma" | "min_child_weight" | "lambda" | "alpha"
+    )
+}
+
+/// Values tried for one hyperparameter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamRange {
+    /// Exactly these values.
+    Values(Vec<String>),
+    /// The integers from `low` to `high`, both included.
+    IntRange(i64, i64),
+    /// Numbers drawn uniformly from `[low, high)`. Only random search can
+    /// use such a range.
+    Uniform(f64, f64),
+}
+
+impl ParamRange {
+    /// Parses the values of parameter `name`: a list like `3,4,6` or a range
+    /// like `2..8`. Ranges of `max_depth`, `gamma`, `min_child_weight`,
+    /// `lambda` and `alpha`, which the xgboost crate takes as integers, are
+    /// integer ranges, the others number ranges, so `eta=0..1` draws from
+    /// `[0, 1)`.
+    pub fn parse(name: &str, value: &str) -> Result<Self> {
+        let invalid =
+            || DataFusionError::Plan(format!("Invalid parameter range {} for {}", value, name));
+        let Some((low, high)) = value.split_once("..") else {
+            return Ok(ParamRange::Values(
+                value.split(',').map(|v| v.trim().to_string()).collect(),
+            ));
+        };
+        let (low, high) = (low.trim(), high.trim());
+        if is_integer_param(name) {
+            let low: i64 = low.parse().map_err(|_| invalid())?;
+            let high: i64 = high.parse().map_err(|_| invalid())?;
+            if low > high {
+                return Err(invalid());
+            }
+            return Ok(ParamRange::IntRange(low, high));
+        }
+        let low: f64 = low.parse().map_err(|_| invalid())?;
+        let high: f64 = high.parse().map_err(|_| invalid())?;
+        if low.is_nan() || high.is_nan() || low >= high {
+            return Err(invalid());
+        }
+        Ok(ParamRange::Uniform(low, high))
+    }
+
+    /// All values, failing for [`ParamRange::Uniform`].
+    fn values(&self, name: &str) -> Result<Vec<String>> {
+        match self {
+            ParamRange::Values(values) => Ok(values.clone()),
+            ParamRange::IntRange(low, high) => Ok((*low..=*high).map(|v| v.to_string()).collect()),
+            ParamRange::Uniform(..) => Err(DataFusionError::Plan(format!(
+                "Grid search needs a list of values for {}",
+                name
+            ))),
+        }
+    }
+
+    fn sample(&self, rng: &mut SplitMix64) -> String {
+        match self {
+            ParamRange::Values(values) => {
+                values[(rng.next() % values.len() as u64) as usize].clone()
+            }
+            ParamRange::IntRange(low, high) => {
+                let size = (high - low) as u64 + 1;
+                (low + (rng.next() % size) as i64).to_string()
+            }
+            ParamRange::Uniform(low, high) => {
+                let value = low + rng.next_unit() * (high - low);
+                // short values keep the trials table readable
+                format!("{:.4}", value)
+            }
+        }
+    }
+}
+
+/// The hyperparameters to search over, in the order they are varied.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchSpace {
+    params: Vec<(String, ParamRange)>,
+}
+
+impl SearchSpace {
+    pub fn new() -> Self {
+        SearchSpace::default()
+    }
+
+    /// Adds a parameter, which must be one of [`SEARCHABLE`]. Listed values
+    /// and range bounds are checked with [`TrainOptions::set`], so a search
+    /// does not fail after some trials were trained.
+    pub fn with(mut self, name: &str, range: ParamRange) -> Result<Self> {
+        if !SEARCHABLE.contains(&name) {
+            return Err(DataFusionError::Plan(format!(
+                "Cannot search over {}, expected one of {:?}",
+                name, SEARCHABLE
+            )));
+        }
+        let mut options = TrainOptions::default();
+        match &range {
+            ParamRange::Values(values) => {
+                for value in values {
+                    options.set(name, value)?;
+                }
+            }
+            ParamRange::IntRange(low, high) => {
+                options.set(name, &low.to_string())?;
+                options.set(name, &high.to_string())?;
+            }
+            ParamRange::Uniform(..) if is_integer_param(name) => {
+                return Err(DataFusionError::Plan(format!(
+                    "{} takes integers, search it over a list or an integer range",
+                    name
+                )))
+            }
+            ParamRange::Uniform(..) => {}
+        }
+        self.params.push((name.to_string(), range));
+        Ok(self)
+    }
+
+    pub fn params(&self) -> &[(String, ParamRange)] {
+        &self.params
+    }
+
+    /// Every combination of values, the last parameter varying fastest.
+    pub fn grid(&self) -> Result<Vec<Vec<(String, String)>>> {
+        let mut candidates = vec![vec![]];
+        for (name, range) in &self.params {
+            let values = range.values(name)?;
+            candidates = candidates
+                .into_iter()
+                .flat_map(|candidate: Vec<(String, String)>| {
+                    values.iter().map(move |value| {
+                        let mut candidate = candidate.clone();
+                        candidate.push((name.clone(), value.clone()));
+                        candidate
+                    })
+                })
+                .collect();
+        }
+        Ok(candidates)
+    }
+
+    /// `trials` combinations drawn with a generator seeded with `seed`.
+    pub fn sample(&self, trials: usize, seed: u64) -> Vec<Vec<(String, String)>> {
+        let mut rng = SplitMix64(seed);
+        (0..trials)
+            .map(|_| {
+                self.params
+                    .iter()
+                    .map(|(name, range)| (name.clone(), range.sample(&mut rng)))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// How candidates are picked from a [`SearchSpace`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchStrategy {
+    /// Every combination of values.
+    Grid,
+    /// `trials` random combinations.
+    Random { trials: usize },
+}
+
+/// Small deterministic generator, so a search gives the same candidates for
+/// the same seed on every platform.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn next_unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Runs the training and validation queries and searches for the
+/// hyperparameters scoring best on the validation rows, see
+/// [`search_batches`].
+pub async fn search(
+    ctx: &SessionContext,
+    train_sql: &str,
+    validation_sql: &str,
+    label: &str,
+    space: &SearchSpace,
+    strategy: SearchStrategy,
+    options: &TrainOptions,
+) -> Result<RecordBatch> {
+    let batches = collect_training_query(ctx, train_sql, options).await?;
+    let validation = collect_training_query(ctx, validation_sql, options).await?;
+    search_batches(&batches, &validation, label, space, strategy, options)
+}
+
+/// Trains one model per candidate of `space` on `batches` and evaluates it
+/// on `validation`.
+///
+/// Every candidate starts from `options` with the searched parameters set.
+/// Trials are scored with the last `eval_metric`, or the default metric of
+/// the objective, on the validation rows after the best round when stopping
+/// early and after the last round otherwise. The best trial is saved to
+/// `options.model_path` like [`train`](crate::train::train) does; ties go to
+/// the earlier trial. Random candidates are drawn with `options.seed`, so a
+/// search is reproducible. Returns one row per trial, see [`trials_schema`].
+pub fn search_batches(
+    batches: &[RecordBatch],
+    validation: &[RecordBatch],
+    label: &str,
+    space: &SearchSpace,
+    strategy: SearchStrategy,
+    options: &TrainOptions,
+) -> Result<RecordBatch> {
+    let candidates = match strategy {
+        SearchStrategy::Grid => space.grid()?,
+        SearchStrategy::Random { trials } => space.sample(trials, options.seed),
+    };
+    if candidates.is_empty() {
+        return Err(DataFusionError::Plan(
+            "Hyperparameter search needs at least one trial".to_string(),
+        ));
+    }
+    let (dtrain, encoding) = training_data(batches, label, options)?;
+    let dvalid = encoding.dmatrix(validation, label, options)?;
+    let evals = [("validation_0", &dvalid)];
+
+    let mut params = Vec::new();
+    let mut rounds = Vec::new();
+    let mut best_iterations = Vec::new();
+    let mut metrics = Vec::new();
+    let mut train_values = Vec::new();
+    let mut validation_values = Vec::new();
+    let mut best: Option<(usize, f32, Booster, TrainingOutcome, TrainOptions)> = None;
+    for candidate in &candidates {
+        let mut trial_options = options.clone();
+        for (name, value) in candidate {
+            trial_options.set(name, value)?;
+        }
+        let (booster, outcome) =
+            train_booster_with_validation(&dtrain, &evals, None, &trial_options)?;
+        let round = match outcome.best {
+            Some(best) => best.round,
+            None => outcome.rounds - 1,
+        };
+        let entry = trial_options.stopping_entry(&outcome.log, round, "validation_0")?;
+        let (metric, value) = (entry.metric.clone(), entry.value);
+        let train_value = outcome.log.value(round, "train", &metric);
+
+        let trial = params.len();
+        let better = best.as_ref().is_none_or(|(_, best_value, ..)| {
+            if trial_options.maximize(&metric) {
+                value > *best_value
+            } else {
+                value < *best_value
+            }
+        });
+        if better {
+            best = Some((trial, value, booster, outcome.clone(), trial_options));
+        }
+        params.push(
+            candidate
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+        rounds.push(outcome.rounds);
+        best_iterations.push(outcome.best.map(|best| best.round));
+        metrics.push(metric);
+        train_values.push(train_value);
+        validation_values.push(value);
+    }
+
+    let (best_trial, _, mut booster, outcome, best_options) =
+        best.ok_or_else(|| DataFusionError::Internal("No trial was trained".to_string()))?;
+    save_model(&mut booster, &encoding, &outcome, label, &best_options)?;
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt32Array::from_iter_values(0..params.len() as u32)),
+        Arc::new(StringArray::from(params)),
+        Arc::new(UInt32Array::from(rounds)),
+        Arc::new(UInt32Array::from(best_iterations)),
+        Arc::new(StringArray::from(metrics)),
+        Arc::new(Float32Array::from(train_values)),
+        Arc::new(Float32Array::from(validation_values)),
+        Arc::new(BooleanArray::from(
+            (0..candidates.len())
+                .map(|trial| trial == best_trial)
+                .collect::<Vec<_>>(),
+        )),
+    ];
+    Ok(RecordBatch::try_new(trials_schema(), columns)?)
+}
+
+/// Schema of the result of [`search`]: the trial number, its parameters as
+/// `name=value` pairs, the rounds trained and the best round when stopping
+/// early, the metric with its value on the training and validation rows, and
+/// whether the trial was saved as the best model.
+pub fn trials_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("trial", DataType::UInt32, false),
+        Field::new("params", DataType::Utf8, false),
+        Field::new("rounds", DataType::UInt32, false),
+        Field::new("best_iteration", DataType::UInt32, true),
+        Field::new("metric", DataType::Utf8, false),
+        Field::new("train", DataType::Float32, true),
+        Field::new("validation", DataType::Float32, false),
+        Field::new("best", DataType::Boolean, false),
+    ]))
+}
+
+/// `xgboost_search('<train query>', '<validation query>', '<label>', 'key=value', ...)`
+/// table function, see [`search`].
+///
+/// Options of a [`SEARCHABLE`] parameter whose value is a list or a range,
+/// e.g. `'max_depth=3,4,6'` or `'eta=0.05..0.3'`, make up the search space.
+/// `'trials=n'` switches from grid search to `n` random trials. All other
+/// options are the ones accepted by [`TrainOptions::set`].
+pub struct SearchFunction;
+
+impl TableFunctionImpl for SearchFunction {
+    fn call(&self, args: &[Expr]) -> Result<Arc<dyn TableProvider>> {
+        let args = args
+            .iter()
+            .map(|arg| literal_string("xgboost_search", arg))
+            .collect::<Result<Vec<_>>>()?;
+        let [train_sql, validation_sql, label, rest @ ..] = &args[..] else {
+            return Err(DataFusionError::Plan(
+                "xgboost_search expects a training query, a validation query and a label column"
+                    .to_string(),
+            ));
+        };
+
+        let mut space = SearchSpace::new();
+        let mut strategy = SearchStrategy::Grid;
+        let mut options = TrainOptions::default();
+        for option in rest {
+            let (key, value) = parse_option(option)?;
+            if key == "trials" {
+                let trials = value.parse().map_err(|_| {
+                    DataFusionError::Plan(format!("Invalid value {} for trials", value))
+                })?;
+                strategy = SearchStrategy::Random { trials };
+            } else if SEARCHABLE.contains(&key) && (value.contains(',') || value.contains("..")) {
+                space = space.with(key, ParamRange::parse(key, value)?)?;
+            } else {
+                options.set(key, value)?;
+            }
+        }
+
+        let (train_sql, validation_sql, label) =
+            (train_sql.clone(), validation_sql.clone(), label.clone());
+        Ok(Arc::new(LazyTable::new(
+            "xgboost_search",
+            trials_schema(),
+            Arc::new(move |ctx| {
+                let (train_sql, validation_sql, label) =
+                    (train_sql.clone(), validation_sql.clone(), label.clone());
+                let (space, options) = (space.clone(), options.clone());
+                Box::pin(async move {
+                    search(
+                        &ctx,
+                        &train_sql,
+                        &validation_sql,
+                        &label,
+                        &space,
+                        strategy,
+                        &options,
+                    )
+                    .await
+                })
+            }),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_search_space() -> Result<()> {
+        assert_eq!(
+            ParamRange::parse("max_depth", "3, 4,6")?,
+            ParamRange::Values(vec!["3".into(), "4".into(), "6".into()])
+        );
+        assert_eq!(
+            ParamRange::parse("max_depth", "2..4")?,
+            ParamRange::IntRange(2, 4)
+        );
+        assert_eq!(
+            ParamRange::parse("eta", "0.05..0.3")?,
+            ParamRange::Uniform(0.05, 0.3)
+        );
+        assert_eq!(
+            ParamRange::parse("subsample", "0..1")?,
+            ParamRange::Uniform(0.0, 1.0)
+        );
+        assert!(ParamRange::parse("eta", "0.3..0.05").is_err());
+        assert!(ParamRange::parse("gamma", "0.5..2").is_err());
+        assert!(SearchSpace::new()
+            .with("gamma", ParamRange::Uniform(0.5, 2.0))
+            .is_err());
+        assert!(SearchSpace::new()
+            .with("lambda", ParamRange::parse("lambda", "0.5,1")?)
+            .is_err());
+        assert!(SearchSpace::new()
+            .with("max_depth", ParamRange::parse("max_depth", "-1..2")?)
+            .is_err());
+
+        let space = SearchSpace::new()
+            .with("max_depth", ParamRange::parse("max_depth", "2..3")?)?
+            .with("eta", ParamRange::parse("eta", "0.1,0.3")?)?;
+        let grid = space.grid()?;
+        assert_eq!(grid.len(), 4);
+        assert_eq!(
+            grid[1],
+            vec![
+                ("max_depth".into(), "2".into()),
+                ("eta".into(), "0.3".into())
+            ]
+        );
+        assert!(SearchSpace::new()
+            .with("rounds", ParamRange::parse("rounds", "1,2")?)
+            .is_err());
+
+        let space = space.with("subsample", ParamRange::parse("subsample", "0.5..1.0")?)?;
+        assert!(space.grid().is_err());
+        let trials = space.sample(5, 42);
+        assert_eq!(trials, space.sample(5, 42));
+        assert_ne!(trials, space.sample(5, 43));
+        for trial in &trials {
+            let subsample: f64 = trial[2].1.parse().unwrap();
+            assert!((0.5..1.0).contains(&subsample));
+        }
+        Ok(())
+    }
+}
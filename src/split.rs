@@ -0,0 +1,192 @@
+use datafusion::arrow::array::{
+    Array, ArrayRef, Float64Array, StringArray, UInt32Array, UInt64Array,
+};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::DataType;
+use datafusion::dataframe::DataFrame;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_expr::{ReturnTypeFunction, ScalarUDF, Signature, Volatility};
+use datafusion::physical_plan::functions::make_scalar_function;
+use datafusion::prelude::{col, lit};
+use std::sync::Arc;
+
+/// Bucket of a row with key bytes `key`, or `None` if the fractions add up
+/// to less than one and the row falls after the last bucket.
+///
+/// The key is hashed together with `seed` into a number in `[0, 1)` that is
+/// compared with the running sum of `fractions`. The hash does not depend on
+/// the platform or on the batch a row is in, so the same key always lands in
+/// the same bucket.
+pub fn split_bucket(key: &[u8], seed: u64, fractions: &[f64]) -> Option<usize> {
+    let position = unit_hash(key, seed);
+    // fractions like 0.8, 0.1, 0.1 add up to slightly less than 1.0
+    let complete = fractions.iter().sum::<f64>() >= 1.0 - 1e-9;
+    let mut upper = 0.0;
+    for (bucket, fraction) in fractions.iter().enumerate() {
+        upper += fraction;
+        if position < upper || (complete && bucket + 1 == fractions.len()) {
+            return Some(bucket);
+        }
+    }
+    None
+}
+
+/// FNV-1a over the seed and key, finished with the splitmix64 mixer so that
+/// similar keys spread over the whole range.
+fn unit_hash(key: &[u8], seed: u64) -> f64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in seed.to_le_bytes().iter().chain(key) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^= hash >> 31;
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Checks that `fractions` are positive and add up to at most one.
+pub fn validate_fractions(fractions: &[f64]) -> Result<()> {
+    if fractions.is_empty() {
+        return Err(DataFusionError::Plan(
+            "Expected at least one split fraction".to_string(),
+        ));
+    }
+    if fractions.iter().any(|f| f.is_nan() || *f <= 0.0) {
+        return Err(DataFusionError::Plan(format!(
+            "Split fractions must be positive, got {:?}",
+            fractions
+        )));
+    }
+    if fractions.iter().sum::<f64>() > 1.0 + 1e-9 {
+        return Err(DataFusionError::Plan(format!(
+            "Split fractions must add up to at most 1, got {:?}",
+            fractions
+        )));
+    }
+    Ok(())
+}
+
+/// Buckets of every row of `keys`, null for null keys. Keys of any type are
+/// hashed through their string representation.
+pub fn split_buckets(keys: &ArrayRef, seed: u64, fractions: &[f64]) -> Result<UInt32Array> {
+    validate_fractions(fractions)?;
+    let keys = cast(keys, &DataType::Utf8)?;
+    let keys = keys
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| DataFusionError::Internal("Expected StringArray".to_string()))?;
+    Ok(keys
+        .iter()
+        .map(|key| {
+            key.and_then(|key| split_bucket(key.as_bytes(), seed, fractions))
+                .map(|bucket| bucket as u32)
+        })
+        .collect())
+}
+
+fn split_bucket_args(args: &[ArrayRef]) -> Result<ArrayRef> {
+    let (keys, seed, fractions) = match args {
+        [keys, seed, fractions @ ..] if !fractions.is_empty() => (keys, seed, fractions),
+        _ => {
+            return Err(DataFusionError::Execution(
+                "split_bucket expects a key, a seed and at least one fraction".to_string(),
+            ))
+        }
+    };
+    if keys.is_empty() {
+        return Ok(Arc::new(UInt32Array::from(Vec::<u32>::new())));
+    }
+    let seed = cast(seed, &DataType::UInt64)?;
+    let seed = seed
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .filter(|seed| seed.is_valid(0))
+        .ok_or_else(|| {
+            DataFusionError::Execution("split_bucket seed must be a non-negative integer".into())
+        })?
+        .value(0);
+    let fractions = fractions
+        .iter()
+        .map(|fraction| {
+            let fraction = cast(fraction, &DataType::Float64)?;
+            fraction
+                .as_any()
+                .downcast_ref::<Float64Array>()
+                .filter(|fraction| fraction.is_valid(0))
+                .map(|fraction| fraction.value(0))
+                .ok_or_else(|| {
+                    DataFusionError::Execution("split_bucket fractions must be numbers".into())
+                })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Arc::new(split_buckets(keys, seed, &fractions)?))
+}
+
+/// `split_bucket(key, seed, f1, ..., fn)` returns the bucket `0..n` a row
+/// belongs to, e.g. `split_bucket(id, 42, 0.8, 0.1, 0.1)` puts 80% of the
+/// rows into bucket 0 (train), 10% into 1 (validation) and 10% into 2 (test).
+/// See [`split_bucket`].
+pub fn split_bucket_udf() -> ScalarUDF {
+    let split = make_scalar_function(split_bucket_args);
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::UInt32)));
+    ScalarUDF::new(
+        "split_bucket",
+        &Signature::variadic_any(Volatility::Immutable),
+        &return_type,
+        &split,
+    )
+}
+
+/// Splits `df` into one DataFrame per fraction, assigning rows by hashing
+/// the `key` column with [`split_bucket`]. Running the same split on the same
+/// table gives the same, non-overlapping subsets.
+pub fn split_dataframe(
+    df: &DataFrame,
+    key: &str,
+    seed: u64,
+    fractions: &[f64],
+) -> Result<Vec<DataFrame>> {
+    validate_fractions(fractions)?;
+    let mut args = vec![col(key), lit(seed)];
+    args.extend(fractions.iter().map(|fraction| lit(*fraction)));
+    let bucket = split_bucket_udf().call(args);
+    (0..fractions.len())
+        .map(|i| df.clone().filter(bucket.clone().eq(lit(i as u32))))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use datafusion::arrow::array::Int64Array;
+
+    #[tokio::test]
+    pub async fn test_split_buckets() -> Result<()> {
+        let keys: ArrayRef = Arc::new(Int64Array::from_iter_values(0..10_000));
+        let buckets = split_buckets(&keys, 7, &[0.8, 0.1, 0.1])?;
+        let mut counts = [0; 3];
+        for bucket in buckets.iter() {
+            counts[bucket.unwrap() as usize] += 1;
+        }
+        assert!((7800..8200).contains(&counts[0]), "{:?}", counts);
+        assert!((900..1100).contains(&counts[1]), "{:?}", counts);
+
+        assert_eq!(split_buckets(&keys, 7, &[0.8, 0.1, 0.1])?, buckets);
+        assert_ne!(split_buckets(&keys, 8, &[0.8, 0.1, 0.1])?, buckets);
+
+        let partial = split_buckets(&keys, 7, &[0.5])?;
+        assert!(partial.null_count() > 4000 && partial.null_count() < 6000);
+        assert!(split_buckets(&keys, 7, &[0.8, 0.3]).is_err());
+        assert!(split_buckets(&keys, 7, &[0.0, 0.3]).is_err());
+
+        let empty = |array: ArrayRef| array.slice(0, 0);
+        let seed: ArrayRef = Arc::new(Int64Array::from(vec![7]));
+        let fraction: ArrayRef = Arc::new(Float64Array::from(vec![0.5]));
+        let buckets = split_bucket_args(&[empty(keys), empty(seed), empty(fraction)])?;
+        assert!(buckets.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+use crate::registry::ModelRegistry;
+use crate::train::{train, TrainOptions};
+use crate::{register_udfs, xgb_error, MlPredictFunction};
+use datafusion::arrow::array::{ArrayRef, StringArray};
+use datafusion::arrow::datatypes::{DataType, Field, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::dataframe::DataFrame;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::prelude::SessionContext;
+use datafusion::sql::sqlparser::ast::{Expr, Value};
+use datafusion::sql::sqlparser::dialect::GenericDialect;
+use datafusion::sql::sqlparser::keywords::Keyword;
+use datafusion::sql::sqlparser::parser::{Parser, ParserError};
+use datafusion::sql::sqlparser::tokenizer::Token;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Model statements understood on top of DataFusion's SQL.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModelStatement {
+    /// `CREATE [OR REPLACE] MODEL name [OPTIONS (key = value, ...)] AS query`
+    ///
+    /// The `label` option names the label column of the query and defaults
+    /// to `label`; the other options are passed to [`TrainOptions::set`].
+    CreateModel {
+        name: String,
+        or_replace: bool,
+        options: Vec<(String, String)>,
+        query: String,
+    },
+    /// `DROP MODEL [IF EXISTS] name`
+    DropModel { name: String, if_exists: bool },
+    /// `SHOW MODELS`
+    ShowModels,
+}
+
+impl ModelStatement {
+    /// Parses `sql` as a model statement, returning `None` for any other SQL.
+    pub fn parse(sql: &str) -> Result<Option<Self>> {
+        let dialect = GenericDialect {};
+        let mut parser = Parser::new(&dialect)
+            .try_with_sql(sql)
+            .map_err(parser_error)?;
+
+        let statement = if parser.parse_keyword(Keyword::CREATE) {
+            let or_replace = parser.parse_keywords(&[Keyword::OR, Keyword::REPLACE]);
+            if !parse_word(&mut parser, "MODEL") {
+                return Ok(None);
+            }
+            let name = parser.parse_identifier().map_err(parser_error)?.value;
+            let options = if parser.parse_keyword(Keyword::OPTIONS) {
+                parser.expect_token(&Token::LParen).map_err(parser_error)?;
+                let options = parser
+                    .parse_comma_separated(parse_model_option)
+                    .map_err(parser_error)?;
+                parser.expect_token(&Token::RParen).map_err(parser_error)?;
+                options
+            } else {
+                vec![]
+            };
+            parser.expect_keyword(Keyword::AS).map_err(parser_error)?;
+            let query = parser.parse_query().map_err(parser_error)?.to_string();
+            ModelStatement::CreateModel {
+                name,
+                or_replace,
+                options,
+                query,
+            }
+        } else if parser.parse_keyword(Keyword::DROP) {
+            if !parse_word(&mut parser, "MODEL") {
+                return Ok(None);
+            }
+            let if_exists = parser.parse_keywords(&[Keyword::IF, Keyword::EXISTS]);
+            let name = parser.parse_identifier().map_err(parser_error)?.value;
+            ModelStatement::DropModel { name, if_exists }
+        } else if parser.parse_keyword(Keyword::SHOW) {
+            if !parse_word(&mut parser, "MODELS") {
+                return Ok(None);
+            }
+            ModelStatement::ShowModels
+        } else {
+            return Ok(None);
+        };
+
+        let _ = parser.consume_token(&Token::SemiColon);
+        let token = parser.next_token();
+        if token.token != Token::EOF {
+            return Err(parser_error(ParserError::ParserError(format!(
+                "Expected end of statement, found {}",
+                token
+            ))));
+        }
+        Ok(Some(statement))
+    }
+}
+
+/// Consumes the next token if it is the (non-keyword) word `expected`.
+fn parse_word(parser: &mut Parser, expected: &str) -> bool {
+    match parser.peek_token().token {
+        Token::Word(word) if word.value.eq_ignore_ascii_case(expected) => {
+            parser.next_token();
+            true
+        }
+        _ => false,
+    }
+}
+
+fn parse_model_option(parser: &mut Parser) -> Result<(String, String), ParserError> {
+    let key = parser.parse_identifier()?.value;
+    parser.expect_token(&Token::Eq)?;
+    let value = match parser.parse_expr()? {
+        Expr::Value(Value::SingleQuotedString(value)) => value,
+        Expr::Value(Value::Number(value, _)) => value,
+        Expr::Value(Value::Boolean(value)) => value.to_string(),
+        Expr::Identifier(ident) => ident.value,
+        Expr::UnaryOp { op, expr } => format!("{}{}", op, expr),
+        expr => {
+            return Err(ParserError::ParserError(format!(
+                "Expected a literal value for option {}, found {}",
+                key, expr
+            )))
+        }
+    };
+    Ok((key.to_lowercase(), value))
+}
+
+fn parser_error(e: ParserError) -> DataFusionError {
+    DataFusionError::SQL(e)
+}
+
+/// A `SessionContext` with the UDFs of this crate and a [`ModelRegistry`],
+/// running model statements in addition to regular SQL.
+///
+/// Models created with `CREATE MODEL` are scored with
+/// `predict_model('<name>', f1, ..., fn)`, or with
+/// `ml_predict('<name>', 'table')` on raw columns, see [`MlPredictFunction`].
+pub struct ModelSession {
+    ctx: SessionContext,
+    registry: Arc<ModelRegistry>,
+}
+
+impl ModelSession {
+    /// Registers the UDFs on `ctx` and keeps models in `model_dir`.
+    pub fn new(ctx: SessionContext, model_dir: impl AsRef<Path>) -> Result<Self> {
+        let registry = Arc::new(ModelRegistry::new(model_dir)?);
+        register_udfs(&ctx);
+        ctx.register_udf(registry.predict_udf());
+        ctx.register_udtf(
+            "ml_predict",
+            Arc::new(MlPredictFunction::with_registry(&ctx, registry.clone())),
+        );
+        Ok(ModelSession { ctx, registry })
+    }
+
+    pub fn context(&self) -> &SessionContext {
+        &self.ctx
+    }
+
+    pub fn registry(&self) -> &Arc<ModelRegistry> {
+        &self.registry
+    }
+
+    /// Runs a model statement or passes `sql` on to DataFusion.
+    pub async fn sql(&self, sql: &str) -> Result<DataFrame> {
+        match ModelStatement::parse(sql)? {
+            Some(statement) => self.execute(statement).await,
+            None => self.ctx.sql(sql).await,
+        }
+    }
+
+    /// Runs a parsed model statement.
+    pub async fn execute(&self, statement: ModelStatement) -> Result<DataFrame> {
+        match statement {
+            ModelStatement::CreateModel {
+                name,
+                or_replace,
+                options,
+                query,
+            } => {
+                let model_path = self.registry.path(&name)?;
+                if model_path.exists() && !or_replace {
+                    return Err(DataFusionError::Plan(format!(
+                        "Model {} already exists",
+                        name
+                    )));
+                }
+
+                let mut label = "label".to_string();
+                let mut train_options = TrainOptions::default();
+                for (key, value) in options {
+                    match key.as_str() {
+                        "label" => label = value,
+                        "model_path" => {
+                            return Err(DataFusionError::Plan(
+                                "model_path is set by the model registry".to_string(),
+                            ))
+                        }
+                        _ => train_options.set(&key, &value)?,
+                    }
+                }
+                train_options.model_path = model_path;
+
+                let summary = train(&self.ctx, &query, &label, &train_options).await?;
+                self.ctx.read_batch(summary)
+            }
+            ModelStatement::DropModel { name, if_exists } => {
+                if !if_exists || self.registry.contains(&name)? {
+                    self.registry.remove(&name)?;
+                }
+                self.ctx.read_empty()
+            }
+            ModelStatement::ShowModels => self.ctx.read_batch(self.show_models()?),
+        }
+    }
+
+    fn show_models(&self) -> Result<RecordBatch> {
+        let names = self.registry.names()?;
+        let mut paths = Vec::with_capacity(names.len());
+        let mut objectives = Vec::with_capacity(names.len());
+        let mut labels = Vec::with_capacity(names.len());
+        for name in &names {
+            let booster = self.registry.load(name)?;
+            paths.push(self.registry.path(name)?.display().to_string());
+            objectives.push(booster.get_attribute("objective").map_err(xgb_error)?);
+            labels.push(booster.get_attribute("label").map_err(xgb_error)?);
+        }
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("name", DataType::Utf8, false),
+            Field::new("path", DataType::Utf8, false),
+            Field::new("objective", DataType::Utf8, true),
+            Field::new("label", DataType::Utf8, true),
+        ]));
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(names)),
+            Arc::new(StringArray::from(paths)),
+            Arc::new(StringArray::from(objectives)),
+            Arc::new(StringArray::from(labels)),
+        ];
+        Ok(RecordBatch::try_new(schema, columns)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_parse_model_statements() -> Result<()> {
+        let statement = ModelStatement::parse(
+            "CREATE MODEL m OPTIONS (objective='binary:logistic', max_depth=6, label=class) \
+             AS SELECT a, class FROM t",
+        )?;
+        assert_eq!(
+            statement,
+            Some(ModelStatement::CreateModel {
+                name: "m".to_string(),
+                or_replace: false,
+                options: vec![
+                    ("objective".to_string(), "binary:logistic".to_string()),
+                    ("max_depth".to_string(), "6".to_string()),
+                    ("label".to_string(), "class".to_string()),
+                ],
+                query: "SELECT a, class FROM t".to_string(),
+            })
+        );
+
+        assert_eq!(
+            ModelStatement::parse("drop model if exists m;")?,
+            Some(ModelStatement::DropModel {
+                name: "m".to_string(),
+                if_exists: true
+            })
+        );
+        assert_eq!(
+            ModelStatement::parse("SHOW MODELS")?,
+            Some(ModelStatement::ShowModels)
+        );
+        assert_eq!(ModelStatement::parse("SELECT 1")?, None);
+        assert_eq!(ModelStatement::parse("CREATE TABLE t AS SELECT 1")?, None);
+        assert_eq!(ModelStatement::parse("SHOW TABLES")?, None);
+        assert!(ModelStatement::parse("CREATE MODEL m AS SELECT 1 garbage garbage").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use async_trait::async_trait;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::common::ScalarValue;
+use datafusion::datasource::{TableProvider, TableType};
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::{SessionState, TaskContext};
+use datafusion::logical_expr::Expr;
+use datafusion::physical_expr::PhysicalSortExpr;
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayAs, DisplayFormatType, ExecutionPlan, Partitioning, SendableRecordBatchStream,
+};
+use datafusion::prelude::SessionContext;
+use futures::future::BoxFuture;
+use std::any::Any;
+use std::fmt;
+use std::sync::Arc;
+
+/// Async job producing the single batch of a [`LazyTable`]. It gets a
+/// `SessionContext` over the state the query is planned in, so it can run
+/// SQL against the tables and UDFs registered there.
+pub(crate) type Job =
+    Arc<dyn Fn(SessionContext) -> BoxFuture<'static, Result<RecordBatch>> + Send + Sync>;
+
+/// Table returned by the query-driven table functions (`train_xgboost`, ...).
+///
+/// Table functions are resolved while the SQL is planned, which is
+/// synchronous, so the actual work is deferred until the plan is executed.
+/// This also keeps `EXPLAIN` from training models.
+pub(crate) struct LazyTable {
+    name: &'static str,
+    schema: SchemaRef,
+    job: Job,
+}
+
+impl LazyTable {
+    pub(crate) fn new(name: &'static str, schema: SchemaRef, job: Job) -> Self {
+        LazyTable { name, schema, job }
+    }
+}
+
+#[async_trait]
+impl TableProvider for LazyTable {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Temporary
+    }
+
+    async fn scan(
+        &self,
+        state: &SessionState,
+        projection: Option<&Vec<usize>>,
+        _filters: &[Expr],
+        _limit: Option<usize>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        let schema = match projection {
+            Some(projection) => Arc::new(self.schema.project(projection)?),
+            None => self.schema.clone(),
+        };
+        Ok(Arc::new(LazyExec {
+            name: self.name,
+            schema,
+            projection: projection.cloned(),
+            state: state.clone(),
+            job: self.job.clone(),
+        }))
+    }
+}
+
+struct LazyExec {
+    name: &'static str,
+    schema: SchemaRef,
+    projection: Option<Vec<usize>>,
+    state: SessionState,
+    job: Job,
+}
+
+impl fmt::Debug for LazyExec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LazyExec: {}", self.name)
+    }
+}
+
+impl DisplayAs for LazyExec {
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LazyExec: {}", self.name)
+    }
+}
+
+impl ExecutionPlan for LazyExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        Partitioning::UnknownPartitioning(1)
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        _children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Ok(self)
+    }
+
+    fn execute(
+        &self,
+        _partition: usize,
+        _context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let job = (self.job)(SessionContext::new_with_state(self.state.clone()));
+        let projection = self.projection.clone();
+        let batch = async move {
+            let batch = job.await?;
+            match projection {
+                Some(projection) => Ok(batch.project(&projection)?),
+                None => Ok(batch),
+            }
+        };
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema.clone(),
+            futures::stream::once(batch),
+        )))
+    }
+}
+
+/// Reads a string literal argument of a table function.
+pub(crate) fn literal_string(function: &str, expr: &Expr) -> Result<String> {
+    match expr {
+        Expr::Literal(ScalarValue::Utf8(Some(value))) => Ok(value.clone()),
+        _ => Err(DataFusionError::Plan(format!(
+            "{} expects string literal arguments, got {}",
+            function, expr
+        ))),
+    }
+}
+
+/// Reads a column name argument given as an identifier or string literal.
+pub(crate) fn column_name(function: &str, expr: &Expr) -> Result<String> {
+    match expr {
+        Expr::Column(column) => Ok(column.name.clone()),
+        _ => literal_string(function, expr),
+    }
+}
+
+/// Splits a `key=value` option argument.
+pub(crate) fn parse_option(option: &str) -> Result<(&str, &str)> {
+    option
+        .split_once('=')
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .ok_or_else(|| DataFusionError::Plan(format!("Expected key=value option, got {}", option)))
+}
@@ -0,0 +1,1182 @@
+use crate::custom::{CustomMetric, CustomObjective};
+use crate::iteration::num_trees;
+use crate::label::is_string_label;
+use crate::table_function::{literal_string, parse_option, LazyTable};
+use crate::training_log::{LogEntry, TrainingLog};
+use crate::{xgb_error, CategoryMapping, DMatrixBuilder, FeatureLayout, LabelMapping};
+use datafusion::arrow::array::{
+    Array, ArrayRef, Float32Array, Int64Array, StringArray, UInt32Array, UInt64Array,
+};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datasource::function::TableFunctionImpl;
+use datafusion::datasource::TableProvider;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_expr::Expr;
+use datafusion::prelude::{count, ident, lit, SessionContext};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use xgboost::parameters::learning::{
+    EvaluationMetric, LearningTaskParametersBuilder, Metrics, Objective,
+};
+use xgboost::parameters::tree::TreeBoosterParametersBuilder;
+use xgboost::parameters::{BoosterParameters, BoosterParametersBuilder, BoosterType};
+use xgboost::{Booster, DMatrix};
+
+/// Hyperparameters and output location for training a model from SQL.
+#[derive(Clone)]
+pub struct TrainOptions {
+    pub objective: Objective,
+    pub rounds: u32,
+    pub max_depth: u32,
+    pub eta: f32,
+    pub gamma: u32,
+    pub min_child_weight: u32,
+    pub subsample: f32,
+    pub colsample_bytree: f32,
+    pub lambda: u32,
+    pub alpha: u32,
+    pub seed: u64,
+    /// Classes of a string label in label order. Defaults to the sorted
+    /// distinct values of the label column.
+    pub classes: Option<Vec<String>>,
+    /// Metrics evaluated after every round, e.g. `auc`. Defaults to the
+    /// metric XGBoost picks for the objective.
+    pub eval_metrics: Vec<String>,
+    /// Queries with the columns of the training query whose rows are
+    /// evaluated after every round, see [`train`].
+    pub validation: Vec<String>,
+    /// Stops training when the last metric on the last validation set has
+    /// not improved for this many rounds.
+    pub early_stopping_rounds: Option<u32>,
+    /// Model to continue boosting from instead of starting from scratch, see
+    /// [`continue_training`].
+    pub base_model: Option<PathBuf>,
+    /// Column with the weight of every training row.
+    pub weight: Option<String>,
+    /// Weight of every row of a class, by the label value cast to a string.
+    pub class_weights: Option<Vec<(String, f32)>>,
+    /// Weighs the classes inversely to their frequency, see
+    /// [`balanced_class_weights`].
+    pub balance_classes: bool,
+    /// XGBoost's weight of positive rows for binary objectives.
+    pub scale_pos_weight: f32,
+    /// Variance power of `reg:tweedie` in `(1, 2)`, XGBoost's default 1.5
+    /// if not set.
+    pub tweedie_variance_power: Option<f32>,
+    /// Column with the query group of every row for ranking objectives,
+    /// which is then not a feature.
+    pub group: Option<String>,
+    /// Loss boosted instead of the loss of `objective`, which still decides
+    /// the output transform and default metric.
+    pub custom_objective: Option<CustomObjective>,
+    /// Metrics evaluated after every round after the `eval_metrics`.
+    pub custom_metrics: Vec<CustomMetric>,
+    /// Where the model is saved, `trained.xgb` by default so training does
+    /// not replace the `model.xgb` served by [`register_udfs`](crate::register_udfs).
+    pub model_path: PathBuf,
+}
+
+impl Default for TrainOptions {
+    fn default() -> Self {
+        TrainOptions {
+            objective: Objective::BinaryLogistic,
+            rounds: 10,
+            max_depth: 6,
+            eta: 0.3,
+            gamma: 0,
+            min_child_weight: 1,
+            subsample: 1.0,
+            colsample_bytree: 1.0,
+            lambda: 1,
+            alpha: 0,
+            seed: 0,
+            classes: None,
+            eval_metrics: vec![],
+            validation: vec![],
+            early_stopping_rounds: None,
+            base_model: None,
+            weight: None,
+            class_weights: None,
+            balance_classes: false,
+            scale_pos_weight: 1.0,
+            tweedie_variance_power: None,
+            group: None,
+            custom_objective: None,
+            custom_metrics: vec![],
+            model_path: PathBuf::from("trained.xgb"),
+        }
+    }
+}
+
+impl TrainOptions {
+    /// Sets an option by its XGBoost name, e.g. `set("max_depth", "4")`.
+    ///
+    /// `eval_metric` takes a comma-separated list and `validation` a query;
+    /// both add to the values set before. `class_weights` takes
+    /// `class:weight` pairs like `fraud:50,ok:1`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "objective" => self.objective = parse_objective(value)?,
+            "rounds" | "num_boost_round" => self.rounds = parse_value(key, value)?,
+            "max_depth" => self.max_depth = parse_value(key, value)?,
+            "eta" | "learning_rate" => self.eta = parse_value(key, value)?,
+            "gamma" => self.gamma = parse_value(key, value)?,
+            "min_child_weight" => self.min_child_weight = parse_value(key, value)?,
+            "subsample" => self.subsample = parse_value(key, value)?,
+            "colsample_bytree" => self.colsample_bytree = parse_value(key, value)?,
+            "lambda" => self.lambda = parse_value(key, value)?,
+            "alpha" => self.alpha = parse_value(key, value)?,
+            "seed" => self.seed = parse_value(key, value)?,
+            "classes" => {
+                self.classes = Some(value.split(',').map(|c| c.trim().to_string()).collect())
+            }
+            "eval_metric" => {
+                for metric in value.split(',').map(str::trim) {
+                    parse_metric(metric)?;
+                    self.eval_metrics.push(metric.to_string());
+                }
+            }
+            "validation" => self.validation.push(value.to_string()),
+            "early_stopping_rounds" => self.early_stopping_rounds = Some(parse_value(key, value)?),
+            "base_model" => self.base_model = Some(PathBuf::from(value)),
+            "weight" => self.weight = Some(value.to_string()),
+            "class_weights" => {
+                self.class_weights = Some(
+                    value
+                        .split(',')
+                        .map(|pair| {
+                            let (class, weight) = pair.rsplit_once(':').ok_or_else(|| {
+                                DataFusionError::Plan(format!(
+                                    "Expected class:weight pairs for {}, got {}",
+                                    key, value
+                                ))
+                            })?;
+                            Ok((class.trim().to_string(), parse_value(key, weight.trim())?))
+                        })
+                        .collect::<Result<_>>()?,
+                )
+            }
+            "balance_classes" => self.balance_classes = parse_value(key, value)?,
+            "scale_pos_weight" => self.scale_pos_weight = parse_value(key, value)?,
+            "tweedie_variance_power" => {
+                let power: f32 = parse_value(key, value)?;
+                if !(power > 1.0 && power < 2.0) {
+                    return Err(DataFusionError::Plan(format!(
+                        "tweedie_variance_power must be between 1 and 2, got {}",
+                        value
+                    )));
+                }
+                self.tweedie_variance_power = Some(power)
+            }
+            "group" => self.group = Some(value.to_string()),
+            "label_lower_bound"
+            | "label_upper_bound"
+            | "aft_loss_distribution"
+            | "aft_loss_distribution_scale" => return Err(aft_unsupported()),
+            "model_path" => self.model_path = PathBuf::from(value),
+            _ => {
+                return Err(DataFusionError::Plan(format!(
+                    "Unknown training option {}",
+                    key
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds options from `key=value` strings on top of the defaults.
+    pub fn from_options<S: AsRef<str>>(options: &[S]) -> Result<Self> {
+        let mut result = TrainOptions::default();
+        for option in options {
+            let (key, value) = parse_option(option.as_ref())?;
+            result.set(key, value)?;
+        }
+        Ok(result)
+    }
+
+    /// The entry of `dataset` after `round` that decides early stopping: the
+    /// last custom metric, the last `eval_metric`, or the default metric of
+    /// the objective.
+    pub(crate) fn stopping_entry<'a>(
+        &self,
+        log: &'a TrainingLog,
+        round: u32,
+        dataset: &str,
+    ) -> Result<&'a LogEntry> {
+        let metric = match self.custom_metrics.last() {
+            Some(metric) => Some(metric.name()),
+            None => self.eval_metrics.last().map(String::as_str),
+        };
+        log.entries()
+            .iter()
+            .filter(|e| e.round == round && e.dataset == dataset)
+            .find(|e| metric.is_none_or(|m| e.metric == m))
+            .ok_or_else(|| {
+                DataFusionError::Execution(format!("{} has no metric to stop on", dataset))
+            })
+    }
+
+    /// Whether higher values of `metric`, a custom or XGBoost metric, are
+    /// better.
+    pub(crate) fn maximize(&self, metric: &str) -> bool {
+        match self.custom_metrics.iter().find(|m| m.name() == metric) {
+            Some(custom) => custom.maximize(),
+            None => maximize_metric(metric),
+        }
+    }
+
+    /// XGBoost's metrics followed by the custom metrics of `booster` on
+    /// `dmat`.
+    pub(crate) fn evaluate(&self, booster: &Booster, dmat: &DMatrix) -> Result<Vec<(String, f32)>> {
+        let mut metrics: Vec<(String, f32)> = booster
+            .evaluate(dmat)
+            .map_err(xgb_error)?
+            .into_iter()
+            .collect();
+        for metric in &self.custom_metrics {
+            metrics.push((metric.name().to_string(), metric.evaluate(booster, dmat)?));
+        }
+        Ok(metrics)
+    }
+
+    /// Boosts one round on `dtrain` with the custom or XGBoost objective.
+    fn update(&self, booster: &mut Booster, dtrain: &DMatrix, round: u32) -> Result<()> {
+        match &self.custom_objective {
+            Some(objective) => objective.update(booster, dtrain),
+            None => booster.update(dtrain, round as i32).map_err(xgb_error),
+        }
+    }
+
+    pub fn booster_params(&self) -> Result<BoosterParameters> {
+        let objective = match self.objective {
+            Objective::RegTweedie(None) => Objective::RegTweedie(self.tweedie_variance_power),
+            objective => objective,
+        };
+        let learning_params = LearningTaskParametersBuilder::default()
+            .objective(objective)
+            .seed(self.seed)
+            .eval_metrics(if self.eval_metrics.is_empty() {
+                match self.objective {
+                    // the xgboost crate cannot read the name of the default
+                    // tweedie-nloglik@rho metric
+                    Objective::RegTweedie(_) => Metrics::Custom(vec![EvaluationMetric::RMSE]),
+                    _ => Metrics::Auto,
+                }
+            } else {
+                Metrics::Custom(
+                    self.eval_metrics
+                        .iter()
+                        .map(|metric| parse_metric(metric))
+                        .collect::<Result<_>>()?,
+                )
+            })
+            .build()
+            .map_err(DataFusionError::Plan)?;
+        let tree_params = TreeBoosterParametersBuilder::default()
+            .max_depth(self.max_depth)
+            .eta(self.eta)
+            .gamma(self.gamma)
+            .min_child_weight(self.min_child_weight)
+            .subsample(self.subsample)
+            .colsample_bytree(self.colsample_bytree)
+            .scale_pos_weight(self.scale_pos_weight)
+            .lambda(self.lambda)
+            .alpha(self.alpha)
+            .build()
+            .map_err(DataFusionError::Plan)?;
+        BoosterParametersBuilder::default()
+            .booster_type(BoosterType::Tree(tree_params))
+            .learning_params(learning_params)
+            .verbose(false)
+            .build()
+            .map_err(DataFusionError::Plan)
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| DataFusionError::Plan(format!("Invalid value {} for {}", value, key)))
+}
+
+/// AFT survival training is not supported: the bundled XGBoost predates
+/// `survival:aft` and cannot hold the lower and upper label bounds it needs.
+fn aft_unsupported() -> DataFusionError {
+    DataFusionError::Plan(
+        "survival:aft and label bound columns need XGBoost 1.2, \
+         the bundled XGBoost only supports survival:cox"
+            .to_string(),
+    )
+}
+
+/// Parses an XGBoost objective name such as `binary:logistic`.
+pub fn parse_objective(name: &str) -> Result<Objective> {
+    let objective = match name {
+        "reg:linear" | "reg:squarederror" => Objective::RegLinear,
+        "reg:logistic" => Objective::RegLogistic,
+        "binary:logistic" => Objective::BinaryLogistic,
+        "binary:logitraw" => Objective::BinaryLogisticRaw,
+        "count:poisson" => Objective::CountPoisson,
+        "survival:cox" => Objective::SurvivalCox,
+        "rank:pairwise" => Objective::RankPairwise,
+        "reg:gamma" => Objective::RegGamma,
+        "reg:tweedie" => Objective::RegTweedie(None),
+        "survival:aft" => return Err(aft_unsupported()),
+        // the bundled XGBoost has them, but the xgboost crate can only set
+        // the objectives of its `Objective` enum
+        "rank:ndcg" | "rank:map" => {
+            return Err(DataFusionError::Plan(format!(
+                "{} is not available through the xgboost crate, use rank:pairwise",
+                name
+            )))
+        }
+        _ => {
+            return Err(DataFusionError::Plan(format!(
+                "Unsupported objective {}",
+                name
+            )))
+        }
+    };
+    Ok(objective)
+}
+
+/// Whether `objective` predicts classes, so it can be trained on a string
+/// label through a [`LabelMapping`].
+fn is_classification(objective: Objective) -> bool {
+    matches!(
+        objective,
+        Objective::BinaryLogistic
+            | Objective::BinaryLogisticRaw
+            | Objective::GpuBinaryLogistic
+            | Objective::GpuBinaryLogisticRaw
+            | Objective::MultiSoftmax(_)
+            | Objective::MultiSoftprob(_)
+    )
+}
+
+/// Checks that the labels of `dmat` are in the domain of `objective`, e.g.
+/// non-negative counts for `count:poisson`, which XGBoost does not report
+/// clearly.
+fn check_labels(dmat: &DMatrix, objective: Objective) -> Result<()> {
+    let (valid, domain): (fn(f32) -> bool, &str) = match objective {
+        Objective::RegLogistic
+        | Objective::BinaryLogistic
+        | Objective::GpuRegLogistic
+        | Objective::GpuBinaryLogistic => (|y| (0.0..=1.0).contains(&y), "between 0 and 1"),
+        Objective::CountPoisson | Objective::RegTweedie(_) => (|y| y >= 0.0, "non-negative"),
+        Objective::RegGamma => (|y| y > 0.0, "positive"),
+        _ => return Ok(()),
+    };
+    let labels = dmat.get_labels().map_err(xgb_error)?;
+    match labels.iter().find(|label| !valid(**label)) {
+        Some(label) => Err(DataFusionError::Execution(format!(
+            "Labels of {} must be {}, got {}",
+            objective.to_string(),
+            domain,
+            label
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Parses an XGBoost evaluation metric name such as `auc` or `error@0.7`.
+pub fn parse_metric(name: &str) -> Result<EvaluationMetric> {
+    let cutoff = |n: &str| parse_value::<u32>("eval_metric", n);
+    let metric = match name.split_once('@') {
+        None => match name {
+            "rmse" => EvaluationMetric::RMSE,
+            "mae" => EvaluationMetric::MAE,
+            "logloss" => EvaluationMetric::LogLoss,
+            "error" => EvaluationMetric::BinaryErrorRate(0.5),
+            "merror" => EvaluationMetric::MultiClassErrorRate,
+            "mlogloss" => EvaluationMetric::MultiClassLogLoss,
+            "auc" => EvaluationMetric::AUC,
+            "ndcg" => EvaluationMetric::NDCG,
+            "ndcg-" => EvaluationMetric::NDCGNegative,
+            "map" => EvaluationMetric::MAP,
+            "map-" => EvaluationMetric::MAPNegative,
+            "poisson-nloglik" => EvaluationMetric::PoissonLogLoss,
+            "gamma-nloglik" => EvaluationMetric::GammaLogLoss,
+            "cox-nloglik" => EvaluationMetric::CoxLogLoss,
+            "gamma-deviance" => EvaluationMetric::GammaDeviance,
+            "tweedie-nloglik" => {
+                return Err(DataFusionError::Plan(
+                    "tweedie-nloglik is not supported by the xgboost crate, use rmse or mae"
+                        .to_string(),
+                ))
+            }
+            _ => {
+                return Err(DataFusionError::Plan(format!(
+                    "Unsupported metric {}",
+                    name
+                )))
+            }
+        },
+        Some(("error", threshold)) => {
+            EvaluationMetric::BinaryErrorRate(parse_value("eval_metric", threshold)?)
+        }
+        Some(("ndcg", n)) => match n.strip_suffix('-') {
+            Some(n) => EvaluationMetric::NDCGCutNegative(cutoff(n)?),
+            None => EvaluationMetric::NDCGCut(cutoff(n)?),
+        },
+        Some(("map", n)) => match n.strip_suffix('-') {
+            Some(n) => EvaluationMetric::MAPCutNegative(cutoff(n)?),
+            None => EvaluationMetric::MAPCut(cutoff(n)?),
+        },
+        _ => {
+            return Err(DataFusionError::Plan(format!(
+                "Unsupported metric {}",
+                name
+            )))
+        }
+    };
+    Ok(metric)
+}
+
+/// Returns true for metrics where higher values are better.
+pub(crate) fn maximize_metric(name: &str) -> bool {
+    let name = name.split('@').next().unwrap_or(name);
+    matches!(name.trim_end_matches('-'), "auc" | "aucpr" | "ndcg" | "map")
+}
+
+/// Runs `sql`, trains a model predicting `label` from all other columns and
+/// saves it to `options.model_path`. Feature columns are `onehot` lists or
+/// categorical dictionary columns as accepted by `predict`; the label is
+/// numeric, boolean or a string whose classes are saved for `predict_label`.
+///
+/// The `options.validation` queries are run as well and their rows are
+/// evaluated after every round as `validation_0`, `validation_1`, ... With
+/// `options.early_stopping_rounds` training stops once the last metric on the
+/// last validation set has not improved for that many rounds, and the model
+/// is saved as it was after the best round, see [`BEST_ITERATION`]. The metrics of
+/// every round are written next to the model, see [`TrainingLog::path`].
+///
+/// Returns a one-row summary of the trained model.
+pub async fn train(
+    ctx: &SessionContext,
+    sql: &str,
+    label: &str,
+    options: &TrainOptions,
+) -> Result<RecordBatch> {
+    let mut options = options.clone();
+    if options.balance_classes && options.class_weights.is_none() {
+        options.class_weights = Some(balanced_class_weights(ctx, sql, label).await?);
+    }
+    let batches = collect_training_query(ctx, sql, &options).await?;
+    let mut validation = Vec::with_capacity(options.validation.len());
+    for query in &options.validation {
+        validation.push(collect_training_query(ctx, query, &options).await?);
+    }
+    train_with_validation(&batches, &validation, label, &options)
+}
+
+/// Collects the rows of a training or validation query, ordered by the
+/// `options.group` column if there is one so the rows of every query group
+/// are contiguous.
+pub(crate) async fn collect_training_query(
+    ctx: &SessionContext,
+    sql: &str,
+    options: &TrainOptions,
+) -> Result<Vec<RecordBatch>> {
+    let df = ctx.sql(sql).await?;
+    match &options.group {
+        Some(group) => {
+            df.sort(vec![ident(group).sort(true, false)])?
+                .collect()
+                .await
+        }
+        None => df.collect().await,
+    }
+}
+
+/// Weights that make every class of `label` in the result of `sql` weigh
+/// the same in total: `rows / (classes * class rows)`, as scikit-learn's
+/// `balanced` class weights. The rows per class are counted with a
+/// DataFusion aggregate, so the query is not collected for this. Rows with a
+/// null label are not counted.
+pub async fn balanced_class_weights(
+    ctx: &SessionContext,
+    sql: &str,
+    label: &str,
+) -> Result<Vec<(String, f32)>> {
+    let counts = ctx
+        .sql(sql)
+        .await?
+        .filter(ident(label).is_not_null())?
+        .aggregate(vec![ident(label)], vec![count(lit(1)).alias("rows")])?
+        .collect()
+        .await?;
+    let mut classes = Vec::new();
+    for batch in &counts {
+        let labels = cast(batch.column(0), &DataType::Utf8)?;
+        let labels = labels
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .ok_or_else(|| DataFusionError::Internal("Expected StringArray".to_string()))?;
+        let rows = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .ok_or_else(|| DataFusionError::Internal("Expected Int64Array".to_string()))?;
+        classes.extend(
+            labels
+                .iter()
+                .zip(rows.values())
+                .map(|(class, rows)| (class.unwrap_or_default().to_string(), *rows as f64)),
+        );
+    }
+    classes.sort_by(|a, b| a.0.cmp(&b.0));
+    let total: f64 = classes.iter().map(|(_, rows)| rows).sum();
+    let num_classes = classes.len() as f64;
+    Ok(classes
+        .into_iter()
+        .map(|(class, rows)| (class, (total / (num_classes * rows)) as f32))
+        .collect())
+}
+
+/// Trains and saves a model from an already collected batch, see [`train`].
+pub fn train_batch(data: &RecordBatch, label: &str, options: &TrainOptions) -> Result<RecordBatch> {
+    train_batches(std::slice::from_ref(data), label, options)
+}
+
+/// Trains and saves a model from the batches of a query result, see [`train`].
+/// Validation sets are given with [`train_with_validation`].
+pub fn train_batches(
+    batches: &[RecordBatch],
+    label: &str,
+    options: &TrainOptions,
+) -> Result<RecordBatch> {
+    train_with_validation(batches, &[], label, options)
+}
+
+/// Trains and saves a model from the batches of a query result, evaluating
+/// it after every round on the results of the validation queries, see
+/// [`train`]. `options.validation` is ignored.
+pub fn train_with_validation(
+    batches: &[RecordBatch],
+    validation: &[Vec<RecordBatch>],
+    label: &str,
+    options: &TrainOptions,
+) -> Result<RecordBatch> {
+    let base = options
+        .base_model
+        .as_ref()
+        .map(|path| base_model(path, options))
+        .transpose()?;
+    let (dtrain, encoding) = match &base {
+        Some(base) => {
+            let encoding = Encoding::of_model(base)?;
+            (
+                encoding.training_dmatrix(batches, label, options)?,
+                encoding,
+            )
+        }
+        None => training_data(batches, label, options)?,
+    };
+    let validation = validation
+        .iter()
+        .enumerate()
+        .map(|(i, batches)| {
+            Ok((
+                format!("validation_{}", i),
+                encoding.dmatrix(batches, label, options)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let evals: Vec<(&str, &DMatrix)> = validation
+        .iter()
+        .map(|(name, dmat)| (name.as_str(), dmat))
+        .collect();
+    let (mut booster, outcome) = train_booster_with_validation(&dtrain, &evals, base, options)?;
+
+    let best = save_model(&mut booster, &encoding, &outcome, label, options)?;
+
+    let mut metrics = options.evaluate(&booster, &dtrain)?;
+    metrics.sort_by(|a, b| a.0.cmp(&b.0));
+    let (metric, value) = metrics
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| (String::new(), f32::NAN));
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(vec![options
+            .model_path
+            .display()
+            .to_string()])),
+        Arc::new(UInt64Array::from(vec![dtrain.num_rows() as u64])),
+        Arc::new(UInt64Array::from(vec![dtrain.num_cols() as u64])),
+        Arc::new(UInt32Array::from(vec![outcome.rounds])),
+        Arc::new(StringArray::from(vec![options.objective.to_string()])),
+        Arc::new(StringArray::from(vec![metric])),
+        Arc::new(Float32Array::from(vec![value])),
+        Arc::new(UInt32Array::from(vec![best])),
+    ];
+    Ok(RecordBatch::try_new(summary_schema(), columns)?)
+}
+
+/// Saves `booster` to `options.model_path` with the encoding of its data,
+/// its objective, label and best iteration, and the training log next to it.
+/// Returns the saved best iteration.
+pub(crate) fn save_model(
+    booster: &mut Booster,
+    encoding: &Encoding,
+    outcome: &TrainingOutcome,
+    label: &str,
+    options: &TrainOptions,
+) -> Result<Option<u32>> {
+    encoding.save(booster)?;
+    booster
+        .set_attribute("objective", &options.objective.to_string())
+        .map_err(xgb_error)?;
+    booster.set_attribute("label", label).map_err(xgb_error)?;
+    let best = match outcome.best {
+        Some(best) => {
+            booster
+                .set_attribute(BEST_SCORE, &best.value.to_string())
+                .map_err(xgb_error)?;
+            Some(best.round)
+        }
+        // a base model that stopped early is now used with all its trees,
+        // which have no validation score to keep
+        None if booster
+            .get_attribute(BEST_ITERATION)
+            .map_err(xgb_error)?
+            .is_some() =>
+        {
+            booster.set_attribute(BEST_SCORE, "").map_err(xgb_error)?;
+            Some(outcome.rounds.saturating_sub(1))
+        }
+        None => None,
+    };
+    if let Some(best) = best {
+        booster
+            .set_attribute(BEST_ITERATION, &best.to_string())
+            .map_err(xgb_error)?;
+    }
+    if let Some(path) = &options.base_model {
+        booster
+            .set_attribute(BASE_MODEL, &path.display().to_string())
+            .map_err(xgb_error)?;
+    }
+    booster.save(&options.model_path).map_err(xgb_error)?;
+    outcome.log.save(TrainingLog::path(&options.model_path))?;
+    Ok(best)
+}
+
+/// Booster attribute with the round that scored best on the validation data
+/// when training stopped early. The trees of later rounds are not saved, so
+/// it is the last round of the model.
+pub const BEST_ITERATION: &str = "best_iteration";
+
+/// Booster attribute with the validation metric value of [`BEST_ITERATION`],
+/// empty for a model continued from an early-stopped one without early
+/// stopping, whose best iteration is then its last round.
+pub const BEST_SCORE: &str = "best_score";
+
+/// Booster attribute with the path of the model a continued model was
+/// trained from.
+pub const BASE_MODEL: &str = "base_model";
+
+/// Loads the model to continue training from, checking that it is trained
+/// for the same objective and is not overwritten by the new model.
+fn base_model(path: &Path, options: &TrainOptions) -> Result<Booster> {
+    if path == options.model_path {
+        return Err(DataFusionError::Plan(format!(
+            "Continued training would overwrite the base model {}",
+            path.display()
+        )));
+    }
+    let booster = Booster::load(path).map_err(xgb_error)?;
+    let objective = booster.get_attribute("objective").map_err(xgb_error)?;
+    if let Some(objective) = objective {
+        if objective != options.objective.to_string() {
+            return Err(DataFusionError::Plan(format!(
+                "Base model {} was trained for {}, not {}",
+                path.display(),
+                objective,
+                options.objective.to_string()
+            )));
+        }
+    }
+    Ok(booster)
+}
+
+/// Path of the next version of a model: `model.xgb` becomes `model.v2.xgb`
+/// and `model.v2.xgb` becomes `model.v3.xgb`.
+pub fn next_version(model_path: &Path) -> PathBuf {
+    let stem = model_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let (name, version) = match stem.rsplit_once(".v") {
+        Some((name, version)) => match version.parse::<u32>() {
+            Ok(version) => (name.to_string(), version),
+            Err(_) => (stem.clone(), 1),
+        },
+        None => (stem.clone(), 1),
+    };
+    let mut file_name = format!("{}.v{}", name, version + 1);
+    if let Some(extension) = model_path.extension() {
+        file_name.push('.');
+        file_name.push_str(&extension.to_string_lossy());
+    }
+    model_path.with_file_name(file_name)
+}
+
+/// Runs `sql` and boosts `options.rounds` more rounds on top of the model at
+/// `base_model`, saving the result as its [`next_version`].
+///
+/// The new rows are encoded with the categories and classes saved with the
+/// base model and must have its feature layout. Rounds are numbered after
+/// the rounds of the base model, also in the training log and the best
+/// iteration. Returns the [`train`] summary.
+pub async fn continue_training(
+    ctx: &SessionContext,
+    sql: &str,
+    label: &str,
+    base_model: impl AsRef<Path>,
+    options: &TrainOptions,
+) -> Result<RecordBatch> {
+    let base_model = base_model.as_ref();
+    let mut options = options.clone();
+    options.base_model = Some(base_model.to_path_buf());
+    options.model_path = next_version(base_model);
+    train(ctx, sql, label, &options).await
+}
+
+/// How the features and the label of a model are encoded, so that other
+/// data can be turned into a `DMatrix` the same way.
+pub(crate) struct Encoding {
+    pub categories: CategoryMapping,
+    pub labels: Option<LabelMapping>,
+    pub layout: Option<FeatureLayout>,
+}
+
+impl Encoding {
+    /// The encoding saved with a model trained by [`train`].
+    pub fn of_model(booster: &Booster) -> Result<Self> {
+        let layout = FeatureLayout::load(booster)?.ok_or_else(|| {
+            DataFusionError::Execution(
+                "Model has no saved feature layout to check the data against".to_string(),
+            )
+        })?;
+        Ok(Encoding {
+            categories: CategoryMapping::load(booster)?.unwrap_or_default(),
+            labels: LabelMapping::load(booster)?,
+            layout: Some(layout),
+        })
+    }
+
+    /// Builds a labeled `DMatrix` of `batches` with the category and label
+    /// mappings of this encoding, failing if the features differ. Rows are
+    /// weighted by the `options.weight` column if the batches have it.
+    pub fn dmatrix(
+        &self,
+        batches: &[RecordBatch],
+        label: &str,
+        options: &TrainOptions,
+    ) -> Result<DMatrix> {
+        let mut builder = self.builder(label);
+        let has_weight = |weight: &String| {
+            batches
+                .first()
+                .is_some_and(|batch| batch.schema().column_with_name(weight).is_some())
+        };
+        if let Some(weight) = options.weight.as_ref().filter(|w| has_weight(w)) {
+            builder = builder.weight(weight);
+        }
+        if let Some(group) = &options.group {
+            builder = builder.group(group);
+        }
+        let dmat = self.build(builder, batches)?;
+        check_labels(&dmat, options.objective)?;
+        Ok(dmat)
+    }
+
+    /// Like [`dmatrix`](Self::dmatrix) for training rows, which always have
+    /// the weight column and are also weighted by class.
+    pub fn training_dmatrix(
+        &self,
+        batches: &[RecordBatch],
+        label: &str,
+        options: &TrainOptions,
+    ) -> Result<DMatrix> {
+        let dmat = self.build(training_weights(self.builder(label), options)?, batches)?;
+        check_labels(&dmat, options.objective)?;
+        Ok(dmat)
+    }
+
+    fn builder(&self, label: &str) -> DMatrixBuilder {
+        let mut builder = DMatrixBuilder::with_categories(self.categories.clone()).label(label);
+        if let Some(labels) = &self.labels {
+            builder = builder.label_mapping(labels.clone());
+        }
+        match &self.layout {
+            Some(layout) => builder.with_layout(layout.clone()),
+            None => builder,
+        }
+    }
+
+    fn build(&self, mut builder: DMatrixBuilder, batches: &[RecordBatch]) -> Result<DMatrix> {
+        builder.append_batches(batches)?;
+        builder.build()
+    }
+
+    /// Stores the encoding on `booster` so it is saved with the model file.
+    pub fn save(&self, booster: &mut Booster) -> Result<()> {
+        self.categories.save(booster)?;
+        if let Some(labels) = &self.labels {
+            labels.save(booster)?;
+        }
+        if let Some(layout) = &self.layout {
+            layout.save(booster)?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds the labeled `DMatrix` of all batches together with its encoding:
+/// the fitted category mapping, the classes of a string label and the
+/// feature layout.
+pub(crate) fn training_data(
+    batches: &[RecordBatch],
+    label: &str,
+    options: &TrainOptions,
+) -> Result<(DMatrix, Encoding)> {
+    let mut builder = training_weights(DMatrixBuilder::new().label(label), options)?;
+    let labels = label_mapping(batches, label, options)?;
+    if let Some(labels) = &labels {
+        builder = builder.label_mapping(labels.clone());
+    }
+    builder.append_batches(batches)?;
+    let encoding = Encoding {
+        categories: builder.categories().clone(),
+        labels,
+        layout: builder.layout().cloned(),
+    };
+    let dmat = builder.build()?;
+    check_labels(&dmat, options.objective)?;
+    Ok((dmat, encoding))
+}
+
+/// Weighs training rows by the `options.weight` column and by class, and
+/// groups them by the `options.group` column.
+fn training_weights(mut builder: DMatrixBuilder, options: &TrainOptions) -> Result<DMatrixBuilder> {
+    if let Some(group) = &options.group {
+        builder = builder.group(group);
+    }
+    if let Some(weight) = &options.weight {
+        builder = builder.weight(weight);
+    }
+    match &options.class_weights {
+        Some(weights) => builder = builder.class_weights(weights.clone()),
+        None if options.balance_classes => {
+            return Err(DataFusionError::Plan(
+                "balance_classes is computed by train from the query, \
+                 set class_weights when training from batches"
+                    .to_string(),
+            ))
+        }
+        None => {}
+    }
+    Ok(builder)
+}
+
+/// The classes of a string label column, `None` for numeric labels.
+fn label_mapping(
+    batches: &[RecordBatch],
+    label: &str,
+    options: &TrainOptions,
+) -> Result<Option<LabelMapping>> {
+    let Some(first) = batches.first() else {
+        return Ok(None);
+    };
+    if !is_string_label(first.schema().field_with_name(label)?.data_type()) {
+        return Ok(None);
+    }
+    if !is_classification(options.objective) {
+        return Err(DataFusionError::Plan(format!(
+            "{} needs a numeric label, {} is a string column",
+            options.objective.to_string(),
+            label
+        )));
+    }
+    if let Some(classes) = &options.classes {
+        return Ok(Some(LabelMapping::new(classes.clone())));
+    }
+    let columns = batches
+        .iter()
+        .map(|batch| Ok(batch.column(batch.schema().index_of(label)?).clone()))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Some(LabelMapping::fit(&columns)?))
+}
+
+/// Boosts `options.rounds` rounds on `dtrain`.
+pub fn train_booster(dtrain: &DMatrix, options: &TrainOptions) -> Result<Booster> {
+    Ok(train_booster_with_validation(dtrain, &[], None, options)?.0)
+}
+
+/// Best validation score seen while training with early stopping.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BestIteration {
+    pub round: u32,
+    pub value: f32,
+}
+
+/// What happened while training with [`train_booster_with_validation`].
+#[derive(Debug, Clone, Default)]
+pub struct TrainingOutcome {
+    /// Number of rounds of the model, up to the best round when stopped
+    /// early.
+    pub rounds: u32,
+    /// Set when early stopping was enabled.
+    pub best: Option<BestIteration>,
+    pub log: TrainingLog,
+}
+
+/// Boosts up to `options.rounds` rounds on `dtrain`, on top of the trees of
+/// `base` if given.
+///
+/// After each round `dtrain` is evaluated as `train`, followed by every
+/// named validation set. The metrics are recorded in the log of the returned
+/// [`TrainingOutcome`]. With `options.early_stopping_rounds` the last metric
+/// on the last validation set decides when to stop; `auc`, `aucpr`, `map` and
+/// `ndcg` are maximized, custom metrics as declared, all other metrics
+/// minimized, and the returned booster is the one after the best round.
+pub fn train_booster_with_validation(
+    dtrain: &DMatrix,
+    validation: &[(&str, &DMatrix)],
+    base: Option<Booster>,
+    options: &TrainOptions,
+) -> Result<(Booster, TrainingOutcome)> {
+    if options.early_stopping_rounds.is_some() && validation.is_empty() {
+        return Err(DataFusionError::Plan(
+            "early_stopping_rounds needs at least one validation query".to_string(),
+        ));
+    }
+    let params = options.booster_params()?;
+    let weighted = options.weight.is_some()
+        || options.class_weights.is_some()
+        || options.balance_classes
+        || options.scale_pos_weight != 1.0;
+    if options.custom_objective.is_some() && weighted {
+        return Err(DataFusionError::Plan(
+            "Custom objectives do not support sample or class weights".to_string(),
+        ));
+    }
+    let (mut booster, first_round) = match base {
+        Some(mut booster) => {
+            booster.set_params(&params).map_err(xgb_error)?;
+            let rounds = num_trees(&booster)? as u32;
+            (booster, rounds)
+        }
+        None => {
+            let mut cached = vec![dtrain];
+            cached.extend(validation.iter().map(|(_, dmat)| *dmat));
+            let booster = Booster::new_with_cached_dmats(&params, &cached).map_err(xgb_error)?;
+            (booster, 0)
+        }
+    };
+
+    let mut outcome = TrainingOutcome {
+        rounds: first_round,
+        ..Default::default()
+    };
+    let mut best_model = None;
+    for round in first_round..first_round + options.rounds {
+        options.update(&mut booster, dtrain, round)?;
+        outcome.rounds = round + 1;
+        outcome
+            .log
+            .record(round, "train", options.evaluate(&booster, dtrain)?);
+        let Some((last_name, _)) = validation.last() else {
+            continue;
+        };
+
+        for (name, dmat) in validation {
+            outcome
+                .log
+                .record(round, name, options.evaluate(&booster, dmat)?);
+        }
+
+        let Some(patience) = options.early_stopping_rounds else {
+            continue;
+        };
+        let entry = options.stopping_entry(&outcome.log, round, last_name)?;
+        let (maximize, value) = (options.maximize(&entry.metric), entry.value);
+        let improved = outcome.best.is_none_or(|best| {
+            if maximize {
+                value > best.value
+            } else {
+                value < best.value
+            }
+        });
+        if improved {
+            outcome.best = Some(BestIteration { round, value });
+            best_model = Some(model_bytes(&booster)?);
+        } else if outcome
+            .best
+            .is_some_and(|best| round - best.round >= patience)
+        {
+            break;
+        }
+    }
+    if let (Some(best), Some(bytes)) = (outcome.best, best_model) {
+        if best.round + 1 < outcome.rounds {
+            booster = Booster::load_buffer(&bytes).map_err(xgb_error)?;
+            booster.set_params(&params).map_err(xgb_error)?;
+            outcome.rounds = best.round + 1;
+        }
+    }
+    Ok((booster, outcome))
+}
+
+/// The model file of `booster`, saved to a scratch file and read back as the
+/// xgboost crate cannot save a model to a buffer.
+fn model_bytes(booster: &Booster) -> Result<Vec<u8>> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "train_xgboost_best_{}_{}.xgb",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    booster.save(&path).map_err(xgb_error)?;
+    let bytes = std::fs::read(&path);
+    let _ = std::fs::remove_file(&path);
+    Ok(bytes?)
+}
+
+/// Schema of the one-row summary returned by [`train`].
+pub fn summary_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("model", DataType::Utf8, false),
+        Field::new("rows", DataType::UInt64, false),
+        Field::new("features", DataType::UInt64, false),
+        Field::new("rounds", DataType::UInt32, false),
+        Field::new("objective", DataType::Utf8, false),
+        Field::new("metric", DataType::Utf8, false),
+        Field::new("value", DataType::Float32, false),
+        Field::new("best_iteration", DataType::UInt32, true),
+    ]))
+}
+
+/// `train_xgboost('<query>', '<label>', 'key=value', ...)` table function.
+///
+/// Trains and saves a model when the query is executed and returns the
+/// [`train`] summary. Options are the ones accepted by [`TrainOptions::set`].
+pub struct TrainFunction;
+
+impl TableFunctionImpl for TrainFunction {
+    fn call(&self, args: &[Expr]) -> Result<Arc<dyn TableProvider>> {
+        let args = args
+            .iter()
+            .map(|arg| literal_string("train_xgboost", arg))
+            .collect::<Result<Vec<_>>>()?;
+        let (sql, label, options) = match &args[..] {
+            [sql, label, options @ ..] => (
+                sql.clone(),
+                label.clone(),
+                TrainOptions::from_options(options)?,
+            ),
+            _ => {
+                return Err(DataFusionError::Plan(
+                    "train_xgboost expects a query and a label column".to_string(),
+                ))
+            }
+        };
+
+        Ok(Arc::new(LazyTable::new(
+            "train_xgboost",
+            summary_schema(),
+            Arc::new(move |ctx| {
+                let (sql, label, options) = (sql.clone(), label.clone(), options.clone());
+                Box::pin(async move { train(&ctx, &sql, &label, &options).await })
+            }),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_train_options() -> Result<()> {
+        let options = TrainOptions::from_options(&[
+            "objective=reg:linear",
+            "max_depth = 3",
+            "eta=0.1",
+            "rounds=4",
+        ])?;
+        assert_eq!(options.objective.to_string(), "reg:linear");
+        assert_eq!(options.max_depth, 3);
+        assert_eq!(options.eta, 0.1);
+        assert_eq!(options.rounds, 4);
+        assert_eq!(options.model_path, PathBuf::from("trained.xgb"));
+
+        assert!(TrainOptions::from_options(&["max_depth=deep"]).is_err());
+        assert!(TrainOptions::from_options(&["depth=3"]).is_err());
+        assert!(TrainOptions::from_options(&["objective=reg:unknown"]).is_err());
+        assert!(TrainOptions::from_options(&["objective=survival:aft"]).is_err());
+        let error = TrainOptions::from_options(&["label_upper_bound=end"]).err();
+        assert!(error.is_some_and(|e| e.to_string().contains("survival:aft")));
+        let error = TrainOptions::from_options(&["objective=rank:ndcg"]).err();
+        assert!(error.is_some_and(|e| e.to_string().contains("rank:pairwise")));
+
+        let options =
+            TrainOptions::from_options(&["objective=reg:tweedie", "tweedie_variance_power=1.2"])?;
+        assert_eq!(options.tweedie_variance_power, Some(1.2));
+        assert!(TrainOptions::from_options(&["tweedie_variance_power=2"]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_next_version() -> Result<()> {
+        let next = |path: &str| next_version(Path::new(path));
+        assert_eq!(next("model.xgb"), PathBuf::from("model.v2.xgb"));
+        assert_eq!(next("models/m.v2.xgb"), PathBuf::from("models/m.v3.xgb"));
+        assert_eq!(next("m.vintage.xgb"), PathBuf::from("m.vintage.v2.xgb"));
+        assert_eq!(next("model"), PathBuf::from("model.v2"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_parse_metrics() -> Result<()> {
+        assert_eq!(parse_metric("auc")?.to_string(), "auc");
+        assert_eq!(parse_metric("error@0.7")?.to_string(), "error@0.7");
+        assert_eq!(parse_metric("ndcg@5-")?.to_string(), "ndcg@5-");
+        assert!(parse_metric("accuracy").is_err());
+        assert!(parse_metric("map@top").is_err());
+        assert!(parse_metric("tweedie-nloglik").is_err());
+
+        assert!(maximize_metric("auc"));
+        assert!(maximize_metric("ndcg@5-"));
+        assert!(!maximize_metric("logloss"));
+
+        let options = TrainOptions::from_options(&[
+            "eval_metric=logloss, auc",
+            "validation=SELECT * FROM t WHERE split = 1",
+            "early_stopping_rounds=3",
+        ])?;
+        assert_eq!(options.eval_metrics, vec!["logloss", "auc"]);
+        assert_eq!(options.validation, vec!["SELECT * FROM t WHERE split = 1"]);
+        assert_eq!(options.early_stopping_rounds, Some(3));
+
+        let options = TrainOptions::from_options(&[
+            "weight=w",
+            "class_weights=fraud:50, ok:1",
+            "scale_pos_weight=2.5",
+        ])?;
+        assert_eq!(options.weight.as_deref(), Some("w"));
+        assert_eq!(
+            options.class_weights,
+            Some(vec![("fraud".to_string(), 50.0), ("ok".to_string(), 1.0)])
+        );
+        assert_eq!(options.scale_pos_weight, 2.5);
+        assert!(TrainOptions::from_options(&["class_weights=fraud"]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+use crate::train::{save_model, train_booster_with_validation, training_data, TrainOptions};
+use crate::{xgb_error, TrainingLog};
+use datafusion::arrow::array::{Array, ArrayRef, BinaryArray, ListArray, StringArray};
+use datafusion::arrow::compute::concat;
+use datafusion::arrow::datatypes::{DataType, Field, Schema};
+use datafusion::arrow::ipc::reader::StreamReader;
+use datafusion::arrow::ipc::writer::StreamWriter;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::common::utils::array_into_list_array;
+use datafusion::common::ScalarValue;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_expr::{
+    Accumulator, AccumulatorFactoryFunction, AggregateUDF, ReturnTypeFunction, Signature,
+    StateTypeFunction, TypeSignature, Volatility,
+};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use xgboost::Booster;
+
+/// Name of the label column of the batches an accumulator trains on.
+const LABEL: &str = "label";
+
+/// `train_xgboost_agg(f1, ..., fn, label, 'key=value ...')` aggregate.
+///
+/// Trains one model per group of a `GROUP BY` on the rows of the group and
+/// returns it as the bytes of a saved model file, which `Booster::load_buffer`
+/// reads. Features are `onehot` or categorical columns as for `predict`, and
+/// the encoding is saved with every model. The last argument holds
+/// whitespace-separated [`TrainOptions`], see [`group_options`]. A group
+/// without rows gives null.
+pub fn train_xgboost_agg_udaf() -> AggregateUDF {
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Binary)));
+    let accumulator: AccumulatorFactoryFunction =
+        Arc::new(|_| Ok(Box::<TrainAccumulator>::default()));
+    let state_type: StateTypeFunction = Arc::new(|_| {
+        Ok(Arc::new(vec![
+            DataType::List(Arc::new(Field::new("item", DataType::Binary, true))),
+            DataType::Utf8,
+        ]))
+    });
+    AggregateUDF::new(
+        "train_xgboost_agg",
+        &Signature::new(TypeSignature::VariadicAny, Volatility::Immutable),
+        &return_type,
+        &accumulator,
+        &state_type,
+    )
+}
+
+/// Parses the options argument of `train_xgboost_agg`. Options that need
+/// other queries or columns than the features and label are rejected.
+pub fn group_options(options: &str) -> Result<TrainOptions> {
+    let options: Vec<&str> = options.split_whitespace().collect();
+    let options = TrainOptions::from_options(&options)?;
+    let unsupported = [
+        ("validation", !options.validation.is_empty()),
+        (
+            "early_stopping_rounds",
+            options.early_stopping_rounds.is_some(),
+        ),
+        ("base_model", options.base_model.is_some()),
+        ("weight", options.weight.is_some()),
+        ("group", options.group.is_some()),
+    ];
+    if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+        return Err(DataFusionError::Plan(format!(
+            "train_xgboost_agg does not support the {} option",
+            name
+        )));
+    }
+    Ok(options)
+}
+
+/// Keeps the rows of a group as batches of `f0, ..., label` columns. As the
+/// state types of an aggregate cannot depend on its argument types, partial
+/// states are exchanged as Arrow IPC streams, one per batch since an IPC
+/// stream keeps the first dictionary of a field.
+#[derive(Debug, Default)]
+struct TrainAccumulator {
+    batches: Vec<RecordBatch>,
+    options: Option<String>,
+}
+
+impl TrainAccumulator {
+    fn options(&self) -> Result<TrainOptions> {
+        group_options(self.options.as_deref().unwrap_or_default())
+    }
+
+    fn encode(batch: &RecordBatch) -> Result<Vec<u8>> {
+        let mut writer = StreamWriter::try_new(Vec::new(), &batch.schema())?;
+        writer.write(batch)?;
+        writer.finish()?;
+        Ok(writer.into_inner()?)
+    }
+}
+
+impl Accumulator for TrainAccumulator {
+    fn update_batch(&mut self, values: &[ArrayRef]) -> Result<()> {
+        let [columns @ .., _, _] = values else {
+            return Err(DataFusionError::Plan(
+                "train_xgboost_agg expects features, a label and options".to_string(),
+            ));
+        };
+        if columns.is_empty() {
+            return Err(DataFusionError::Plan(
+                "train_xgboost_agg needs at least one feature".to_string(),
+            ));
+        }
+        let options = values[values.len() - 1]
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .ok_or_else(|| {
+                DataFusionError::Plan("train_xgboost_agg options must be a string".to_string())
+            })?;
+        if options.is_empty() {
+            return Ok(());
+        }
+        self.options = Some(options.value(0).to_string());
+
+        // the values are slices of the input batch; copying them keeps the
+        // rest of the batch from being held and written to the state
+        let arrays = values[..values.len() - 1]
+            .iter()
+            .map(|column| concat(&[column.as_ref()]))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        // IPC streams share a dictionary between fields of the same id
+        let fields: Vec<Field> = arrays
+            .iter()
+            .enumerate()
+            .map(|(i, array)| {
+                let name = if i == columns.len() {
+                    LABEL.to_string()
+                } else {
+                    format!("f{}", i)
+                };
+                Field::new_dict(name, array.data_type().clone(), true, i as i64, false)
+            })
+            .collect();
+        self.batches
+            .push(RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?);
+        Ok(())
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue> {
+        if self.batches.iter().all(|batch| batch.num_rows() == 0) {
+            return Ok(ScalarValue::Binary(None));
+        }
+        let mut options = self.options()?;
+        options.model_path = scratch_path();
+        let result = train_model(&self.batches, &options);
+        let _ = std::fs::remove_file(&options.model_path);
+        let _ = std::fs::remove_file(TrainingLog::path(&options.model_path));
+        Ok(ScalarValue::Binary(Some(result?)))
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of_val(self)
+            + self
+                .batches
+                .iter()
+                .map(|batch| batch.get_array_memory_size())
+                .sum::<usize>()
+    }
+
+    fn state(&self) -> Result<Vec<ScalarValue>> {
+        let streams = self
+            .batches
+            .iter()
+            .map(Self::encode)
+            .collect::<Result<Vec<_>>>()?;
+        let streams: ArrayRef = Arc::new(BinaryArray::from_iter_values(streams));
+        Ok(vec![
+            ScalarValue::List(Arc::new(array_into_list_array(streams))),
+            ScalarValue::Utf8(self.options.clone()),
+        ])
+    }
+
+    fn merge_batch(&mut self, states: &[ArrayRef]) -> Result<()> {
+        let streams = states[0]
+            .as_any()
+            .downcast_ref::<ListArray>()
+            .ok_or_else(|| DataFusionError::Internal("Expected ListArray".to_string()))?;
+        let options = states[1]
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .ok_or_else(|| DataFusionError::Internal("Expected StringArray".to_string()))?;
+        for (stream, options) in streams.iter().zip(options.iter()) {
+            if let Some(options) = options {
+                self.options = Some(options.to_string());
+            }
+            let Some(stream) = stream else {
+                continue;
+            };
+            let stream = stream
+                .as_any()
+                .downcast_ref::<BinaryArray>()
+                .ok_or_else(|| DataFusionError::Internal("Expected BinaryArray".to_string()))?;
+            for bytes in stream.iter().flatten() {
+                for batch in StreamReader::try_new(bytes, None)? {
+                    self.batches.push(batch?);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Trains on the rows of a group and returns the saved model file.
+fn train_model(batches: &[RecordBatch], options: &TrainOptions) -> Result<Vec<u8>> {
+    let (dtrain, encoding) = training_data(batches, LABEL, options)?;
+    let (mut booster, outcome) = train_booster_with_validation(&dtrain, &[], None, options)?;
+    save_model(&mut booster, &encoding, &outcome, LABEL, options)?;
+    Ok(std::fs::read(&options.model_path)?)
+}
+
+/// A file the model of one group is saved to before it is read back, as
+/// the xgboost crate cannot save a model to a buffer.
+fn scratch_path() -> std::path::PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    std::env::temp_dir().join(format!(
+        "train_xgboost_agg_{}_{}.xgb",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Loads a model returned by `train_xgboost_agg`.
+pub fn load_model_bytes(bytes: &[u8]) -> Result<Booster> {
+    Booster::load_buffer(bytes).map_err(xgb_error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use datafusion::arrow::array::{BooleanArray, StringDictionaryBuilder};
+    use datafusion::arrow::datatypes::Int32Type;
+
+    #[tokio::test]
+    pub async fn test_group_options() -> Result<()> {
+        let options = group_options("objective=reg:squarederror  max_depth=2\trounds=3")?;
+        assert_eq!(options.objective.to_string(), "reg:linear");
+        assert_eq!((options.max_depth, options.rounds), (2, 3));
+        let error = group_options("validation=SELECT").err().unwrap();
+        assert!(error.to_string().contains("the validation option"));
+        assert!(group_options("weight=w").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_accumulator_merges_states() -> Result<()> {
+        let dictionary = |values: [&str; 4]| -> Result<ArrayRef> {
+            let mut builder = StringDictionaryBuilder::<Int32Type>::new();
+            for value in values {
+                builder.append(value)?;
+            }
+            Ok(Arc::new(builder.finish()))
+        };
+        let values: Vec<ArrayRef> = vec![
+            dictionary(["red", "blue", "red", "blue"])?,
+            dictionary(["s", "s", "m", "l"])?,
+            Arc::new(BooleanArray::from(vec![true, false, true, false])),
+            Arc::new(StringArray::from(vec!["rounds=2"; 4])),
+        ];
+        let mut partial = TrainAccumulator::default();
+        partial.update_batch(&values)?;
+        partial.update_batch(&values)?;
+        let state = partial
+            .state()?
+            .iter()
+            .map(|value| value.to_array())
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut merged = TrainAccumulator::default();
+        assert_eq!(merged.evaluate()?, ScalarValue::Binary(None));
+        merged.merge_batch(&state)?;
+        assert_eq!(merged.batches.len(), 2);
+        // every dictionary column keeps its own values
+        for (i, column) in values[..3].iter().enumerate() {
+            assert_eq!(merged.batches[1].column(i).as_ref(), column.as_ref());
+        }
+        let ScalarValue::Binary(Some(bytes)) = merged.evaluate()? else {
+            panic!("expected a model");
+        };
+        let booster = load_model_bytes(&bytes)?;
+        assert_eq!(
+            booster.get_attribute("label").map_err(xgb_error)?,
+            Some(LABEL.to_string())
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+use datafusion::arrow::array::{Array, ArrayRef, Float32Array, StringArray, UInt32Array};
+use datafusion::arrow::csv::{ReaderBuilder, Writer};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::prelude::SessionContext;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Value of an evaluation metric on a dataset after a boosting round.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub round: u32,
+    pub dataset: String,
+    pub metric: String,
+    pub value: f32,
+}
+
+/// Metrics recorded while training, in the order they were evaluated.
+///
+/// Training evaluates the training rows as `train` and every validation set
+/// after each round. The log is saved next to the model file, see
+/// [`TrainingLog::path`], and can be queried as a table of
+/// `(round, dataset, metric, value)` rows, see [`TrainingLog::schema`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrainingLog {
+    entries: Vec<LogEntry>,
+}
+
+impl TrainingLog {
+    pub fn entries(&self) -> &[LogEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Records the `metrics` of `dataset` in sorted metric order.
+    pub fn record(
+        &mut self,
+        round: u32,
+        dataset: &str,
+        metrics: impl IntoIterator<Item = (String, f32)>,
+    ) {
+        let mut metrics: Vec<(String, f32)> = metrics.into_iter().collect();
+        metrics.sort_by(|a, b| a.0.cmp(&b.0));
+        self.entries
+            .extend(metrics.into_iter().map(|(metric, value)| LogEntry {
+                round,
+                dataset: dataset.to_string(),
+                metric,
+                value,
+            }));
+    }
+
+    /// Value of `metric` on `dataset` after `round`.
+    pub fn value(&self, round: u32, dataset: &str, metric: &str) -> Option<f32> {
+        self.entries
+            .iter()
+            .find(|e| e.round == round && e.dataset == dataset && e.metric == metric)
+            .map(|e| e.value)
+    }
+
+    /// Schema of [`to_batch`](Self::to_batch).
+    pub fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("round", DataType::UInt32, false),
+            Field::new("dataset", DataType::Utf8, false),
+            Field::new("metric", DataType::Utf8, false),
+            Field::new("value", DataType::Float32, false),
+        ]))
+    }
+
+    pub fn to_batch(&self) -> Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(
+                self.entries
+                    .iter()
+                    .map(|e| e.round)
+                    .collect::<UInt32Array>(),
+            ),
+            Arc::new(StringArray::from_iter_values(
+                self.entries.iter().map(|e| e.dataset.as_str()),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                self.entries.iter().map(|e| e.metric.as_str()),
+            )),
+            Arc::new(
+                self.entries
+                    .iter()
+                    .map(|e| e.value)
+                    .collect::<Float32Array>(),
+            ),
+        ];
+        Ok(RecordBatch::try_new(Self::schema(), columns)?)
+    }
+
+    /// Reads the rows of batches with the [`schema`](Self::schema) of a log.
+    pub fn from_batches(batches: &[RecordBatch]) -> Result<Self> {
+        let mut log = TrainingLog::default();
+        for batch in batches {
+            let column = |i: usize| batch.column(i).as_any();
+            let downcast_error = || DataFusionError::Internal("Unexpected log schema".to_string());
+            let rounds = column(0)
+                .downcast_ref::<UInt32Array>()
+                .ok_or_else(downcast_error)?;
+            let datasets = column(1)
+                .downcast_ref::<StringArray>()
+                .ok_or_else(downcast_error)?;
+            let metrics = column(2)
+                .downcast_ref::<StringArray>()
+                .ok_or_else(downcast_error)?;
+            let values = column(3)
+                .downcast_ref::<Float32Array>()
+                .ok_or_else(downcast_error)?;
+            log.entries
+                .extend((0..batch.num_rows()).map(|row| LogEntry {
+                    round: rounds.value(row),
+                    dataset: datasets.value(row).to_string(),
+                    metric: metrics.value(row).to_string(),
+                    value: values.value(row),
+                }));
+        }
+        Ok(log)
+    }
+
+    /// Registers the log as table `name` of `ctx`.
+    pub fn register(&self, ctx: &SessionContext, name: &str) -> Result<()> {
+        ctx.register_batch(name, self.to_batch()?)?;
+        Ok(())
+    }
+
+    /// Where the log of the model saved at `model_path` is written, e.g.
+    /// `mushrooms.log.csv` for `mushrooms.xgb`.
+    pub fn path(model_path: impl AsRef<Path>) -> PathBuf {
+        model_path.as_ref().with_extension("log.csv")
+    }
+
+    /// Writes the log as CSV with a header.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut writer = Writer::new(File::create(path)?);
+        writer.write(&self.to_batch()?)?;
+        Ok(())
+    }
+
+    /// Reads a log written by [`save`](Self::save).
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let reader = ReaderBuilder::new(Self::schema())
+            .with_header(true)
+            .build(File::open(path)?)?;
+        let batches = reader.collect::<Result<Vec<_>, _>>()?;
+        Self::from_batches(&batches)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_save_and_load_log() -> Result<()> {
+        let mut log = TrainingLog::default();
+        log.record(
+            0,
+            "train",
+            [("logloss".to_string(), 0.5), ("auc".to_string(), 0.9)],
+        );
+        log.record(0, "validation_0", [("logloss".to_string(), 0.6)]);
+        log.record(1, "train", [("logloss".to_string(), 0.25)]);
+        assert_eq!(log.value(1, "train", "logloss"), Some(0.25));
+        assert_eq!(log.entries()[0].metric, "auc");
+
+        let path = std::env::temp_dir().join("test_training_log.log.csv");
+        log.save(&path)?;
+        assert_eq!(TrainingLog::load(&path)?, log);
+        assert_eq!(
+            TrainingLog::path("models/m.xgb"),
+            PathBuf::from("models/m.log.csv")
+        );
+        Ok(())
+    }
+}
@@ -1,8 +1,34 @@
+use datafusion::arrow::array::{
+    Array, ArrayRef, BooleanArray, Float32Array, Float64Array, Int64Array, StringArray,
+    UInt32Array, UInt64Array,
+};
+use datafusion::arrow::datatypes::DataType;
 use datafusion::assert_batches_eq;
 use datafusion::error::Result;
-use datafusion::{execution::options::CsvReadOptions, prelude::SessionContext};
+use datafusion::logical_expr::{create_udf, ScalarFunctionImplementation, Volatility};
+use datafusion::{
+    execution::options::CsvReadOptions,
+    prelude::{SessionConfig, SessionContext},
+};
+use std::sync::Arc;
 use xgboost::{parameters, Booster};
-use xgboost_udf_example::{convert_to_native, create_dmatrix, register_udfs};
+use xgboost_udf_example::{
+    balanced_class_weights, continue_training, convert_to_native, create_dmatrix,
+    create_dmatrix_from_dataframe, create_dmatrix_from_stream, create_dmatrix_with_categories,
+    cross_validate, load_model_bytes, model_routes, next_version, predict_ensemble_udf,
+    predict_label_udf, predict_routed_udf, predict_udf, register_udfs, split_dataframe, train,
+    with_predict_raw, CategoryMapping, Combination, CustomMetric, CustomObjective, DMatrixBuilder,
+    Encoder, Ensemble, FeatureLayout, LabelMapping, ModelSession, Pipeline, TrainOptions,
+    TrainingLog,
+};
+
+/// Numbers the mushrooms rows in the order of all their columns, which are
+/// unique per row, so splits on the id do not depend on the scan order.
+const MUSHROOM_ID: &str = "row_number() OVER (ORDER BY class, cap_shape, cap_surface, \
+    cap_color, bruises, odor, gill_attachment, gill_spacing, gill_size, gill_color, stalk_shape, \
+    stalk_root, stalk_surface_above_ring, stalk_surface_below_ring, stalk_color_above_ring, \
+    stalk_color_below_ring, veil_type, veil_color, ring_number, ring_type, spore_print_color, \
+    population, habitat)";
 
 #[tokio::test]
 async fn it_onehots() -> Result<()> {
@@ -39,13 +65,64 @@ async fn it_creates_dmatrix() -> Result<()> {
         .await?;
     // how do I make this string multiline?
     let sql = "SELECT onehot(arrow_cast(cap_shape, 'Dictionary(Int32, Utf8)')) as cap_shap, onehot(arrow_cast(cap_surface, 'Dictionary(Int32, Utf8)')) as cap_surface, onehot(arrow_cast(cap_color, 'Dictionary(Int32, Utf8)')) as cap_color, onehot(arrow_cast(bruises, 'Dictionary(Int32, Utf8)')) as bruises FROM mushrooms";
-    let batches = ctx.sql(sql).await?.collect().await?;
-    let dmat = create_dmatrix(&batches[0])?;
+    let dmat = create_dmatrix_from_dataframe(ctx.sql(sql).await?).await?;
     assert_eq!(dmat.shape(), (8124, 22));
 
     Ok(())
 }
 
+#[tokio::test]
+async fn it_creates_dmatrix_from_several_batches() -> Result<()> {
+    let ctx = SessionContext::new_with_config(SessionConfig::new().with_batch_size(1000));
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let sql = "SELECT arrow_cast(odor, 'Dictionary(Int32, Utf8)') as odor, \
+                      arrow_cast(habitat, 'Dictionary(Int32, Utf8)') as habitat \
+               FROM mushrooms";
+    let batches = ctx.sql(sql).await?.collect().await?;
+    assert!(batches.len() > 1);
+
+    let mut builder = DMatrixBuilder::new();
+    builder.append_batches(&batches)?;
+    assert_eq!(builder.categories().vocabulary(0).unwrap().len(), 9);
+    assert_eq!(builder.build()?.shape(), (8124, 2));
+
+    let stream = ctx.sql(sql).await?.execute_stream().await?;
+    assert_eq!(create_dmatrix_from_stream(stream).await?.shape(), (8124, 2));
+
+    // onehot keys follow the dictionary of each batch and are merged
+    let sql = "SELECT onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor, \
+                      onehot(arrow_cast(habitat, 'Dictionary(Int32, Utf8)')) as habitat \
+               FROM mushrooms";
+    let batches = ctx.sql(sql).await?.collect().await?;
+    let layouts = batches
+        .iter()
+        .map(FeatureLayout::of)
+        .collect::<Result<Vec<_>>>()?;
+    assert!(layouts.iter().any(|layout| *layout != layouts[0]));
+    let mut builder = DMatrixBuilder::new();
+    builder.append_batches(&batches)?;
+    let mut names = builder.layout().unwrap().feature_names();
+    names.sort();
+    assert_eq!(names, single_batch_feature_names(sql).await?);
+    assert_eq!(builder.build()?.shape(), (8124, 16));
+    Ok(())
+}
+
+/// Sorted feature names of the result of `sql` over mushrooms in one batch.
+async fn single_batch_feature_names(sql: &str) -> Result<Vec<String>> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let batches = ctx.sql(sql).await?.collect().await?;
+    assert_eq!(batches.len(), 1);
+    let mut names = FeatureLayout::of(&batches[0])?.feature_names();
+    names.sort();
+    Ok(names)
+}
+
 #[tokio::test]
 async fn it_trains_a_model() -> Result<()> {
     let ctx = SessionContext::new();
@@ -73,21 +150,16 @@ async fn it_trains_a_model() -> Result<()> {
                     onehot(arrow_cast(ring_number, 'Dictionary(Int32, Utf8)')) as ring_number, \
                     onehot(arrow_cast(ring_type, 'Dictionary(Int32, Utf8)')) as ring_type, \
                     onehot(arrow_cast(population, 'Dictionary(Int32, Utf8)')) as population, \
-                    onehot(arrow_cast(habitat, 'Dictionary(Int32, Utf8)')) as habitat \
+                    onehot(arrow_cast(habitat, 'Dictionary(Int32, Utf8)')) as habitat, \
+                    class \
               FROM mushrooms";
     let batches = ctx.sql(sql).await?.collect().await?;
     println!("batches len: {}", batches.len());
-    let mut dmat = create_dmatrix(&batches[0])?;
-    let sql = "SELECT onehot(arrow_cast(class, 'Dictionary(Int32, Utf8)')) as class FROM mushrooms";
-    let classes = ctx.sql(sql).await?.collect().await?;
-    println!("batches len: {}", batches.len());
-    let (classes, _) = convert_to_native(&classes[0].column(0), 1)?;
-    let data_transform = classes
-        .into_iter()
-        .map(|x| x as u8 as f32)
-        .collect::<Vec<f32>>();
-
-    dmat.set_labels(&data_transform).unwrap();
+    let mut builder = DMatrixBuilder::new()
+        .label("class")
+        .label_mapping(LabelMapping::new(["p", "e"]));
+    builder.append_batches(&batches)?;
+    let dmat = builder.build()?;
     // configure objectives, metrics, etc.
     let learning_params = parameters::learning::LearningTaskParametersBuilder::default()
         .objective(parameters::learning::Objective::BinaryLogistic)
@@ -109,9 +181,6 @@ async fn it_trains_a_model() -> Result<()> {
         .build()
         .unwrap();
 
-    // specify datasets to evaluate against during training
-    let evaluation_sets = [(&dmat, "train")];
-
     println!("dmat shape: {:?}", dmat.shape());
     // specify overall training setup// overall configuration for training/evaluation
     let training_params = parameters::TrainingParametersBuilder::default()
@@ -126,10 +195,13 @@ async fn it_trains_a_model() -> Result<()> {
     println!("\nTraining tree booster...");
 
     // train model, and print evaluation data
-    let bst = Booster::train(&training_params).unwrap();
+    let mut bst = Booster::train(&training_params).unwrap();
 
     println!("{:?}", bst.predict(&dmat.slice(&[1, 5]).unwrap()).unwrap());
-    bst.save("model.xgb").unwrap();
+    LabelMapping::new(["p", "e"]).save(&mut bst)?;
+    builder.layout().unwrap().save(&mut bst)?;
+    bst.save(std::env::temp_dir().join("mushrooms_trained.xgb"))
+        .unwrap();
     Ok(())
 }
 
@@ -168,3 +240,1567 @@ async fn it_predicts() -> Result<()> {
     assert_eq!(_batches[0].column(0).len(), 8124);
     Ok(())
 }
+
+#[tokio::test]
+async fn it_trains_with_categorical_features() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let sql = "SELECT \
+                    arrow_cast(odor, 'Dictionary(Int32, Utf8)') as odor, \
+                    arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)') as spore_print_color, \
+                    onehot(arrow_cast(bruises, 'Dictionary(Int32, Utf8)')) as bruises, \
+                    onehot(arrow_cast(class, 'Dictionary(Int32, Utf8)')) as class \
+              FROM mushrooms";
+    let batches = ctx.sql(sql).await?.collect().await?;
+    let features = batches[0].project(&[0, 1, 2])?;
+
+    let categories = CategoryMapping::fit(features.columns())?;
+    assert_eq!(categories.vocabulary(0).unwrap().len(), 9);
+    assert!(categories.vocabulary(2).is_none());
+
+    let mut dmat = create_dmatrix_with_categories(&features, &categories)?;
+    assert_eq!(dmat.shape(), (8124, 4));
+    let (labels, _) = convert_to_native(batches[0].column(3), 1)?;
+    let labels = labels
+        .into_iter()
+        .map(|x| x as u8 as f32)
+        .collect::<Vec<f32>>();
+    dmat.set_labels(&labels).unwrap();
+
+    let booster_params = parameters::BoosterParametersBuilder::default()
+        .learning_params(
+            parameters::learning::LearningTaskParametersBuilder::default()
+                .objective(parameters::learning::Objective::BinaryLogistic)
+                .build()
+                .unwrap(),
+        )
+        .verbose(false)
+        .build()
+        .unwrap();
+    let training_params = parameters::TrainingParametersBuilder::default()
+        .dtrain(&dmat)
+        .boost_rounds(10)
+        .booster_params(booster_params)
+        .build()
+        .unwrap();
+    let mut bst = Booster::train(&training_params).unwrap();
+    categories.save(&mut bst)?;
+    let model_path = std::env::temp_dir().join("mushrooms_categorical.xgb");
+    bst.save(&model_path).unwrap();
+
+    let loaded = Booster::load(&model_path).unwrap();
+    assert_eq!(CategoryMapping::load(&loaded)?, Some(categories));
+
+    ctx.register_udf(predict_udf("predict_categorical", &model_path));
+    let sql =
+        "SELECT predict_categorical(odor, spore_print_color, bruises) as prediction FROM (SELECT \
+                    arrow_cast(odor, 'Dictionary(Int32, Utf8)') as odor, \
+                    arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)') as spore_print_color, \
+                    onehot(arrow_cast(bruises, 'Dictionary(Int32, Utf8)')) as bruises \
+              FROM mushrooms WHERE odor <> 'p') data";
+    let scored = ctx.sql(sql).await?.collect().await?;
+    let predictions = scored[0]
+        .column(0)
+        .as_any()
+        .downcast_ref::<Float32Array>()
+        .unwrap();
+
+    // rows are filtered before arrow_cast, so dictionary keys differ from
+    // the training batch and only the saved mapping lines the codes up
+    let expected = bst.predict(&dmat).unwrap();
+    let odor = ctx
+        .sql("SELECT odor FROM mushrooms")
+        .await?
+        .collect()
+        .await?;
+    let odor = odor[0]
+        .column(0)
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    let expected: Vec<f32> = expected
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| odor.value(*i) != "p")
+        .map(|(_, p)| p)
+        .collect();
+    assert_eq!(predictions.values().to_vec(), expected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_predicts_with_iteration_range() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let sql = "SELECT \
+                    onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor, \
+                    onehot(arrow_cast(gill_size, 'Dictionary(Int32, Utf8)')) as gill_size, \
+                    onehot(arrow_cast(class, 'Dictionary(Int32, Utf8)')) as class \
+              FROM mushrooms";
+    let batches = ctx.sql(sql).await?.collect().await?;
+    let mut dmat = create_dmatrix(&batches[0].project(&[0, 1])?)?;
+    let (labels, _) = convert_to_native(batches[0].column(2), 1)?;
+    let labels = labels
+        .into_iter()
+        .map(|x| x as u8 as f32)
+        .collect::<Vec<f32>>();
+    dmat.set_labels(&labels).unwrap();
+
+    let booster_params = parameters::BoosterParametersBuilder::default()
+        .learning_params(
+            parameters::learning::LearningTaskParametersBuilder::default()
+                .objective(parameters::learning::Objective::BinaryLogistic)
+                .build()
+                .unwrap(),
+        )
+        .verbose(false)
+        .build()
+        .unwrap();
+    let params = |rounds| {
+        parameters::TrainingParametersBuilder::default()
+            .dtrain(&dmat)
+            .boost_rounds(rounds)
+            .booster_params(booster_params.clone())
+            .build()
+            .unwrap()
+    };
+    // training is deterministic, so a 3 round model is the first 3 trees of a 6 round one
+    let full = Booster::train(&params(6)).unwrap();
+    let early = Booster::train(&params(3)).unwrap();
+    let model_path = std::env::temp_dir().join("mushrooms_iteration_range.xgb");
+    full.save(&model_path).unwrap();
+
+    ctx.register_udf(predict_udf("predict_range", &model_path));
+    let sql = "SELECT predict_range(odor, gill_size) as full_model, \
+                      predict_range(odor, gill_size, 3) as first_trees, \
+                      predict_range(odor, gill_size, 3, 6) as last_trees \
+              FROM (SELECT \
+                    onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor, \
+                    onehot(arrow_cast(gill_size, 'Dictionary(Int32, Utf8)')) as gill_size \
+              FROM mushrooms) data";
+    let scored = ctx.sql(sql).await?.collect().await?;
+    let column = |i: usize| {
+        scored[0]
+            .column(i)
+            .as_any()
+            .downcast_ref::<Float32Array>()
+            .unwrap()
+            .values()
+            .to_vec()
+    };
+
+    let expected_full = full.predict(&dmat).unwrap();
+    let expected_early = early.predict(&dmat).unwrap();
+    for (actual, expected) in column(0).iter().zip(&expected_full) {
+        assert_eq!(actual, expected);
+    }
+    for (actual, expected) in column(1).iter().zip(&expected_early) {
+        assert!((actual - expected).abs() < 1e-5);
+    }
+    assert_ne!(column(1), column(2));
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_trains_with_table_function() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let model_path = std::env::temp_dir().join("mushrooms_train_xgboost.xgb");
+    let sql = format!(
+        "SELECT rows, features, rounds, objective, metric FROM train_xgboost(\
+            'SELECT onehot(arrow_cast(odor, ''Dictionary(Int32, Utf8)'')) as odor, \
+                    arrow_cast(spore_print_color, ''Dictionary(Int32, Utf8)'') as spore_print_color, \
+                    CASE WHEN class = ''e'' THEN 1 ELSE 0 END as label \
+             FROM mushrooms', \
+            'label', 'objective=binary:logistic', 'max_depth=4', 'eta=0.5', 'rounds=5', \
+            'model_path={}')",
+        model_path.display()
+    );
+    let batches = ctx.sql(&sql).await?.collect().await?;
+
+    let expected = [
+        "+------+----------+--------+-----------------+--------+",
+        "| rows | features | rounds | objective       | metric |",
+        "+------+----------+--------+-----------------+--------+",
+        "| 8124 | 10       | 5      | binary:logistic | error  |",
+        "+------+----------+--------+-----------------+--------+",
+    ];
+    assert_batches_eq!(expected, &batches);
+    assert!(model_path.exists());
+
+    ctx.register_udf(predict_udf("predict_trained", &model_path));
+    let sql = "SELECT predict_trained(odor, spore_print_color) as prediction FROM (SELECT \
+                    onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor, \
+                    arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)') as spore_print_color \
+              FROM mushrooms) data";
+    let scored = ctx.sql(sql).await?.collect().await?;
+    assert_eq!(scored[0].num_rows(), 8124);
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_trains_with_session_api() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let mut options = TrainOptions::from_options(&["max_depth=3", "rounds=3"])?;
+    options.model_path = std::env::temp_dir().join("mushrooms_train_api.xgb");
+    let sql = "SELECT onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor, \
+                      class = 'e' as label \
+               FROM mushrooms";
+    let summary = train(&ctx, sql, "label", &options).await?;
+
+    assert_eq!(summary.num_rows(), 1);
+    let error = summary
+        .column_by_name("value")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<Float32Array>()
+        .unwrap()
+        .value(0);
+    assert!(error < 0.05);
+    assert!(options.model_path.exists());
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_creates_and_drops_models_with_sql() -> Result<()> {
+    let model_dir = std::env::temp_dir().join("mushrooms_model_statements");
+    let _ = std::fs::remove_dir_all(&model_dir);
+    let session = ModelSession::new(SessionContext::new(), &model_dir)?;
+    session
+        .context()
+        .register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+
+    let created = session
+        .sql(
+            "CREATE MODEL edible OPTIONS (objective='binary:logistic', max_depth=4, rounds=5, label='is_edible') AS \
+             SELECT onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor, \
+                    arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)') as spore_print_color, \
+                    class = 'e' as is_edible \
+             FROM mushrooms",
+        )
+        .await?
+        .collect()
+        .await?;
+    assert_eq!(created[0].num_rows(), 1);
+    assert!(session
+        .sql("CREATE MODEL edible AS SELECT 1 as label")
+        .await
+        .is_err());
+
+    let models = session
+        .sql("SHOW MODELS")
+        .await?
+        .select_columns(&["name", "objective", "label"])?
+        .collect()
+        .await?;
+    let expected = [
+        "+--------+-----------------+-----------+",
+        "| name   | objective       | label     |",
+        "+--------+-----------------+-----------+",
+        "| edible | binary:logistic | is_edible |",
+        "+--------+-----------------+-----------+",
+    ];
+    assert_batches_eq!(expected, &models);
+
+    let scored = session
+        .sql(
+            "SELECT predict_model('edible', odor, spore_print_color) as prediction FROM (SELECT \
+                    onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor, \
+                    arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)') as spore_print_color \
+             FROM mushrooms) data",
+        )
+        .await?
+        .collect()
+        .await?;
+    assert_eq!(scored[0].num_rows(), 8124);
+    let scored = session
+        .sql("SELECT count(prediction) AS predictions FROM ml_predict('edible', 'mushrooms')")
+        .await?
+        .collect()
+        .await?;
+    let expected = [
+        "+-------------+",
+        "| predictions |",
+        "+-------------+",
+        "| 8124        |",
+        "+-------------+",
+    ];
+    assert_batches_eq!(expected, &scored);
+
+    session.sql("DROP MODEL edible").await?.collect().await?;
+    session
+        .sql("DROP MODEL IF EXISTS edible")
+        .await?
+        .collect()
+        .await?;
+    assert!(session.sql("DROP MODEL edible").await.is_err());
+    let models = session.sql("SHOW MODELS").await?.collect().await?;
+    assert_eq!(models[0].num_rows(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_predicts_string_labels() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let mut options = TrainOptions::from_options(&["max_depth=3", "rounds=3", "classes=p,e"])?;
+    options.model_path = std::env::temp_dir().join("mushrooms_labels.xgb");
+    let sql = "SELECT onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor, class \
+               FROM mushrooms";
+    train(&ctx, sql, "class", &options).await?;
+
+    let booster = Booster::load(&options.model_path).unwrap();
+    assert_eq!(
+        LabelMapping::load(&booster)?,
+        Some(LabelMapping::new(["p", "e"]))
+    );
+
+    ctx.register_udf(predict_label_udf("predict_class", &options.model_path));
+    let sql = "SELECT count(*) as rows, \
+                      sum(CASE WHEN predicted = class THEN 1 ELSE 0 END) as correct, \
+                      sum(CASE WHEN strict = 'e' THEN 1 ELSE 0 END) as strict_e \
+               FROM (SELECT class, \
+                            predict_class(onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)'))) as predicted, \
+                            predict_class(onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')), 1.0) as strict \
+                     FROM mushrooms) data";
+    let batches = ctx.sql(sql).await?.collect().await?;
+    let expected = [
+        "+------+---------+----------+",
+        "| rows | correct | strict_e |",
+        "+------+---------+----------+",
+        "| 8124 | 8004    | 0        |",
+        "+------+---------+----------+",
+    ];
+    assert_batches_eq!(expected, &batches);
+
+    // the threshold applies to the sigmoid of binary:logitraw margins
+    options.objective = parameters::learning::Objective::BinaryLogisticRaw;
+    train(
+        &ctx,
+        "SELECT onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor, class \
+         FROM mushrooms",
+        "class",
+        &options,
+    )
+    .await?;
+    ctx.register_udf(predict_label_udf("predict_class", &options.model_path));
+    let batches = ctx.sql(sql).await?.collect().await?;
+    assert_batches_eq!(expected, &batches);
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_splits_rows_deterministically() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let sql = format!(
+        "SELECT bucket, count(*) as rows FROM \
+           (SELECT split_bucket(id, 42, 0.8, 0.1, 0.1) as bucket FROM \
+             (SELECT {} as id FROM mushrooms) ids) buckets \
+         GROUP BY bucket ORDER BY bucket",
+        MUSHROOM_ID
+    );
+    let first = ctx.sql(&sql).await?.collect().await?;
+    let second = ctx.sql(&sql).await?.collect().await?;
+    assert_eq!(first, second);
+    assert_eq!(first[0].num_rows(), 3);
+
+    let mushrooms = ctx
+        .sql(&format!(
+            "SELECT {} as id, \
+                    onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor, \
+                    class = 'e' as label \
+             FROM mushrooms",
+            MUSHROOM_ID
+        ))
+        .await?;
+    let splits = split_dataframe(&mushrooms, "id", 42, &[0.8, 0.2])?;
+    let mut rows = 0;
+    for split in &splits {
+        let dmat = create_dmatrix_from_dataframe(split.clone().select_columns(&["odor"])?).await?;
+        rows += dmat.num_rows();
+    }
+    assert_eq!(rows, 8124);
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_cross_validates() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let options = TrainOptions::from_options(&["max_depth=3", "rounds=3", "seed=1"])?;
+    let sql = "SELECT onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor, \
+                      onehot(arrow_cast(gill_size, 'Dictionary(Int32, Utf8)')) as gill_size, \
+                      class \
+               FROM mushrooms";
+    let result = cross_validate(&ctx, sql, "class", 4, &options).await?;
+
+    let folds = result
+        .column(0)
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    let folds: Vec<&str> = folds.iter().map(|fold| fold.unwrap()).collect();
+    assert_eq!(folds, vec!["0", "1", "2", "3", "mean", "std"]);
+
+    let test_error = result
+        .column_by_name("test")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<Float64Array>()
+        .unwrap();
+    assert!(test_error.value(4) < 0.05);
+    assert!(test_error.value(5) < 0.02);
+
+    let again = cross_validate(&ctx, sql, "class", 4, &options).await?;
+    assert_eq!(result, again);
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_evaluates_predictions_in_sql() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let mut options = TrainOptions::from_options(&["max_depth=3", "rounds=3"])?;
+    options.model_path = std::env::temp_dir().join("mushrooms_metrics.xgb");
+    let features = "onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor";
+    let sql = format!("SELECT {}, class = 'e' as label FROM mushrooms", features);
+    train(&ctx, &sql, "label", &options).await?;
+    ctx.register_udf(predict_udf("predict_odor", &options.model_path));
+
+    let sql = format!(
+        "SELECT round(auc(score, label), 3) as auc, \
+                round(logloss(score, label), 3) as logloss, \
+                round(accuracy_at(score, label, 0.5), 4) as accuracy, \
+                round(rmse(score, label), 3) as rmse, \
+                round(mae(score, label), 3) as mae, \
+                confusion_matrix(score, label, 0.5) as confusion \
+         FROM (SELECT predict_odor(odor) as score, label FROM \
+                 (SELECT {}, class = 'e' as label FROM mushrooms) data) scored",
+        features
+    );
+    let batches = ctx.sql(&sql).await?.collect().await?;
+    let expected = [
+        "+-------+---------+----------+-------+-------+--------------------------------------+",
+        "| auc   | logloss | accuracy | rmse  | mae   | confusion                            |",
+        "+-------+---------+----------+-------+-------+--------------------------------------+",
+        "| 0.988 | 0.238   | 0.9852   | 0.217 | 0.205 | {tp: 4208, fp: 120, tn: 3796, fn: 0} |",
+        "+-------+---------+----------+-------+-------+--------------------------------------+",
+    ];
+    assert_batches_eq!(expected, &batches);
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_computes_roc_and_pr_curves() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let mut options = TrainOptions::from_options(&["max_depth=3", "rounds=3"])?;
+    options.model_path = std::env::temp_dir().join("mushrooms_curves.xgb");
+    let features = "onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor";
+    let sql = format!("SELECT {}, class = 'e' as label FROM mushrooms", features);
+    train(&ctx, &sql, "label", &options).await?;
+    ctx.register_udf(predict_udf("predict_odor", &options.model_path));
+
+    let query = format!(
+        "SELECT predict_odor(odor) as score, label FROM (SELECT {}, class = ''e'' as label FROM mushrooms) data",
+        features.replace('\'', "''")
+    );
+    let roc = ctx
+        .sql(&format!(
+            "SELECT round(tpr, 3) as tpr, round(fpr, 3) as fpr FROM roc_curve('{}', score, label)",
+            query
+        ))
+        .await?
+        .collect()
+        .await?;
+    let pr = ctx
+        .sql(&format!(
+            "SELECT round(precision, 3) as precision, round(recall, 3) as recall \
+             FROM pr_curve('{}', 'score', 'label')",
+            query
+        ))
+        .await?
+        .collect()
+        .await?;
+    assert_batches_eq!(
+        [
+            "+------+-------+",
+            "| tpr  | fpr   |",
+            "+------+-------+",
+            "| 0.0  | 0.0   |",
+            "| 0.19 | 0.0   |",
+            "| 1.0  | 0.031 |",
+            "| 1.0  | 1.0   |",
+            "+------+-------+",
+        ],
+        &roc
+    );
+    assert_batches_eq!(
+        [
+            "+-----------+--------+",
+            "| precision | recall |",
+            "+-----------+--------+",
+            "| 1.0       | 0.19   |",
+            "| 0.972     | 1.0    |",
+            "| 0.518     | 1.0    |",
+            "+-----------+--------+",
+        ],
+        &pr
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_stops_early_on_validation_sets() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let rows = ctx
+        .sql(&format!(
+            "SELECT split_bucket({}, 7, 0.8, 0.2) as bucket, \
+                    arrow_cast(odor, 'Dictionary(Int32, Utf8)') as odor, \
+                    arrow_cast(gill_size, 'Dictionary(Int32, Utf8)') as gill_size, \
+                    class \
+             FROM mushrooms",
+            MUSHROOM_ID
+        ))
+        .await?;
+    ctx.register_table("rows", rows.into_view())?;
+    let split = |bucket| {
+        format!(
+            "SELECT odor, gill_size, class FROM rows WHERE bucket = {}",
+            bucket
+        )
+    };
+
+    let mut options = TrainOptions::from_options(&[
+        "max_depth=2",
+        "rounds=50",
+        "eval_metric=error",
+        "early_stopping_rounds=3",
+    ])?;
+    options.validation.push(split(1));
+    options.model_path = std::env::temp_dir().join("mushrooms_early_stopping.xgb");
+    let summary = train(&ctx, &split(0), "class", &options).await?;
+
+    let rounds = summary
+        .column_by_name("rounds")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<UInt32Array>()
+        .unwrap()
+        .value(0);
+    let best = summary
+        .column_by_name("best_iteration")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<UInt32Array>()
+        .unwrap()
+        .value(0);
+    // the trees after the best round are dropped
+    assert_eq!(rounds, best + 1);
+    assert!(rounds + 3 < 50);
+
+    let booster = Booster::load(&options.model_path).unwrap();
+    assert_eq!(
+        booster.get_attribute("best_iteration").unwrap(),
+        Some(best.to_string())
+    );
+    let log = TrainingLog::load(TrainingLog::path(&options.model_path))?;
+    log.register(&ctx, "training_log")?;
+    let last = ctx
+        .sql(
+            "SELECT max(round) as last_round, count(DISTINCT dataset) as datasets \
+             FROM training_log WHERE metric = 'error'",
+        )
+        .await?
+        .collect()
+        .await?;
+    let last_row = format!("| {:<10} | 2        |", best + 3);
+    let expected = [
+        "+------------+----------+",
+        "| last_round | datasets |",
+        "+------------+----------+",
+        last_row.as_str(),
+        "+------------+----------+",
+    ];
+    assert_batches_eq!(expected, &last);
+
+    ctx.register_udf(predict_udf("predict_best", &options.model_path));
+    let scored = ctx
+        .sql("SELECT predict_best(odor, gill_size) FROM rows WHERE bucket = 1")
+        .await?
+        .collect()
+        .await?;
+    assert!(!scored.is_empty());
+
+    // continuing without early stopping uses all trees of the base model
+    let mut continued = options.clone();
+    continued.validation.clear();
+    continued.early_stopping_rounds = None;
+    continued.rounds = 0;
+    let _ = std::fs::remove_file(next_version(&options.model_path));
+    continue_training(&ctx, &split(0), "class", &options.model_path, &continued).await?;
+    let booster = Booster::load(next_version(&options.model_path)).unwrap();
+    assert_eq!(
+        booster.get_attribute("best_iteration").unwrap(),
+        Some((rounds - 1).to_string())
+    );
+    assert_eq!(
+        booster.get_attribute("best_score").unwrap(),
+        Some(String::new())
+    );
+
+    options.validation = vec!["SELECT odor, class FROM rows WHERE bucket = 1".to_string()];
+    assert!(train(&ctx, &split(0), "class", &options).await.is_err());
+    options.validation.clear();
+    assert!(train(&ctx, &split(0), "class", &options).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_continues_training_from_a_model() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let week = |bucket| {
+        format!(
+            "SELECT arrow_cast(odor, 'Dictionary(Int32, Utf8)') as odor, \
+                    arrow_cast(gill_size, 'Dictionary(Int32, Utf8)') as gill_size, \
+                    class \
+             FROM mushrooms \
+             WHERE split_bucket(cap_shape || cap_color || stalk_root || habitat, 3, 0.5, 0.5) = {}",
+            bucket
+        )
+    };
+    let mut options = TrainOptions::from_options(&["max_depth=2", "rounds=3"])?;
+    options.model_path = std::env::temp_dir().join("mushrooms_weekly.xgb");
+    let _ = std::fs::remove_file(next_version(&options.model_path));
+    train(&ctx, &week(0), "class", &options).await?;
+
+    let summary = continue_training(&ctx, &week(1), "class", &options.model_path, &options).await?;
+    let rounds = summary
+        .column_by_name("rounds")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<UInt32Array>()
+        .unwrap()
+        .value(0);
+    assert_eq!(rounds, 6);
+
+    let continued = next_version(&options.model_path);
+    assert!(continued.ends_with("mushrooms_weekly.v2.xgb"));
+    let booster = Booster::load(&continued).unwrap();
+    assert_eq!(
+        booster.get_attribute("base_model").unwrap(),
+        Some(options.model_path.display().to_string())
+    );
+    let dump = booster.dump_model(false, None).unwrap();
+    assert_eq!(dump.lines().filter(|l| l.starts_with("0:")).count(), 6);
+    let log = TrainingLog::load(TrainingLog::path(&continued))?;
+    assert_eq!(log.entries()[0].round, 3);
+
+    ctx.register_udf(predict_label_udf("predict_weekly", &continued));
+    let labels = ctx
+        .sql(&format!(
+            "SELECT predict_weekly(odor, gill_size) as predicted, class FROM ({})",
+            week(1)
+        ))
+        .await?
+        .collect()
+        .await?;
+    assert!(!labels.is_empty());
+
+    let odor_only =
+        "SELECT arrow_cast(odor, 'Dictionary(Int32, Utf8)') as odor, class FROM mushrooms";
+    let error = continue_training(&ctx, odor_only, "class", &options.model_path, &options)
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("Feature layout mismatch"));
+    options.objective = parameters::learning::Objective::RegLinear;
+    assert!(
+        continue_training(&ctx, &week(1), "class", &options.model_path, &options)
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_searches_hyperparameters() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let model_path = std::env::temp_dir().join("mushrooms_search.xgb");
+    let split = |bucket| {
+        format!(
+            "SELECT arrow_cast(odor, ''Dictionary(Int32, Utf8)'') as odor, \
+                    arrow_cast(spore_print_color, ''Dictionary(Int32, Utf8)'') as spore_print_color, \
+                    class \
+             FROM mushrooms \
+             WHERE split_bucket(cap_shape || cap_color || stalk_root || habitat, 5, 0.8, 0.2) = {}",
+            bucket
+        )
+    };
+    let grid = format!(
+        "SELECT trial, params, rounds, metric, best FROM xgboost_search('{}', '{}', 'class', \
+            'max_depth=1,3', 'eta=0.1,0.5', 'rounds=4', 'model_path={}')",
+        split(0),
+        split(1),
+        model_path.display()
+    );
+    let trials = ctx.sql(&grid).await?.collect().await?;
+    assert_eq!(trials[0].num_rows(), 4);
+    let params = trials[0]
+        .column_by_name("params")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    assert_eq!(params.value(1), "max_depth=1, eta=0.5");
+    let best = trials[0]
+        .column_by_name("best")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<BooleanArray>()
+        .unwrap();
+    assert_eq!(best.true_count(), 1);
+    assert!(model_path.exists());
+
+    let random = format!(
+        "SELECT * FROM xgboost_search('{}', '{}', 'class', \
+            'max_depth=1..4', 'subsample=0.5..1.0', 'trials=3', 'seed=11', 'rounds=3', \
+            'eval_metric=logloss', 'model_path={}')",
+        split(0),
+        split(1),
+        model_path.display()
+    );
+    let first = ctx.sql(&random).await?.collect().await?;
+    let second = ctx.sql(&random).await?.collect().await?;
+    assert_eq!(first, second);
+    assert_eq!(first[0].num_rows(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_balances_imbalanced_classes() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    // about 1 edible mushroom for 20 poisonous ones
+    let sql = "SELECT arrow_cast(odor, 'Dictionary(Int32, Utf8)') as odor, \
+                      arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)') as spore_print_color, \
+                      class = 'e' as label, \
+                      CASE WHEN habitat = 'g' THEN 2.0 ELSE 1.0 END as importance \
+               FROM mushrooms \
+               WHERE class = 'p' OR split_bucket(cap_shape || cap_color || habitat, 1, 0.05) = 0";
+    let weights = balanced_class_weights(&ctx, sql, "label").await?;
+    assert_eq!(weights.len(), 2);
+    assert_eq!(weights[0].0, "false");
+    assert_eq!(weights[1].0, "true");
+    assert!(weights[1].1 > 5.0 * weights[0].1);
+
+    let mut options = TrainOptions::from_options(&[
+        "max_depth=2",
+        "rounds=3",
+        "balance_classes=true",
+        "weight=importance",
+    ])?;
+    options.model_path = std::env::temp_dir().join("mushrooms_balanced.xgb");
+    let summary = train(&ctx, sql, "label", &options).await?;
+    let features = summary
+        .column_by_name("features")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .unwrap()
+        .value(0);
+    assert_eq!(features, 2);
+
+    options.class_weights = Some(vec![("true".to_string(), 20.0)]);
+    train(&ctx, sql, "label", &options).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_ranks_query_groups() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    // every habitat is a query ranking its mushrooms, edible ones are relevant
+    let sql = "SELECT arrow_cast(odor, 'Dictionary(Int32, Utf8)') as odor, \
+                      arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)') as spore_print_color, \
+                      CASE WHEN class = 'e' THEN 1 ELSE 0 END as relevance, \
+                      habitat \
+               FROM mushrooms";
+    let mut options = TrainOptions::from_options(&[
+        "objective=rank:pairwise",
+        "group=habitat",
+        "eval_metric=ndcg@10",
+        "max_depth=3",
+        "rounds=5",
+    ])?;
+    options.model_path = std::env::temp_dir().join("mushrooms_rank.xgb");
+    let summary = train(&ctx, sql, "relevance", &options).await?;
+    let features = summary
+        .column_by_name("features")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .unwrap()
+        .value(0);
+    assert_eq!(features, 2);
+    let log = TrainingLog::load(TrainingLog::path(&options.model_path))?;
+    assert!(log.value(4, "train", "ndcg@10").unwrap() > 0.9);
+
+    ctx.register_udf(predict_udf("predict_rank", &options.model_path));
+    let scored = format!(
+        "SELECT predict_rank(odor, spore_print_color) as score, relevance, habitat FROM ({})",
+        sql
+    );
+    let results = ctx
+        .sql(&format!(
+            "SELECT ndcg_at(score, relevance, habitat, 10) as ndcg, \
+                    ndcg_at(-score, relevance, habitat, 10) as reversed FROM ({})",
+            scored
+        ))
+        .await?
+        .collect()
+        .await?;
+    let value = |i: usize| {
+        results[0]
+            .column(i)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap()
+            .value(0)
+    };
+    assert!(value(0) > 0.9);
+    assert!(value(1) < value(0));
+
+    let per_group = ctx
+        .sql(&format!(
+            "SELECT habitat, ndcg_at(score, relevance, habitat, 10) FROM ({}) GROUP BY habitat",
+            scored
+        ))
+        .await?
+        .collect()
+        .await?;
+    assert_eq!(per_group.iter().map(|b| b.num_rows()).sum::<usize>(), 7);
+
+    let empty = ctx
+        .sql(&format!(
+            "SELECT ndcg_at(score, relevance, habitat, 10) as ndcg FROM ({}) WHERE score > 100",
+            scored
+        ))
+        .await?
+        .collect()
+        .await?;
+    let expected = ["+------+", "| ndcg |", "+------+", "|      |", "+------+"];
+    assert_batches_eq!(expected, &empty);
+
+    let error = cross_validate(&ctx, sql, "relevance", 3, &options)
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("query groups"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_trains_regression_objectives() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let sql = "SELECT arrow_cast(odor, 'Dictionary(Int32, Utf8)') as odor, \
+                      arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)') as spore_print_color, \
+                      CASE WHEN class = 'e' THEN 4.0 ELSE 1.0 END as target \
+               FROM mushrooms";
+    for (name, objective) in [
+        ("squarederror", "objective=reg:squarederror"),
+        ("poisson", "objective=count:poisson"),
+        ("gamma", "objective=reg:gamma"),
+        ("tweedie", "objective=reg:tweedie"),
+    ] {
+        let mut options = TrainOptions::from_options(&[
+            objective,
+            "tweedie_variance_power=1.2",
+            "max_depth=3",
+            "rounds=30",
+        ])?;
+        options.model_path = std::env::temp_dir().join(format!("mushrooms_{}.xgb", name));
+        train(&ctx, sql, "target", &options).await?;
+
+        // predictions are on the scale of the target, not log counts
+        ctx.register_udf(predict_udf("predict_target", &options.model_path));
+        let results = ctx
+            .sql(&format!(
+                "SELECT rmse(predict_target(odor, spore_print_color), target) as rmse, \
+                        min(predict_target(odor, spore_print_color, 10)) as early \
+                 FROM ({})",
+                sql
+            ))
+            .await?
+            .collect()
+            .await?;
+        let rmse = results[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap()
+            .value(0);
+        assert!(rmse < 0.5, "{} has rmse {}", name, rmse);
+        let early = results[0]
+            .column(1)
+            .as_any()
+            .downcast_ref::<Float32Array>()
+            .unwrap()
+            .value(0);
+        assert!(early > 0.0, "{} predicts {} after 10 rounds", name, early);
+    }
+
+    let options = TrainOptions::from_options(&["objective=count:poisson"])?;
+    let negative = "SELECT arrow_cast(odor, 'Dictionary(Int32, Utf8)') as odor, -1.0 as target \
+                    FROM mushrooms";
+    let error = train(&ctx, negative, "target", &options).await.unwrap_err();
+    assert!(error.to_string().contains("must be non-negative"));
+    let string_label = "SELECT arrow_cast(odor, 'Dictionary(Int32, Utf8)') as odor, class \
+                        FROM mushrooms";
+    let error = train(&ctx, string_label, "class", &options)
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("needs a numeric label"));
+    assert!(TrainOptions::from_options(&["objective=survival:aft"]).is_err());
+    Ok(())
+}
+
+/// Binary focal loss on margins with focusing parameter `gamma`, with the
+/// derivatives taken numerically.
+fn focal_loss(gamma: f32) -> CustomObjective {
+    let loss = move |margin: f32, label: f32| {
+        let p = (1.0 / (1.0 + (-margin).exp())).clamp(1e-6, 1.0 - 1e-6);
+        -(label * (1.0 - p).powf(gamma) * p.ln() + (1.0 - label) * p.powf(gamma) * (1.0 - p).ln())
+    };
+    CustomObjective::new(move |margins, labels| {
+        let eps = 1e-2;
+        margins
+            .iter()
+            .zip(labels)
+            .map(|(x, y)| {
+                let (lower, mid, upper) = (loss(x - eps, *y), loss(*x, *y), loss(x + eps, *y));
+                let gradient = (upper - lower) / (2.0 * eps);
+                let hessian = (upper - 2.0 * mid + lower) / (eps * eps);
+                (gradient, hessian.max(1e-3))
+            })
+            .unzip()
+    })
+}
+
+#[tokio::test]
+async fn it_trains_with_custom_objectives() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let rows = "SELECT arrow_cast(odor, 'Dictionary(Int32, Utf8)') as odor, \
+                       arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)') as spore_print_color, \
+                       class = 'e' as label, \
+                       split_bucket(cap_shape || cap_color || habitat, 3, 0.8, 0.2) as bucket \
+                FROM mushrooms";
+    let rows = ctx.sql(rows).await?;
+    ctx.register_table("focal_rows", rows.into_view())?;
+    let sql = "SELECT odor, spore_print_color, label FROM focal_rows WHERE bucket = 0";
+    let validation = "SELECT odor, spore_print_color, label FROM focal_rows WHERE bucket = 1";
+
+    let mut options = TrainOptions::from_options(&[
+        "objective=binary:logitraw",
+        "max_depth=3",
+        "rounds=50",
+        "early_stopping_rounds=3",
+    ])?;
+    options.validation.push(validation.to_string());
+    options.custom_objective = Some(focal_loss(2.0));
+    options.custom_metrics.push(CustomMetric::new(
+        "margin_error",
+        false,
+        |margins, labels| {
+            let wrong = margins
+                .iter()
+                .zip(labels)
+                .filter(|(margin, label)| (**margin > 0.0) != (**label > 0.5))
+                .count();
+            wrong as f32 / labels.len() as f32
+        },
+    ));
+    options.model_path = std::env::temp_dir().join("mushrooms_focal.xgb");
+    let summary = train(&ctx, sql, "label", &options).await?;
+    let rounds = summary
+        .column_by_name("rounds")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<UInt32Array>()
+        .unwrap()
+        .value(0);
+    assert!(rounds < 50);
+
+    let log = TrainingLog::load(TrainingLog::path(&options.model_path))?;
+    let error = log
+        .value(rounds - 1, "validation_0", "margin_error")
+        .unwrap();
+    assert!(error < 0.05, "validation error {}", error);
+
+    // binary:logitraw models predict margins
+    ctx.register_udf(predict_udf("predict_focal", &options.model_path));
+    let results = ctx
+        .sql(&format!(
+            "SELECT accuracy_at(predict_focal(odor, spore_print_color), label, 0.0) FROM ({})",
+            validation
+        ))
+        .await?
+        .collect()
+        .await?;
+    let accuracy = results[0]
+        .column(0)
+        .as_any()
+        .downcast_ref::<Float64Array>()
+        .unwrap()
+        .value(0);
+    assert!(accuracy > 0.95);
+
+    // the same loss on a DMatrix built directly
+    let batches = ctx.sql(sql).await?.collect().await?;
+    let mut builder = DMatrixBuilder::new().label("label");
+    builder.append_batches(&batches)?;
+    let dtrain = builder.build()?;
+    options.rounds = 5;
+    options.early_stopping_rounds = None;
+    let booster = xgboost_udf_example::train::train_booster(&dtrain, &options)?;
+    let metric = &options.custom_metrics[0];
+    assert!(metric.evaluate(&booster, &dtrain)? < 0.05);
+
+    options.scale_pos_weight = 2.0;
+    let error = train(&ctx, sql, "label", &options).await.unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("do not support sample or class weights"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_trains_one_model_per_group() -> Result<()> {
+    // several partitions, so partial aggregates are merged
+    let ctx = SessionContext::new_with_config(SessionConfig::new().with_target_partitions(4));
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let features = "arrow_cast(odor, 'Dictionary(Int32, Utf8)'), \
+                    arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)')";
+    let models = ctx
+        .sql(&format!(
+            "SELECT habitat, count(*) as rows, \
+                    train_xgboost_agg({}, class = 'e', 'max_depth=3 rounds=5') as model \
+             FROM mushrooms GROUP BY habitat ORDER BY habitat",
+            features
+        ))
+        .await?
+        .collect()
+        .await?;
+    let habitats = models[0]
+        .column(0)
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    assert_eq!(habitats.len(), 7);
+    let bytes = models[0]
+        .column(2)
+        .as_any()
+        .downcast_ref::<datafusion::arrow::array::BinaryArray>()
+        .unwrap();
+
+    // every model is a complete model file with its categories
+    let dir = std::env::temp_dir().join("habitat_models");
+    std::fs::create_dir_all(&dir)?;
+    for (habitat, model) in habitats.iter().zip(bytes.iter()) {
+        let (habitat, model) = (habitat.unwrap(), model.unwrap());
+        let booster = load_model_bytes(model)?;
+        assert!(booster.get_attribute("categories").unwrap().is_some());
+        let path = dir.join(format!("{}.xgb", habitat));
+        std::fs::write(&path, model)?;
+        ctx.register_udf(predict_udf(&format!("predict_{}", habitat), &path));
+    }
+    let results = ctx
+        .sql(&format!(
+            "SELECT accuracy_at(predict_g({}), class = 'e', 0.5) FROM mushrooms WHERE habitat = 'g'",
+            features
+        ))
+        .await?
+        .collect()
+        .await?;
+    let accuracy = results[0]
+        .column(0)
+        .as_any()
+        .downcast_ref::<Float64Array>()
+        .unwrap()
+        .value(0);
+    assert!(accuracy > 0.95);
+
+    let error = ctx
+        .sql(&format!(
+            "SELECT train_xgboost_agg({}, class = 'e', 'early_stopping_rounds=2') FROM mushrooms",
+            features
+        ))
+        .await?
+        .collect()
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("early_stopping_rounds"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_routes_rows_to_models() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let features = "arrow_cast(odor, 'Dictionary(Int32, Utf8)'), \
+                    arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)')";
+    let models = ctx
+        .sql(&format!(
+            "SELECT habitat, train_xgboost_agg({}, class = 'e', 'max_depth=3 rounds=5') \
+             FROM mushrooms WHERE habitat <> 'u' GROUP BY habitat",
+            features
+        ))
+        .await?
+        .collect()
+        .await?;
+    let dir = std::env::temp_dir().join("routed_models");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    for batch in &models {
+        let habitats = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        let bytes = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<datafusion::arrow::array::BinaryArray>()
+            .unwrap();
+        for (habitat, model) in habitats.iter().zip(bytes.iter()) {
+            std::fs::write(
+                dir.join(format!("{}.xgb", habitat.unwrap())),
+                model.unwrap(),
+            )?;
+        }
+    }
+    let routes = model_routes(&dir)?;
+    assert_eq!(routes.len(), 6);
+    ctx.register_udf(predict_udf("predict_g", &routes["g"]));
+    ctx.register_udf(predict_routed_udf("predict_routed", routes));
+
+    // rows of a key score as with the key's own model, rows without a model
+    // get null
+    let results = ctx
+        .sql(&format!(
+            "SELECT habitat, count(*), count(prediction), max(abs(prediction - own)) FROM ( \
+                 SELECT habitat, predict_routed(habitat, {f}) as prediction, \
+                        CASE WHEN habitat = 'g' THEN predict_g({f}) END as own \
+                 FROM mushrooms) \
+             WHERE habitat IN ('g', 'u') GROUP BY habitat ORDER BY habitat",
+            f = features
+        ))
+        .await?
+        .collect()
+        .await?;
+    let expected = [
+        "+---------+----------+-------------------+----------------------------+",
+        "| habitat | COUNT(*) | COUNT(prediction) | MAX(abs(prediction - own)) |",
+        "+---------+----------+-------------------+----------------------------+",
+        "| g       | 2148     | 2148              | 0.0                        |",
+        "| u       | 368      | 0                 |                            |",
+        "+---------+----------+-------------------+----------------------------+",
+    ];
+    assert_batches_eq!(expected, &results);
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_combines_models_into_ensembles() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let rows = "SELECT arrow_cast(odor, 'Dictionary(Int32, Utf8)') as odor, \
+                       arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)') as spore_print_color, \
+                       class = 'e' as label \
+                FROM mushrooms";
+    let rows = ctx.sql(rows).await?;
+    ctx.register_table("ensemble_rows", rows.into_view())?;
+    let sql = "SELECT odor, spore_print_color, label FROM ensemble_rows";
+
+    let mut members = Vec::new();
+    for (name, depth, rounds) in [("shallow", 1, 2), ("deep", 4, 10)] {
+        let mut options = TrainOptions::from_options(&[
+            "objective=binary:logistic",
+            &format!("max_depth={}", depth),
+            &format!("rounds={}", rounds),
+        ])?;
+        options.model_path = std::env::temp_dir().join(format!("ensemble_{}.xgb", name));
+        train(&ctx, sql, "label", &options).await?;
+        ctx.register_udf(predict_udf(
+            &format!("predict_{}", name),
+            &options.model_path,
+        ));
+        members.push(options.model_path);
+    }
+    let average = Ensemble::new(&members, Combination::Average)?;
+    ctx.register_udf(predict_ensemble_udf("predict_average", average.clone()));
+    let weighted = Ensemble::new(&members, Combination::Weighted(vec![1.0, 3.0]))?;
+    ctx.register_udf(predict_ensemble_udf("predict_weighted", weighted));
+
+    let results = ctx
+        .sql(
+            "SELECT max(abs(predict_average(odor, spore_print_color) \
+                            - (predict_shallow(odor, spore_print_color) \
+                               + predict_deep(odor, spore_print_color)) / 2)), \
+                    max(abs(predict_weighted(odor, spore_print_color) \
+                            - (predict_shallow(odor, spore_print_color) \
+                               + 3 * predict_deep(odor, spore_print_color)) / 4)) \
+             FROM ensemble_rows",
+        )
+        .await?
+        .collect()
+        .await?;
+    for column in results[0].columns() {
+        let difference = column
+            .as_any()
+            .downcast_ref::<Float32Array>()
+            .unwrap()
+            .value(0);
+        assert!(difference < 1e-6, "difference {}", difference);
+    }
+
+    // a meta-model trained on the member margins
+    let mut margins = Vec::new();
+    let mut labels = Vec::new();
+    for batch in ctx.sql(sql).await?.collect().await? {
+        let (batch_margins, _) = average.margins(&batch.columns()[..2])?;
+        margins.extend(batch_margins);
+        let label = batch
+            .column(2)
+            .as_any()
+            .downcast_ref::<BooleanArray>()
+            .unwrap();
+        labels.extend(label.iter().map(|label| label.unwrap() as u8 as f32));
+    }
+    let mut dtrain = xgboost::DMatrix::from_dense(&margins, labels.len()).unwrap();
+    dtrain.set_labels(&labels).unwrap();
+    let options = TrainOptions::from_options(&["objective=binary:logistic", "max_depth=2"])?;
+    let meta = xgboost_udf_example::train::train_booster(&dtrain, &options)?;
+    let meta_path = std::env::temp_dir().join("ensemble_meta.xgb");
+    meta.save(&meta_path).unwrap();
+    let stacked = Ensemble::new(&members, Combination::Stacked(meta_path))?;
+    ctx.register_udf(predict_ensemble_udf("predict_stacked", stacked));
+    let results = ctx
+        .sql(
+            "SELECT accuracy_at(predict_stacked(odor, spore_print_color), label, 0.5) \
+             FROM ensemble_rows",
+        )
+        .await?
+        .collect()
+        .await?;
+    let accuracy = results[0]
+        .column(0)
+        .as_any()
+        .downcast_ref::<Float64Array>()
+        .unwrap()
+        .value(0);
+    assert!(accuracy > 0.98, "accuracy {}", accuracy);
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_scores_raw_columns_with_a_pipeline() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let mut options = TrainOptions::from_options(&["max_depth=3", "rounds=10"])?;
+    options.model_path = std::env::temp_dir().join("mushrooms_pipeline.xgb");
+    Pipeline::train(
+        &ctx,
+        "SELECT * FROM mushrooms",
+        "class",
+        &["odor"],
+        &options,
+    )
+    .await?;
+
+    // the model file alone holds the encoders and the feature order
+    let pipeline = Pipeline::load(&options.model_path)?;
+    let columns = pipeline.columns();
+    assert_eq!(columns.len(), 22);
+    assert_eq!(columns[0], "cap_shape");
+    assert!(matches!(
+        pipeline.encoder("odor"),
+        Some(Encoder::Categorical(_))
+    ));
+    assert_eq!(
+        pipeline.encoder("bruises").map(Encoder::vocabulary),
+        Some(&["f".to_string(), "t".to_string()][..])
+    );
+
+    ctx.register_udf(pipeline.score_udf("score"));
+    let results = ctx
+        .sql(&format!(
+            "SELECT accuracy_at(score({}), class = 'p', 0.5) FROM mushrooms",
+            columns.join(", ")
+        ))
+        .await?
+        .collect()
+        .await?;
+    let accuracy = results[0]
+        .column(0)
+        .as_any()
+        .downcast_ref::<Float64Array>()
+        .unwrap()
+        .value(0);
+    assert!(accuracy > 0.99, "accuracy {}", accuracy);
+    let empty: Vec<ArrayRef> = columns
+        .iter()
+        .map(|_| Arc::new(StringArray::from(Vec::<&str>::new())) as ArrayRef)
+        .collect();
+    assert!(pipeline.predict(&empty)?.is_empty());
+
+    let error = ctx
+        .sql("SELECT score(cap_shape) FROM mushrooms")
+        .await?
+        .collect()
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("takes 22 columns"));
+    let error = Pipeline::load("model.xgb").unwrap_err();
+    assert!(error.to_string().contains("is not a pipeline"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_appends_predictions_with_ml_predict() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let sql = "SELECT onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor, \
+                      arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)') as spore_print_color, \
+                      class \
+               FROM mushrooms";
+    let mut options = TrainOptions::from_options(&["max_depth=3", "rounds=10"])?;
+    options.model_path = std::env::temp_dir().join("mushrooms_ml_predict.xgb");
+    train(&ctx, sql, "class", &options).await?;
+    let model = options.model_path.display();
+
+    // raw columns are matched by name and encoded as in training
+    let results = ctx
+        .sql(&format!(
+            "SELECT count(*), sum(CASE WHEN prediction = class THEN 1 ELSE 0 END), \
+                    max(abs(probability_e + probability_p - 1)) \
+             FROM ml_predict('{}', 'mushrooms')",
+            model
+        ))
+        .await?
+        .collect()
+        .await?;
+    let count = |i: usize| {
+        results[0]
+            .column(i)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap()
+            .value(0)
+    };
+    let (rows, correct) = (count(0), count(1));
+    assert_eq!(rows, 8124);
+    assert!(correct as f64 / rows as f64 > 0.98, "correct {}", correct);
+    let difference = results[0]
+        .column(2)
+        .as_any()
+        .downcast_ref::<Float32Array>()
+        .unwrap()
+        .value(0);
+    assert!(difference < 1e-6);
+
+    // all input columns are kept, also over a query
+    let results = ctx
+        .sql(&format!(
+            "SELECT * FROM ml_predict('{}', \
+                 'SELECT habitat, odor, spore_print_color FROM mushrooms WHERE habitat = ''g''')",
+            model
+        ))
+        .await?
+        .collect()
+        .await?;
+    let schema = results[0].schema();
+    let names: Vec<&str> = schema
+        .fields()
+        .iter()
+        .map(|field| field.name().as_str())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "habitat",
+            "odor",
+            "spore_print_color",
+            "prediction",
+            "probability_e",
+            "probability_p"
+        ]
+    );
+    let rows: usize = results.iter().map(|batch| batch.num_rows()).sum();
+    assert_eq!(rows, 2148);
+
+    // the input is planned with the UDFs of the session when the query runs
+    let same: ScalarFunctionImplementation = Arc::new(|args| Ok(args[0].clone()));
+    ctx.register_udf(create_udf(
+        "same",
+        vec![DataType::Utf8],
+        Arc::new(DataType::Utf8),
+        Volatility::Immutable,
+        same,
+    ));
+    let results = ctx
+        .sql(&format!(
+            "SELECT count(prediction) FROM ml_predict('{}', \
+                 'SELECT same(odor) AS odor, spore_print_color FROM mushrooms')",
+            model
+        ))
+        .await?
+        .collect()
+        .await?;
+    assert_eq!(
+        results[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap()
+            .value(0),
+        8124
+    );
+
+    let error = ctx
+        .sql(&format!(
+            "SELECT * FROM ml_predict('{}', 'SELECT odor FROM mushrooms')",
+            model
+        ))
+        .await
+        .unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("needs a column spore_print_color, the input has odor"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn it_rewrites_predict_raw_into_encoded_features() -> Result<()> {
+    let ctx = SessionContext::new();
+    register_udfs(&ctx);
+    ctx.register_csv("mushrooms", "./data/mushrooms.csv", CsvReadOptions::new())
+        .await?;
+    let encoded = "onehot(arrow_cast(cap_shape, 'Dictionary(Int32, Utf8)')), \
+                   onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')), \
+                   arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)')";
+    let sql = "SELECT onehot(arrow_cast(cap_shape, 'Dictionary(Int32, Utf8)')) as cap_shape, \
+                      onehot(arrow_cast(odor, 'Dictionary(Int32, Utf8)')) as odor, \
+                      arrow_cast(spore_print_color, 'Dictionary(Int32, Utf8)') as spore_print_color, \
+                      class = 'e' as label \
+               FROM mushrooms";
+    let mut options = TrainOptions::from_options(&["max_depth=3", "rounds=10"])?;
+    options.model_path = std::env::temp_dir().join("mushrooms_predict_raw.xgb");
+    train(&ctx, sql, "label", &options).await?;
+
+    let ctx = with_predict_raw(&ctx, "predict_raw", &options.model_path);
+    ctx.register_udf(predict_udf("predict_encoded", &options.model_path));
+    let results = ctx
+        .sql(&format!(
+            "SELECT count(*), max(abs(raw - encoded)), accuracy_at(raw, class = 'e', 0.5) FROM ( \
+                 SELECT predict_raw(cap_shape, odor, spore_print_color) as raw, \
+                        predict_encoded({}) as encoded, class \
+                 FROM mushrooms)",
+            encoded
+        ))
+        .await?
+        .collect()
+        .await?;
+    let rows = results[0]
+        .column(0)
+        .as_any()
+        .downcast_ref::<Int64Array>()
+        .unwrap()
+        .value(0);
+    assert_eq!(rows, 8124);
+    let difference = results[0]
+        .column(1)
+        .as_any()
+        .downcast_ref::<Float32Array>()
+        .unwrap()
+        .value(0);
+    assert_eq!(difference, 0.0);
+    let accuracy = results[0]
+        .column(2)
+        .as_any()
+        .downcast_ref::<Float64Array>()
+        .unwrap()
+        .value(0);
+    assert!(accuracy > 0.98, "accuracy {}", accuracy);
+
+    // calls in aggregates and filters are rewritten as well
+    let results = ctx
+        .sql(
+            "SELECT habitat, accuracy_at(predict_raw(cap_shape, odor, spore_print_color), class = 'e', 0.5) \
+             FROM mushrooms WHERE predict_raw(cap_shape, odor, spore_print_color) > 0.5 \
+             GROUP BY habitat",
+        )
+        .await?
+        .collect()
+        .await?;
+    assert!(!results.is_empty());
+
+    let error = ctx
+        .sql("SELECT predict_raw(odor) FROM mushrooms")
+        .await?
+        .collect()
+        .await
+        .unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("takes the 3 columns cap_shape, odor, spore_print_color, got 1 arguments"));
+    Ok(())
+}